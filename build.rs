@@ -1,6 +1,6 @@
-use std::{env, fs::File, io::Write};
+use std::{env, fs, fs::File, io::Write, path::Path};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use tinytemplate::TinyTemplate;
 
@@ -12,6 +12,74 @@ struct BundleConfiguration {
     cf_bundle_identifier: String,
     wix_upgrade_code: String,
     manufacturer: String,
+    // Relative to the config directory, seeds the settings on first launch
+    #[serde(default)]
+    default_settings_file: Option<String>,
+    // Relative to the config directory, an SDL gamecontrollerdb.txt
+    #[serde(default)]
+    gamepad_database_file: Option<String>,
+}
+
+/// Checks the bundled default settings and returns them so they can be embedded.
+/// Every key has to be one of `default_settings` in config.yaml, so a typo fails the build instead of being ignored.
+fn read_default_settings_file(file: &str) -> Result<String> {
+    let path = Path::new("config").join(file);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("Could not read the default settings file {path:?}: {e}"))?;
+    let settings: serde_yaml::Value = serde_yaml::from_str(&content)
+        .map_err(|e| anyhow!("The default settings file {path:?} is not valid YAML: {e}"))?;
+    let serde_yaml::Value::Mapping(settings) = settings else {
+        return Err(anyhow!(
+            "The default settings file {path:?} has to be a mapping of settings"
+        ));
+    };
+
+    let config: serde_yaml::Value = serde_yaml::from_str(include_str!("config/config.yaml"))?;
+    let known_settings = config
+        .get("default_settings")
+        .and_then(serde_yaml::Value::as_mapping)
+        .ok_or_else(|| anyhow!("config.yaml has no default_settings"))?;
+    for (key, value) in &settings {
+        match known_settings.get(key) {
+            None => {
+                return Err(anyhow!(
+                    "Unknown setting {key:?} in the default settings file {path:?}"
+                ))
+            }
+            // A null default (like no output device) takes anything
+            Some(known)
+                if !known.is_null()
+                    && std::mem::discriminant(known) != std::mem::discriminant(value) =>
+            {
+                return Err(anyhow!(
+                    "The setting {key:?} in the default settings file {path:?} has the wrong type, it should look like {known:?}"
+                ))
+            }
+            _ => {}
+        }
+    }
+    Ok(content)
+}
+/// Checks that every mapping in the gamepad database looks like `<GUID>,<name>,<mapping>,...`, SDL would skip broken ones without telling.
+fn read_gamepad_database_file(file: &str) -> Result<String> {
+    let path = Path::new("config").join(file);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("Could not read the gamepad database {path:?}: {e}"))?;
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(',');
+        let guid = fields.next().unwrap_or_default();
+        if guid.len() != 32 || !guid.chars().all(|c| c.is_ascii_hexdigit()) || fields.count() < 2 {
+            return Err(anyhow!(
+                "Line {} of the gamepad database {path:?} is not an SDL gamepad mapping",
+                line_number + 1
+            ));
+        }
+    }
+    Ok(content)
 }
 
 fn main() -> Result<()> {
@@ -26,6 +94,31 @@ fn main() -> Result<()> {
     let mut bundle_config: BundleConfiguration =
         serde_yaml::from_str(include_str!("config/config.yaml"))?;
 
+    let default_settings = match &bundle_config.default_settings_file {
+        Some(file) => {
+            println!("cargo:rerun-if-changed=config/{file}");
+            read_default_settings_file(file)?
+        }
+        None => String::new(),
+    };
+    let out_dir = env::var("OUT_DIR")?;
+    fs::write(
+        Path::new(&out_dir).join("default-settings.yaml"),
+        default_settings,
+    )?;
+
+    let gamepad_database = match &bundle_config.gamepad_database_file {
+        Some(file) => {
+            println!("cargo:rerun-if-changed=config/{file}");
+            read_gamepad_database_file(file)?
+        }
+        None => String::new(),
+    };
+    fs::write(
+        Path::new(&out_dir).join("gamecontrollerdb.txt"),
+        gamepad_database,
+    )?;
+
     if bundle_config.version.is_none() {
         bundle_config.version = Some(env!("CARGO_PKG_VERSION").to_string());
     }
@@ -3,7 +3,9 @@ use egui::{Slider, Ui};
 
 use super::{
     //debug::{AudioStat, AudioStats},
+    resample::ResamplerQuality,
     Audio,
+    ChannelLayout,
 };
 
 pub struct AudioGui {
@@ -72,8 +74,9 @@ impl GuiComponent for AudioGui {
         // Self::stats_ui(ui, &self.stats);
         let available_device_names =
             Audio::get_available_output_device_names_for_subsystem(&self.audio.audio_subsystem);
-        let new_device = {
+        let (new_device, new_channel_layout) = {
             let mut new_device = None;
+            let mut new_channel_layout = None;
             let audio_settings = &mut Settings::current_mut().audio;
             ui.horizontal(|ui| {
                 ui.label("Output");
@@ -102,16 +105,80 @@ impl GuiComponent for AudioGui {
                 }
             });
 
+            ui.horizontal(|ui| {
+                ui.label("Channels");
+                for channel_layout in [ChannelLayout::Mono, ChannelLayout::Stereo] {
+                    if ui
+                        .radio_value(
+                            &mut audio_settings.channel_layout,
+                            channel_layout,
+                            format!("{:?}", channel_layout),
+                        )
+                        .changed()
+                    {
+                        new_channel_layout = Some(channel_layout);
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Resampling");
+                egui::ComboBox::from_id_source("audio-resampler-quality")
+                    .selected_text(format!("{:?}", audio_settings.resampler_quality))
+                    .show_ui(ui, |ui| {
+                        for quality in [
+                            ResamplerQuality::Fast,
+                            ResamplerQuality::Balanced,
+                            ResamplerQuality::High,
+                        ] {
+                            ui.selectable_value(
+                                &mut audio_settings.resampler_quality,
+                                quality,
+                                format!("{:?}", quality),
+                            );
+                        }
+                    });
+            });
+
             ui.horizontal(|ui| {
                 ui.label("Volume");
                 ui.add(Slider::new(&mut audio_settings.volume, 0..=100).suffix("%"));
             });
+            ui.checkbox(
+                &mut audio_settings.mute_when_unfocused,
+                "Mute when the window is in the background",
+            );
 
-            new_device
+            ui.collapsing("Diagnostics", |ui| {
+                ui.label(match self.audio.measure_audio_latency() {
+                    Some(latency) => format!("Estimated output latency: {latency:.1} ms"),
+                    None => "Estimated output latency: -".to_string(),
+                });
+                if let Some(stats) = self.audio.audio_stats() {
+                    ui.label(format!(
+                        "Queue {:.0}% full, {:.1} ms until heard",
+                        stats.fill_level * 100.0,
+                        stats.latency_ms
+                    ));
+                    ui.label(format!(
+                        "Underruns: {}, dropped samples: {}",
+                        stats.underruns, stats.overruns
+                    ));
+                }
+                ui.checkbox(
+                    &mut audio_settings.log_underruns,
+                    "Warn in the log when the audio keeps running out",
+                );
+            });
+
+            (new_device, new_channel_layout)
         };
         if let Some(new_device) = new_device {
             self.audio.stream.set_output_device(Some(new_device));
         }
+        if let Some(new_channel_layout) = new_channel_layout {
+            self.audio.stream.set_channel_layout(new_channel_layout);
+        }
     }
 
     fn name(&self) -> Option<&str> {
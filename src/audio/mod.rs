@@ -1,6 +1,8 @@
 use std::ops::Add;
 
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
@@ -10,50 +12,223 @@ use serde::{Deserialize, Serialize};
 
 use crate::settings::Settings;
 
+use self::resample::ResamplerQuality;
+
 pub mod gui;
+pub mod resample;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub struct AudioSettings {
     pub volume: u8,
     #[serde(default = "AudioSettings::default_latency")]
     pub latency: u8,
+    // Netplay wants the audio to keep up with the inputs, so it has its own (usually lower) latency
+    #[serde(default = "AudioSettings::default_netplay_latency")]
+    pub netplay_latency: u8,
     pub output_device: Option<String>,
+    #[serde(default)]
+    pub channel_layout: ChannelLayout,
+    #[serde(default)]
+    pub resampler_quality: ResamplerQuality,
+    /// Silence to queue up before the first frame's audio in milliseconds, so the start doesn't underrun. Never more than `latency`.
+    #[serde(default = "AudioSettings::default_prebuffer")]
+    pub prebuffer: u8,
+    /// Silences the output while the window is in the background, the game keeps running so it's safe during netplay
+    #[serde(default)]
+    pub mute_when_unfocused: bool,
+    /// Warns in the log when the output keeps running out of samples, see `Audio::audio_stats`
+    #[serde(default)]
+    pub log_underruns: bool,
 }
 impl AudioSettings {
     fn default_latency() -> u8 {
         30
     }
+    fn default_netplay_latency() -> u8 {
+        20
+    }
+    fn default_prebuffer() -> u8 {
+        10
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum ChannelLayout {
+    #[default]
+    Mono,
+    Stereo,
+}
+
+impl ChannelLayout {
+    fn channels(&self) -> u8 {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+        }
+    }
+}
+
+struct AudioReceiverCallback {
+    rx: AudioReceiver,
+    handover: AudioChannelHandover,
+    channels: usize,
+    focused: Arc<AtomicBool>,
+    // For `log_underruns`, counted since `last_underrun_report`
+    recent_underruns: u32,
+    last_underrun_report: Instant,
+}
+
+/// This many underruns within `UNDERRUN_REPORT_INTERVAL` is worth a warning, a single one now and then isn't audible
+const UNDERRUN_SPIKE: u32 = 5;
+const UNDERRUN_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+impl AudioReceiverCallback {
+    fn recv(&mut self) -> Option<f32> {
+        match self.rx.try_recv() {
+            Ok(sample) => Some(sample),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                // The old channel is drained and its sender is gone, continue with the new one
+                let next_rx = self.handover.next_rx.try_lock().ok()?.take()?;
+                self.rx = next_rx;
+                self.rx.try_recv().ok()
+            }
+        }
+    }
 }
-struct AudioReceiverCallback(AudioReceiver);
 
 impl AudioCallback for AudioReceiverCallback {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        let consumer = &mut self.0;
-
-        let volume = Settings::current().audio.volume as f32 / 100.0;
+        let volume = {
+            let audio_settings = &Settings::current().audio;
+            if audio_settings.mute_when_unfocused && !self.focused.load(Ordering::Relaxed) {
+                // Only the gain, the volume setting stays for when the window comes back
+                0.0
+            } else {
+                audio_settings.volume as f32 / 100.0
+            }
+        };
         let mut missing_samples = 0;
-        for s in out {
-            if let Ok(new_sample) = consumer.try_recv() {
-                *s = new_sample * volume;
+        let mut played_samples = 0;
+        // The NES is mono, so every sample is copied to all the channels of a frame
+        for frame in out.chunks_mut(self.channels) {
+            let sample = if let Some(new_sample) = self.recv() {
+                played_samples += 1;
+                new_sample * volume
             } else {
                 missing_samples += 1;
-                *s = 0.0;
-            }
+                0.0
+            };
+            frame.fill(sample);
         }
+        let health = &self.handover.health;
+        health.queued.fetch_sub(played_samples, Ordering::Relaxed);
         if missing_samples > 0 {
             log::trace!("Buffer underrun: {missing_samples} samples");
+            health.underruns.fetch_add(1, Ordering::Relaxed);
+            self.recent_underruns += 1;
+        }
+        if self.last_underrun_report.elapsed() >= UNDERRUN_REPORT_INTERVAL {
+            if self.recent_underruns >= UNDERRUN_SPIKE && Settings::current().audio.log_underruns {
+                log::warn!(
+                    "{} audio underruns in the last {:?}, try a higher latency",
+                    self.recent_underruns,
+                    self.last_underrun_report.elapsed()
+                );
+            }
+            self.recent_underruns = 0;
+            self.last_underrun_report = Instant::now();
         }
     }
 }
 pub type AudioSender = SyncSender<f32>;
 pub type AudioReceiver = Receiver<f32>;
 
+/// Counters of the output shared by the emulation (sending) and the audio callback (playing), see [`AudioStats`]
+#[derive(Default)]
+struct AudioHealth {
+    // Signed since the callback can play a sample before the emulation counted it as sent
+    queued: AtomicI32,
+    underruns: AtomicU32,
+    overruns: AtomicU32,
+}
+
+/// How the audio output is doing, for tuning the latency and finding out where crackling comes from
+#[derive(Debug, Clone, Copy)]
+pub struct AudioStats {
+    /// How full the sample queue is, 0 is empty and 1 is the whole `latency`
+    pub fill_level: f32,
+    /// How many times the output ran out of samples and played silence
+    pub underruns: u32,
+    /// How many samples were dropped since the queue was full
+    pub overruns: u32,
+    /// How long a sample sent now waits before it's heard, the queued samples and the device buffer, in milliseconds
+    pub latency_ms: f32,
+}
+
+/// Lets the emulator switch to a channel with another latency without losing or inserting samples.
+/// The audio callback plays what's left in the old channel before moving on to the new one.
+#[derive(Clone)]
+pub struct AudioChannelHandover {
+    next_rx: Arc<Mutex<Option<AudioReceiver>>>,
+    sample_latency: Arc<AtomicU32>,
+    sample_rate: u32,
+    health: Arc<AudioHealth>,
+}
+
+impl AudioChannelHandover {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            next_rx: Arc::new(Mutex::new(None)),
+            sample_latency: Arc::new(AtomicU32::new(0)),
+            sample_rate,
+            health: Arc::new(AudioHealth::default()),
+        }
+    }
+
+    /// Sends a sample, counting it for the `AudioStats`. Without `wait` a full queue drops the sample instead of blocking.
+    pub fn send(&self, tx: &AudioSender, sample: f32, wait: bool) {
+        let sent = if wait {
+            tx.send(sample).is_ok()
+        } else {
+            match tx.try_send(sample) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.health.overruns.fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        };
+        if sent {
+            self.health.queued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn channel(&self, latency: Duration) -> (AudioSender, AudioReceiver) {
+        let sample_latency = (latency.as_secs_f32() * self.sample_rate as f32).ceil() as u32;
+        self.sample_latency.store(sample_latency, Ordering::Relaxed);
+        sync_channel(sample_latency as usize)
+    }
+
+    /// Creates a new channel to send audio through. The sender of the current channel has to be dropped for the switch to happen.
+    pub fn new_channel(&self, latency: Duration) -> AudioSender {
+        log::debug!("Switching audio latency to {latency:?}");
+        let (tx, rx) = self.channel(latency);
+        *self.next_rx.lock().unwrap() = Some(rx);
+        tx
+    }
+}
+
 pub struct Stream {
     tx: Option<AudioSender>,
+    handover: AudioChannelHandover,
     output_device_name: Option<String>,
+    channel_layout: ChannelLayout,
     audio_device: Option<AudioDevice<AudioReceiverCallback>>,
+    focused: Arc<AtomicBool>,
 }
 
 impl Stream {
@@ -65,26 +240,37 @@ impl Stream {
         log::debug!(
             "Trying to start audio: sample rate={desired_sample_rate}, latency={latency:?}"
         );
-        let sample_latency =
-            (latency.as_secs_f32() * desired_sample_rate as f32 * 1.0).ceil() as u16;
+        let handover = AudioChannelHandover::new(desired_sample_rate);
+        let (tx, audio_rx) = handover.channel(latency);
 
-        let (tx, audio_rx) = sync_channel(sample_latency as usize);
-        // Fill with silence
-        for _ in 0..sample_latency {
-            let _ = tx.send(0.0);
+        let audio_settings = &Settings::current().audio;
+        // Fill with silence, the channel blocks when full so it can't take more than the latency
+        let prebuffer = Duration::from_millis(audio_settings.prebuffer as u64).min(latency);
+        let prebuffer_samples =
+            (prebuffer.as_secs_f32() * desired_sample_rate as f32).ceil() as u32;
+        for _ in 0..prebuffer_samples.min(handover.sample_latency.load(Ordering::Relaxed)) {
+            handover.send(&tx, 0.0, true);
         }
 
-        let output_device = &Settings::current().audio.output_device;
+        let output_device = &audio_settings.output_device;
+        let channel_layout = audio_settings.channel_layout;
+        let focused = Arc::new(AtomicBool::new(true));
         let audio_device = Stream::new_audio_device(
             desired_sample_rate,
             audio_subsystem,
             output_device,
+            channel_layout,
             audio_rx,
+            handover.clone(),
+            focused.clone(),
         )?;
         Ok(Self {
             tx: Some(tx),
+            handover,
             output_device_name: output_device.clone(),
+            channel_layout,
             audio_device: Some(audio_device),
+            focused,
         })
     }
 
@@ -95,13 +281,25 @@ impl Stream {
         self.tx.take().ok_or(anyhow!("Stream already started"))
     }
 
+    pub fn handover(&self) -> AudioChannelHandover {
+        self.handover.clone()
+    }
+
+    /// Tells the output whether the window has focus, see `AudioSettings::mute_when_unfocused`
+    pub fn set_focused(&self, focused: bool) {
+        self.focused.store(focused, Ordering::Relaxed);
+    }
+
     fn new_audio_device(
         desired_sample_rate: u32,
         audio_subsystem: &AudioSubsystem,
         output_device: &Option<String>,
+        channel_layout: ChannelLayout,
         audio_rx: AudioReceiver,
+        handover: AudioChannelHandover,
+        focused: Arc<AtomicBool>,
     ) -> Result<AudioDevice<AudioReceiverCallback>> {
-        let channels = 1;
+        let channels = channel_layout.channels();
 
         let desired_spec = AudioSpecDesired {
             freq: Some(desired_sample_rate as i32),
@@ -120,38 +318,85 @@ impl Stream {
             .or_else(|| Audio::get_default_device_name_for_subsystem(audio_subsystem));
 
         let output_device = audio_subsystem
-            .open_playback(output_device.as_deref(), &desired_spec, |_| {
-                AudioReceiverCallback(audio_rx)
+            .open_playback(output_device.as_deref(), &desired_spec, |spec| {
+                AudioReceiverCallback {
+                    rx: audio_rx,
+                    handover,
+                    channels: spec.channels as usize,
+                    focused,
+                    recent_underruns: 0,
+                    last_underrun_report: Instant::now(),
+                }
             })
             .map_err(anyhow::Error::msg)?;
         log::info!("Audio started with {:?}", output_device.spec());
         Ok(output_device)
     }
 
+    /// Estimates the output latency in milliseconds from the sample queue and the device buffer.
+    pub fn measure_latency(&self) -> Option<f32> {
+        self.audio_device.as_ref().map(|audio_device| {
+            let spec = audio_device.spec();
+            let queued_samples =
+                self.handover.sample_latency.load(Ordering::Relaxed) as f32 + spec.samples as f32;
+            queued_samples / spec.freq as f32 * 1000.0
+        })
+    }
+
+    pub fn audio_stats(&self) -> Option<AudioStats> {
+        self.audio_device.as_ref().map(|audio_device| {
+            let spec = audio_device.spec();
+            let health = &self.handover.health;
+            let queued = health.queued.load(Ordering::Relaxed).max(0) as f32;
+            let capacity = self.handover.sample_latency.load(Ordering::Relaxed).max(1) as f32;
+            AudioStats {
+                fill_level: (queued / capacity).min(1.0),
+                underruns: health.underruns.load(Ordering::Relaxed),
+                overruns: health.overruns.load(Ordering::Relaxed),
+                latency_ms: (queued + spec.samples as f32) / spec.freq as f32 * 1000.0,
+            }
+        })
+    }
+
     pub(crate) fn set_output_device(&mut self, output_device_name: Option<String>) {
         if self.output_device_name != output_device_name {
-            if let Some(audio_device) = self.audio_device.take() {
-                let subsystem = audio_device.subsystem().clone();
-                let old_device_status = audio_device.status();
-                let desired_sample_rate = audio_device.spec().freq as u32;
-                let old_callback = audio_device.close_and_get_callback();
-
-                match Stream::new_audio_device(
-                    desired_sample_rate,
-                    &subsystem,
-                    &output_device_name,
-                    old_callback.0,
-                ) {
-                    Ok(audio_device) => {
-                        if old_device_status == AudioStatus::Playing {
-                            audio_device.resume();
-                        }
-                        self.output_device_name = output_device_name;
-                        self.audio_device = Some(audio_device);
-                    }
-                    Err(e) => {
-                        log::error!("Failed to set audio output device: {:?}", e);
+            self.reopen(output_device_name, self.channel_layout);
+        }
+    }
+
+    pub(crate) fn set_channel_layout(&mut self, channel_layout: ChannelLayout) {
+        if self.channel_layout != channel_layout {
+            self.reopen(self.output_device_name.clone(), channel_layout);
+        }
+    }
+
+    fn reopen(&mut self, output_device_name: Option<String>, channel_layout: ChannelLayout) {
+        if let Some(audio_device) = self.audio_device.take() {
+            let subsystem = audio_device.subsystem().clone();
+            let old_device_status = audio_device.status();
+            let desired_sample_rate = audio_device.spec().freq as u32;
+            // Closing the old device first makes sure there is never more than one stream open
+            let old_callback = audio_device.close_and_get_callback();
+
+            match Stream::new_audio_device(
+                desired_sample_rate,
+                &subsystem,
+                &output_device_name,
+                channel_layout,
+                old_callback.rx,
+                old_callback.handover,
+                old_callback.focused,
+            ) {
+                Ok(audio_device) => {
+                    if old_device_status == AudioStatus::Playing {
+                        audio_device.resume();
                     }
+                    self.output_device_name = output_device_name;
+                    self.channel_layout = channel_layout;
+                    self.audio_device = Some(audio_device);
+                }
+                Err(e) => {
+                    log::error!("Failed to reopen audio output device: {:?}", e);
                 }
             }
         }
@@ -202,6 +447,15 @@ impl Audio {
         }
     }
 
+    pub fn measure_audio_latency(&self) -> Option<f32> {
+        self.stream.measure_latency()
+    }
+
+    /// The fill level, underruns, overruns and actual latency of the output, `None` without an audio device
+    pub fn audio_stats(&self) -> Option<AudioStats> {
+        self.stream.audio_stats()
+    }
+
     pub fn sync_audio_devices(&mut self) {
         let available_device_names =
             Self::get_available_output_device_names_for_subsystem(&self.audio_subsystem);
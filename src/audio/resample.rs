@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+
+/// How the samples in between are interpolated. Better quality looks at more samples around each one.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum ResamplerQuality {
+    /// Linear, the cheapest, for weak hardware
+    Fast,
+    /// Cubic (Catmull-Rom)
+    #[default]
+    Balanced,
+    /// Windowed sinc (Lanczos)
+    High,
+}
+
+impl ResamplerQuality {
+    /// How many samples the interpolation needs on each side of a position
+    fn half_width(&self) -> usize {
+        match self {
+            ResamplerQuality::Fast => 1,
+            ResamplerQuality::Balanced => 2,
+            ResamplerQuality::High => LANCZOS_A,
+        }
+    }
+}
+
+const LANCZOS_A: usize = 4;
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < f32::EPSILON {
+        1.0
+    } else {
+        let x = x * std::f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+/// Stretches audio by interpolating between the samples. The position and the last samples are kept from one call to the next so frames join up without seams.
+pub struct Resampler {
+    quality: ResamplerQuality,
+    // In input samples relative to the first sample of the next input, negative positions are in `history`
+    position: f32,
+    // The end of the previous input, so the interpolation can look back across calls
+    history: Vec<f32>,
+    work: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(quality: ResamplerQuality) -> Self {
+        Self {
+            quality,
+            position: 0.0,
+            history: vec![0.0; quality.half_width() * 2],
+            work: Vec::new(),
+        }
+    }
+
+    pub fn quality(&self) -> ResamplerQuality {
+        self.quality
+    }
+
+    /// Appends `input` stretched by `ratio` to `output`, a ratio of 2.0 gives about twice as many samples.
+    pub fn process(&mut self, input: &[f32], ratio: f32, output: &mut Vec<f32>) {
+        if input.is_empty() {
+            return;
+        }
+        let half_width = self.quality.half_width();
+        let offset = self.history.len();
+        self.work.clear();
+        self.work.extend_from_slice(&self.history);
+        self.work.extend_from_slice(input);
+        let samples = &self.work;
+
+        let step = 1.0 / ratio;
+        let end = input.len() as f32 - half_width as f32;
+        while self.position < end {
+            let index = self.position.floor();
+            let fraction = self.position - index;
+            // Where `index` is in the work buffer
+            let i = (index as isize + offset as isize) as usize;
+            let sample = match self.quality {
+                ResamplerQuality::Fast => {
+                    let (from, to) = (samples[i], samples[i + 1]);
+                    from + (to - from) * fraction
+                }
+                ResamplerQuality::Balanced => {
+                    let (p0, p1, p2, p3) =
+                        (samples[i - 1], samples[i], samples[i + 1], samples[i + 2]);
+                    p1 + 0.5
+                        * fraction
+                        * (p2 - p0
+                            + fraction
+                                * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3
+                                    + fraction * (3.0 * (p1 - p2) + p3 - p0)))
+                }
+                ResamplerQuality::High => (1 - LANCZOS_A as isize..=LANCZOS_A as isize)
+                    .map(|k| {
+                        let x = fraction - k as f32;
+                        samples[(i as isize + k) as usize] * sinc(x) * sinc(x / LANCZOS_A as f32)
+                    })
+                    .sum(),
+            };
+            output.push(sample);
+            self.position += step;
+        }
+        self.position -= input.len() as f32;
+        let history_start = samples.len() - offset;
+        self.history.copy_from_slice(&samples[history_start..]);
+    }
+}
@@ -4,11 +4,16 @@ use std::{
     sync::OnceLock,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use directories::ProjectDirs;
 use serde::Deserialize;
+use serde_yaml::Value;
 
-use crate::{emulation::NesRegion, input::gui::InputButtonsVoca, settings::Settings};
+use crate::{
+    emulation::{save_state::SaveStateFormat, MatchEndWatch, NesRegion},
+    input::gui::InputButtonsVoca,
+    settings::{Settings, MAX_PLAYERS},
+};
 
 #[derive(Deserialize, Default, Debug)]
 pub struct Vocabulary {
@@ -32,8 +37,18 @@ pub struct BuildConfiguration {
     pub start_in_fullscreen: bool,
     #[serde(default = "Default::default")]
     pub vocabulary: Vocabulary,
+    /// Lets the GUI offer to leave when a match is over
+    #[serde(default)]
+    pub match_end: Option<MatchEndWatch>,
+    /// How many players the game reads, `None` is all of them. See [`BuildConfiguration::player_count`].
+    #[serde(default)]
+    pub players: Option<usize>,
+    /// How states are stored, see [`SaveStateFormat`]
+    #[serde(default)]
+    pub save_state_format: SaveStateFormat,
 
     #[cfg(feature = "netplay")]
+    #[serde(default = "Default::default")]
     pub netplay: crate::netplay::NetplayBuildConfiguration,
 }
 
@@ -43,6 +58,11 @@ impl BuildConfiguration {
             .first()
             .expect("at least one supported nes region")
     }
+    /// The inputs of the players after these are ignored, they'd do nothing in the game anyway
+    pub fn player_count(&self) -> usize {
+        self.players.unwrap_or(MAX_PLAYERS).clamp(1, MAX_PLAYERS)
+    }
+
     pub fn get_config_dir(&self) -> Option<PathBuf> {
         let path = ProjectDirs::from("", &self.manufacturer, &self.name)
             .map(|pd| pd.config_dir().to_path_buf());
@@ -55,6 +75,40 @@ impl BuildConfiguration {
     }
 }
 
+/// The file `default_settings_file` in config.yaml points to, checked by the build. Empty when there is none.
+const BUNDLED_DEFAULT_SETTINGS: &str =
+    include_str!(concat!(env!("OUT_DIR"), "/default-settings.yaml"));
+
+/// The file `gamepad_database_file` in config.yaml points to, checked by the build. Empty when there is none.
+pub const BUNDLED_GAMEPAD_DATABASE: &str =
+    include_str!(concat!(env!("OUT_DIR"), "/gamecontrollerdb.txt"));
+
+/// Uses the bundled default settings over the ones in config.yaml, settings it doesn't mention are kept.
+fn with_bundled_defaults(default_settings: &Settings) -> Result<Settings> {
+    fn merge(base: &mut Value, overrides: Value) {
+        match (base, overrides) {
+            (Value::Mapping(base), Value::Mapping(overrides)) => {
+                for (key, value) in overrides {
+                    match base.get_mut(&key) {
+                        Some(existing) => merge(existing, value),
+                        None => {
+                            base.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (base, overrides) => *base = overrides,
+        }
+    }
+    let mut settings = serde_yaml::to_value(default_settings)?;
+    merge(
+        &mut settings,
+        serde_yaml::from_str(BUNDLED_DEFAULT_SETTINGS)?,
+    );
+    serde_yaml::from_value(settings)
+        .map_err(|e| anyhow!("The bundled default settings are invalid: {e}"))
+}
+
 pub struct Bundle {
     pub settings_path: PathBuf,
     pub config: BuildConfiguration,
@@ -79,8 +133,11 @@ impl Bundle {
 
         // Try to load from external bundle first and if that doesn't work fall back to the embedded bundle
 
-        let config: BuildConfiguration =
+        let mut config: BuildConfiguration =
             external_config.unwrap_or(serde_yaml::from_str(include_str!("../config/config.yaml"))?);
+        if !BUNDLED_DEFAULT_SETTINGS.is_empty() {
+            config.default_settings = with_bundled_defaults(&config.default_settings)?;
+        }
 
         let rom = external_rom.unwrap_or(include_bytes!("../config/rom.nes").to_vec());
 
@@ -97,6 +154,7 @@ impl Bundle {
 
             #[cfg(feature = "netplay")]
             netplay_rom: fs::read(Path::new("netplay-rom.nes"))
+                .inspect(|_| log::info!("Using external netplay-rom.nes"))
                 .inspect_err(|e| log::info!("Not using external netplay-rom.nes: {:?}", e))
                 .unwrap_or(include_bytes!("../config/netplay-rom.nes").to_vec()),
         })
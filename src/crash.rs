@@ -0,0 +1,197 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, TryLockError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{
+    bundle::Bundle,
+    emulation::{replay::Movie, NesRegion, NesStateHandler},
+    input::{rom_id, JoypadState},
+    settings::MAX_PLAYERS,
+};
+
+/// A new checkpoint is taken this often, so a dump has between one and two intervals of inputs
+const CHECKPOINT_INTERVAL: usize = 600;
+
+/// A state to replay from and the inputs of every frame after it
+struct Checkpoint {
+    state: Vec<u8>,
+    inputs: Vec<[u8; MAX_PLAYERS]>,
+}
+
+/// What the emulation was doing, kept up to date so the panic hook can write it out.
+struct CrashContext {
+    // `None` for the bundled ROM
+    rom_hash: Option<String>,
+    region: Option<NesRegion>,
+    previous: Option<Checkpoint>,
+    current: Option<Checkpoint>,
+    netplay_phase: Option<&'static str>,
+    netplay_timeline: String,
+}
+
+static CONTEXT: Mutex<Option<CrashContext>> = Mutex::new(None);
+
+fn with_context(f: impl FnOnce(&mut CrashContext)) {
+    let mut context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(context) = context.as_mut() {
+        f(context);
+    }
+}
+
+/// Writes a crash dump on every panic, after the default hook printed it. See [`write_dump`].
+pub fn install_panic_hook() {
+    *CONTEXT.lock().unwrap_or_else(|e| e.into_inner()) = Some(CrashContext {
+        rom_hash: None,
+        region: None,
+        previous: None,
+        current: None,
+        netplay_phase: None,
+        netplay_timeline: String::new(),
+    });
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        // The panic might have happened while the context was locked, waiting for it would hang
+        let context = match CONTEXT.try_lock() {
+            Ok(context) => context,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                log::error!("No crash dump, the crash context is in use");
+                return;
+            }
+        };
+        if let Some(context) = context.as_ref() {
+            match write_dump(context, &info.to_string()) {
+                Ok(path) => log::error!("Wrote a crash dump to {:?}", path),
+                Err(e) => log::error!("Could not write a crash dump: {:?}", e),
+            }
+        }
+    }));
+}
+
+/// Called when another ROM is loaded. Movies always play the bundled ROM, so there are no inputs in the dump while another one runs.
+pub fn set_rom(rom: &[u8]) {
+    with_context(|context| {
+        context.rom_hash = (rom != Bundle::current().rom.as_slice()).then(|| rom_id(rom));
+        context.previous = None;
+        context.current = None;
+    });
+}
+
+/// Called before every frame with the inputs it's about to be run with.
+/// States that can't be replayed on their own (netplay) drop the checkpoints, their inputs alone wouldn't reproduce anything.
+pub fn before_frame(
+    nes_state: &impl NesStateHandler,
+    region: &NesRegion,
+    joypads: [JoypadState; MAX_PLAYERS],
+) {
+    with_context(|context| {
+        if context.rom_hash.is_some() {
+            return;
+        }
+        let needs_checkpoint = context.region.as_ref() != Some(region)
+            || context
+                .current
+                .as_ref()
+                .map_or(true, |current| current.inputs.len() >= CHECKPOINT_INTERVAL);
+        if needs_checkpoint {
+            let Some(state) = nes_state.snapshot() else {
+                context.previous = None;
+                context.current = None;
+                return;
+            };
+            if context.region.as_ref() != Some(region) {
+                context.region = Some(region.clone());
+                context.current = None;
+            }
+            context.previous = context.current.replace(Checkpoint {
+                state,
+                inputs: Vec::with_capacity(CHECKPOINT_INTERVAL),
+            });
+        }
+        if let Some(current) = &mut context.current {
+            current.inputs.push(joypads.map(|joypad| joypad.0));
+        }
+    });
+}
+
+/// Called when the netplay state changes
+#[cfg(feature = "netplay")]
+pub fn set_netplay(phase: &'static str, timeline: String) {
+    with_context(|context| {
+        context.netplay_phase = Some(phase);
+        context.netplay_timeline = timeline;
+    });
+}
+
+impl CrashContext {
+    /// The oldest checkpoint with all the inputs since, as something `--verify-replay` can play
+    fn movie(&self) -> Option<Movie> {
+        let oldest = self.previous.as_ref().or(self.current.as_ref())?;
+        let mut inputs = oldest.inputs.clone();
+        if let (Some(_), Some(current)) = (&self.previous, &self.current) {
+            inputs.extend_from_slice(&current.inputs);
+        }
+        Some(Movie {
+            region: self.region.clone()?,
+            state: oldest.state.clone(),
+            inputs,
+        })
+    }
+}
+
+/// Writes `crash-<time>.txt` to the settings directory, with the panic, the ROM hash and the netplay phase and timeline.
+/// When the last frames were played offline a `crash-<time>.movie` of them is written next to it.
+/// `--verify-replay <movie> <any md5>` plays it again, which panics again if the emulation was what crashed.
+fn write_dump(context: &CrashContext, panic: &str) -> Result<PathBuf> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let settings_path = &Bundle::current().settings_path;
+    let path = settings_path.join(format!("crash-{time}.txt"));
+
+    let mut report = String::new();
+    writeln!(report, "nes-bundler {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(report, "{panic}")?;
+    match &context.rom_hash {
+        Some(rom_hash) => writeln!(report, "ROM: {rom_hash} (loaded)")?,
+        None => writeln!(report, "ROM: {} (bundled)", rom_id(&Bundle::current().rom))?,
+    }
+    if let Some(phase) = context.netplay_phase {
+        writeln!(report, "Netplay: {phase}")?;
+        writeln!(report, "Netplay timeline:\n{}", context.netplay_timeline)?;
+    }
+    match context.movie() {
+        Some(movie) => {
+            let movie_path = settings_path.join(format!("crash-{time}.movie"));
+            movie.save(&movie_path)?;
+            writeln!(
+                report,
+                "The last {} frames are in {}",
+                movie.inputs.len(),
+                file_name(&movie_path)
+            )?;
+        }
+        None => writeln!(report, "No inputs, the last frames weren't played offline")?,
+    }
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+    writer.write_all(report.as_bytes())?;
+    writer.flush()?;
+    Ok(path)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
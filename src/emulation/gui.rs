@@ -1,8 +1,12 @@
-use std::sync::{mpsc::Sender, Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::Sender,
+    Arc, Mutex,
+};
 
-use crate::main_view::gui::GuiComponent;
+use crate::{main_view::gui::GuiComponent, settings::Settings};
 
-use super::{EmulatorCommand, StateHandler};
+use super::{pacing::TimingAccuracy, EmulatorCommand, NesStateHandler, StateHandler};
 
 #[cfg(feature = "debug")]
 struct DebugGui {
@@ -13,9 +17,16 @@ struct DebugGui {
     pub override_speed: bool,
 }
 
+/// What the battery saver shows when it's turned on in the GUI, every other frame
+const DEFAULT_BATTERY_SAVER_FPS: u8 = 30;
+
 pub struct EmulatorGui {
-    #[cfg(feature = "netplay")]
     nes_state: Arc<Mutex<StateHandler>>,
+    emulator_tx: Sender<EmulatorCommand>,
+    // Set by the emulation thread while the match is over, see `MatchEndWatch`
+    match_over: Arc<AtomicBool>,
+    // Set by the emulation thread while the frame rate follows the display, see `TimingAccuracy::Smooth`
+    timing_smoothed: Arc<AtomicBool>,
 
     #[cfg(feature = "netplay")]
     pub netplay_gui: crate::netplay::gui::NetplayGui,
@@ -23,23 +34,121 @@ pub struct EmulatorGui {
     debug_gui: DebugGui,
 }
 impl EmulatorGui {
-    #[allow(unused_variables)]
-    pub fn new(nes_state: Arc<Mutex<StateHandler>>, emulator_tx: Sender<EmulatorCommand>) -> Self {
+    pub fn new(
+        nes_state: Arc<Mutex<StateHandler>>,
+        emulator_tx: Sender<EmulatorCommand>,
+        match_over: Arc<AtomicBool>,
+        timing_smoothed: Arc<AtomicBool>,
+    ) -> Self {
         Self {
             #[cfg(feature = "netplay")]
             netplay_gui: crate::netplay::gui::NetplayGui::new(),
             #[cfg(feature = "debug")]
             debug_gui: DebugGui {
                 nes_state: nes_state.clone(),
-                emulator_tx,
+                emulator_tx: emulator_tx.clone(),
                 speed: 1.0,
                 override_speed: false,
             },
 
-            #[cfg(feature = "netplay")]
             nes_state,
+            emulator_tx,
+            match_over,
+            timing_smoothed,
         }
     }
+
+    /// The timing in effect, which is `Hardware` during netplay or when the display is too far off to smooth to
+    pub fn timing_accuracy(&self) -> TimingAccuracy {
+        if self.timing_smoothed.load(Ordering::Relaxed) {
+            TimingAccuracy::Smooth
+        } else {
+            TimingAccuracy::Hardware
+        }
+    }
+
+    /// Picks the timing accuracy, showing when another one is in effect
+    pub fn timing_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let timing_accuracy = &mut Settings::current_mut().timing_accuracy;
+            ui.label("Timing").on_hover_text(
+                "Hardware runs at the exact frame rate of the console, for speedruns. Smooth follows the display when it's close, without the occasional stutter.",
+            );
+            ui.radio_value(timing_accuracy, TimingAccuracy::Hardware, "Hardware");
+            ui.radio_value(timing_accuracy, TimingAccuracy::Smooth, "Smooth");
+            if *timing_accuracy != self.timing_accuracy() {
+                ui.label(format!("({:?} in effect)", self.timing_accuracy()))
+                    .on_hover_text("Netplay always runs at the console's frame rate, and displays that are too far off aren't smoothed to");
+            }
+        });
+    }
+
+    /// Turns the battery saver on and off and sets how many frames per second it shows, see `Settings::battery_saver_fps`
+    pub fn battery_saver_ui(&mut self, ui: &mut egui::Ui) {
+        let online = self.nes_state.lock().unwrap().is_online();
+        ui.horizontal(|ui| {
+            let battery_saver_fps = &mut Settings::current_mut().battery_saver_fps;
+            let mut enabled = battery_saver_fps.is_some();
+            if ui
+                .checkbox(&mut enabled, "Battery saver")
+                .on_hover_text(
+                    "Shows fewer frames to save battery, the game and the audio keep their speed",
+                )
+                .changed()
+            {
+                *battery_saver_fps = enabled.then_some(DEFAULT_BATTERY_SAVER_FPS);
+            }
+            if let Some(fps) = battery_saver_fps {
+                ui.add(egui::Slider::new(fps, 10..=30).suffix(" fps"));
+                if online {
+                    ui.label("(off during netplay)");
+                }
+            }
+        });
+    }
+
+    /// Shows and changes the emulation speed, it can't be changed during netplay.
+    pub fn speed_ui(&mut self, ui: &mut egui::Ui) {
+        let (mut percent, online) = {
+            let nes_state = self.nes_state.lock().unwrap();
+            (nes_state.speed_percent(), nes_state.is_online())
+        };
+        ui.horizontal(|ui| {
+            ui.label("Speed");
+            if ui
+                .add_enabled(
+                    !online,
+                    egui::Slider::new(&mut percent, 25..=400)
+                        .suffix("%")
+                        .logarithmic(true),
+                )
+                .on_disabled_hover_text("The speed can't be changed during netplay")
+                .changed()
+            {
+                let _ = self
+                    .emulator_tx
+                    .send(EmulatorCommand::SetSpeedPercent(percent));
+            }
+            if ui
+                .add_enabled(!online && percent != 100, egui::Button::new("Reset"))
+                .clicked()
+            {
+                let _ = self.emulator_tx.send(EmulatorCommand::SetSpeedPercent(100));
+            }
+        });
+    }
+
+    /// Text that should go to the clipboard, like the netplay diagnostics report when its key was pressed
+    pub fn take_clipboard_text(&mut self) -> Option<String> {
+        #[cfg(feature = "netplay")]
+        if std::mem::take(&mut self.netplay_gui.copy_diagnostics) {
+            log::info!("Copying the netplay diagnostics to the clipboard");
+            return Some(crate::netplay::report::diagnostics_report(
+                &self.nes_state.lock().unwrap(),
+            ));
+        }
+        None
+    }
 }
 #[cfg(feature = "debug")]
 impl DebugGui {
@@ -76,12 +185,30 @@ impl GuiComponent for EmulatorGui {
         self.debug_gui.ui(ui);
 
         #[cfg(feature = "netplay")]
-        self.netplay_gui.ui(ui, &mut self.nes_state.lock().unwrap());
+        {
+            self.netplay_gui.match_over = self.match_over.load(Ordering::Relaxed);
+            self.netplay_gui.ui(ui, &mut self.nes_state.lock().unwrap());
+        }
     }
 
     #[cfg(feature = "netplay")]
+    fn handle_event(&mut self, gui_event: &crate::main_view::gui::GuiEvent) {
+        self.netplay_gui.handle_event(gui_event);
+    }
+
     fn messages(&self) -> Option<Vec<String>> {
-        self.netplay_gui.messages(&self.nes_state.lock().unwrap())
+        #[cfg(feature = "netplay")]
+        let mut messages = self
+            .netplay_gui
+            .messages(&self.nes_state.lock().unwrap())
+            .unwrap_or_default();
+        #[cfg(not(feature = "netplay"))]
+        let mut messages = Vec::new();
+
+        if self.match_over.load(Ordering::Relaxed) {
+            messages.push("Match over".to_string());
+        }
+        (!messages.is_empty()).then_some(messages)
     }
 
     fn name(&self) -> Option<&str> {
@@ -0,0 +1,87 @@
+use anyhow::Result;
+
+use crate::{
+    audio::resample::Resampler,
+    input::JoypadState,
+    settings::{Settings, MAX_PLAYERS},
+};
+
+use super::{
+    new_state_handler, NESAudioFrame, NESBuffers, NESVideoFrame, NesStateHandler, StateHandler,
+};
+
+/// What one frame produced, borrowed until the next `advance`.
+pub struct HeadlessFrame<'a> {
+    /// RGBA, `NES_WIDTH` x `NES_HEIGHT`
+    pub video: &'a [u8],
+    /// Mono samples at `SAMPLE_RATE`, already stretched to the speed
+    pub audio: &'a [f32],
+}
+
+/// The emulation without a window, an audio device or a thread of its own, for frontends that bring their own.
+/// The frontend calls `advance` at the frame rate of the region (see [`NesRegion::to_fps`](super::NesRegion::to_fps)) and presents what comes back.
+pub struct Headless {
+    nes_state: StateHandler,
+    video: NESVideoFrame,
+    nes_audio: NESAudioFrame,
+    audio: NESAudioFrame,
+    resampler: Resampler,
+}
+
+impl Headless {
+    /// Starts the bundled ROM like the windowed mode does, with netplay if the feature is on.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            nes_state: new_state_handler()?,
+            video: NESVideoFrame::new(),
+            nes_audio: NESAudioFrame::new(),
+            audio: NESAudioFrame::new(),
+            resampler: Resampler::new(Settings::current().audio.resampler_quality),
+        })
+    }
+
+    /// Runs one frame with the inputs of both players.
+    pub fn advance(&mut self, joypads: [JoypadState; MAX_PLAYERS]) -> HeadlessFrame<'_> {
+        self.nes_audio.clear();
+        self.nes_state.advance(
+            joypads,
+            &mut NESBuffers {
+                video: Some(&mut self.video),
+                audio: Some(&mut self.nes_audio),
+            },
+        );
+        self.audio.clear();
+        self.resampler.process(
+            &self.nes_audio,
+            1.0 / self.nes_state.speed(),
+            &mut self.audio,
+        );
+        HeadlessFrame {
+            video: &self.video,
+            audio: &self.audio,
+        }
+    }
+
+    /// For everything else, like resetting, loading a ROM or starting netplay
+    pub fn state(&mut self) -> &mut StateHandler {
+        &mut self.nes_state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulation::{NES_HEIGHT, NES_WIDTH};
+
+    #[test]
+    fn advance_returns_a_frame() {
+        let mut headless = Headless::new().expect("the bundled ROM to start");
+        let mut samples = 0;
+        for _ in 0..10 {
+            let frame = headless.advance([JoypadState(0), JoypadState(0)]);
+            assert_eq!(frame.video.len(), (NES_WIDTH * NES_HEIGHT * 4) as usize);
+            samples += frame.audio.len();
+        }
+        assert!(samples > 0);
+    }
+}
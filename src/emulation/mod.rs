@@ -1,27 +1,40 @@
 use std::{
     ops::{Deref, DerefMut},
     sync::{
-        mpsc::{channel, Sender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender, TryRecvError},
         Arc, Mutex, RwLock,
     },
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use serde::{Deserialize, Serialize};
 
 use thingbuf::{Recycle, ThingBuf};
 
 use crate::{
-    audio::AudioSender,
+    audio::{resample::Resampler, AudioChannelHandover, AudioSender},
+    bundle::Bundle,
     fps::RateCounter,
-    input::JoypadState,
+    input::{InputLatch, JoypadButton, JoypadState},
     settings::{Settings, MAX_PLAYERS},
 };
 
 pub mod gui;
+#[allow(dead_code)]
+// For embedders, the windowed mode runs its own loop on top of the same state handler
+pub mod headless;
+pub mod pacing;
+pub mod replay;
+pub mod save_state;
 pub mod tetanes;
-use self::{gui::EmulatorGui, tetanes::TetanesNesState};
+use self::{
+    gui::EmulatorGui,
+    pacing::{battery_saver_interval, FixedTimestep, FramePacing, TimingAccuracy},
+    tetanes::TetanesNesState,
+};
 pub type LocalNesState = TetanesNesState;
 
 pub const NES_WIDTH: u32 = 256;
@@ -39,84 +52,398 @@ pub type StateHandler = crate::emulation::LocalNesState;
 pub enum EmulatorCommand {
     Reset(bool),
     SetSpeed(f32),
+    SetSpeedPercent(u32),
+    LoadRom(Vec<u8>),
+    Practice(replay::Movie),
+    SetFocused(bool),
+    /// The refresh rate of the display the window is on, in Hz. See [`TimingAccuracy::Smooth`].
+    SetDisplayRefreshRate(Option<f32>),
+    /// The embedder saw the match end, like the memory watch in the bundle configuration would
+    EndMatch,
+}
+/// Taps a button for player 1 during the first frames after the game (re)starts, to get past intros and logos.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash)]
+pub struct IntroSkip {
+    pub frames: u32,
+    pub button: JoypadButton,
+}
+
+impl IntroSkip {
+    /// Only depends on the frame so every start plays out the same. Released in between so the game sees separate presses.
+    fn apply(&self, frame: u32, joypad: &mut JoypadState) {
+        if frame < self.frames && frame % 8 < 2 {
+            joypad.0 |= self.button as u8;
+        }
+    }
+}
+
+/// Power cycles the game after a while, so a kiosk or demo machine goes back to the title screen for the next visitor.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash)]
+pub struct SessionTimeout {
+    /// Seconds without any button pressed on this machine
+    pub idle_secs: Option<u32>,
+    /// Seconds since the game was started or reset, no matter what's going on
+    pub max_secs: Option<u32>,
+}
+
+impl SessionTimeout {
+    /// What ran out, if anything
+    fn expired(&self, started: Instant, last_input: Instant) -> Option<&'static str> {
+        let is_over = |secs: Option<u32>, since: Instant| {
+            secs.is_some_and(|secs| since.elapsed() >= Duration::from_secs(secs as u64))
+        };
+        if is_over(self.idle_secs, last_input) {
+            Some("idle")
+        } else if is_over(self.max_secs, started) {
+            Some("total")
+        } else {
+            None
+        }
+    }
+}
+
+/// A memory location that holds `value` once a match is over, like the round counter of a fighting game.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct MatchEndWatch {
+    pub address: u16,
+    pub value: u8,
+}
+
+impl MatchEndWatch {
+    fn is_met(&self, nes_state: &impl NesStateHandler) -> bool {
+        nes_state.peek_confirmed(self.address) == Some(self.value)
+    }
+}
+
+/// Called on the emulation thread with the frame a match ended on.
+pub type MatchEndHook = Arc<dyn Fn(u32) + Send + Sync>;
+
+pub struct Emulator {
+    on_match_end: Option<MatchEndHook>,
 }
-pub struct Emulator {}
 pub const SAMPLE_RATE: f32 = 44_100.0;
+// About 3ms, enough to not click when the audio stops or starts at a pause
+const AUDIO_FADE_SAMPLES: usize = 128;
 
 impl Emulator {
     pub fn new() -> Result<Self> {
-        Ok(Self {})
+        Ok(Self { on_match_end: None })
+    }
+
+    /// Sets a hook that is called every time a match ends, see [`MatchEndWatch`].
+    #[allow(dead_code)] // For embedders that want to act on it outside of the GUI
+    pub fn set_on_match_end(&mut self, hook: Option<MatchEndHook>) {
+        self.on_match_end = hook;
     }
 
     pub async fn start_thread(
         &self,
-        audio_tx: AudioSender,
+        mut audio_tx: AudioSender,
+        audio_handover: AudioChannelHandover,
         inputs: Arc<RwLock<[JoypadState; MAX_PLAYERS]>>,
         frame_buffer: BufferPool,
     ) -> Result<(EmulatorGui, Sender<EmulatorCommand>)> {
-        #[cfg(not(feature = "netplay"))]
-        let nes_state = crate::emulation::LocalNesState::start_rom(
-            &crate::bundle::Bundle::current().rom,
-            true,
-            Settings::current_mut().get_nes_region(),
-        )?;
-
-        #[cfg(feature = "netplay")]
-        let nes_state = crate::netplay::NetplayStateHandler::new()?;
-
-        let nes_state = Arc::new(Mutex::new(nes_state));
+        let nes_state = Arc::new(Mutex::new(new_state_handler()?));
         let (command_tx, command_rx) = channel();
+        let match_over = Arc::new(AtomicBool::new(false));
+        let timing_smoothed = Arc::new(AtomicBool::new(false));
 
-        tokio::task::spawn_blocking({
+        spawn_blocking({
             let nes_state = nes_state.clone();
+            let match_over = match_over.clone();
+            let timing_smoothed = timing_smoothed.clone();
+            let on_match_end = self.on_match_end.clone();
+            let match_end_watch = Bundle::current().config.match_end;
 
             move || {
                 let mut audio_buffer = NESAudioFrame::new();
+                // What the emulation produced, before it's stretched to the current speed
+                let mut nes_audio = NESAudioFrame::new();
+                let mut resampler = Resampler::new(Settings::current().audio.resampler_quality);
                 let mut rate_counter = RateCounter::new();
+                let mut audio_latency = Settings::current().audio.latency;
+                let mut practice: Option<replay::Practice> = None;
+                let mut focused = true;
+                let mut paused = false;
+                let mut latency_test_pressed = false;
+                // Frames since the game was started or reset, for the intro skip
+                let mut frames_since_start: u32 = 0;
+                // For the session timeout, reset along with `frames_since_start`
+                let mut session_started = Instant::now();
+                let mut last_input = Instant::now();
+                let mut match_ended_by_embedder = false;
+                let mut fixed_timestep = FixedTimestep::new();
+                let mut input_latch = InputLatch::default();
+                // The region only changes with a reset (or a practice movie from another region)
+                let mut region = Settings::current_mut().get_nes_region().clone();
+                let mut display_hz = None;
+                let mut frames_since_shown: u32 = 0;
 
                 loop {
                     #[cfg(feature = "debug")]
                     puffin::profile_function!("Emulator loop");
 
+                    let (is_online, speed) = {
+                        let nes_state = nes_state.lock().unwrap();
+                        (nes_state.is_online(), nes_state.speed())
+                    };
+                    // Both peers have to run at the console's frame rate
+                    let timing_accuracy = if is_online {
+                        TimingAccuracy::Hardware
+                    } else {
+                        Settings::current().timing_accuracy
+                    };
+                    let fps = timing_accuracy.frame_rate(region.to_fps(), display_hz);
+                    // Smoothing is a slight change of speed, for the audio as well
+                    let timing_factor = fps / region.to_fps();
+                    timing_smoothed.store(fps != region.to_fps(), Ordering::Relaxed);
+                    // Netplay keeps the audio clock, the session adjusts its own cadence to the confirmed frames
+                    let use_fixed_timestep = Settings::current().frame_pacing
+                        == FramePacing::FixedTimestep
+                        && !is_online;
+                    if use_fixed_timestep {
+                        #[cfg(feature = "debug")]
+                        puffin::profile_scope!("wait for frame");
+
+                        fixed_timestep.wait(fps * speed);
+                    } else {
+                        fixed_timestep.restart();
+                    }
+
                     {
                         #[cfg(feature = "debug")]
                         puffin::profile_scope!("push audio");
 
                         log::trace!("Pushing {:} audio samples", audio_buffer.len());
                         for s in audio_buffer.iter() {
-                            let _ = audio_tx.send(*s);
+                            // With a fixed timestep the clock sets the pace, a full queue drops samples instead of holding the emulation back
+                            audio_handover.send(&audio_tx, *s, !use_fixed_timestep);
                         }
                     }
 
+                    let target_audio_latency = {
+                        let audio_settings = &Settings::current().audio;
+                        if is_online {
+                            audio_settings.netplay_latency
+                        } else {
+                            audio_settings.latency
+                        }
+                    };
+                    if target_audio_latency != audio_latency {
+                        audio_latency = target_audio_latency;
+                        // Replacing the sender drops the old one, which lets the audio callback move over once it has played the rest of it
+                        audio_tx =
+                            audio_handover.new_channel(Duration::from_millis(audio_latency as u64));
+                    }
+
                     {
                         #[cfg(feature = "debug")]
                         puffin::profile_scope!("advance");
 
-                        rate_counter.tick("Frame");
+                        let last_sample = audio_buffer.last().copied().unwrap_or(0.0);
                         audio_buffer.clear();
-                        let frame = frame_buffer.push_ref();
-                        if frame.is_err() {
-                            //TODO: If we get in a bad sync with vsync and drop a lot of frames then perhaps we can do something to yank things in place again?
-                            rate_counter.tick("Dropped frame");
-                        }
-                        for command in command_rx.try_iter() {
+                        loop {
+                            let command = match command_rx.try_recv() {
+                                Ok(command) => command,
+                                Err(TryRecvError::Empty) => break,
+                                // Nothing can send commands anymore, the app has stopped (or is restarting)
+                                Err(TryRecvError::Disconnected) => return,
+                            };
                             use EmulatorCommand::*;
                             match command {
                                 Reset(hard) => {
+                                    practice = None;
+                                    frames_since_start = 0;
+                                    session_started = Instant::now();
+                                    last_input = Instant::now();
+                                    match_ended_by_embedder = false;
+                                    region = Settings::current_mut().get_nes_region().clone();
                                     nes_state.lock().unwrap().reset(hard);
                                 }
                                 SetSpeed(speed) => {
                                     nes_state.lock().unwrap().set_speed(speed);
                                 }
+                                SetSpeedPercent(percent) => {
+                                    nes_state.lock().unwrap().set_speed_percent(percent);
+                                }
+                                LoadRom(rom) => {
+                                    practice = None;
+                                    frames_since_start = 0;
+                                    session_started = Instant::now();
+                                    last_input = Instant::now();
+                                    match_ended_by_embedder = false;
+                                    match nes_state.lock().unwrap().load_rom(&rom) {
+                                        Ok(()) => crate::crash::set_rom(&rom),
+                                        Err(e) => log::error!("Failed to load ROM: {:?}", e),
+                                    }
+                                }
+                                Practice(movie) => {
+                                    match nes_state
+                                        .lock()
+                                        .unwrap()
+                                        .start_from_state(&movie.region, &movie.state)
+                                    {
+                                        Ok(()) => {
+                                            log::info!("Practicing against a recorded opponent");
+                                            // The recording starts past the intro
+                                            frames_since_start = u32::MAX;
+                                            region = movie.region.clone();
+                                            practice = Some(replay::Practice::new(movie));
+                                        }
+                                        Err(e) => log::error!("Failed to start practice: {:?}", e),
+                                    }
+                                }
+                                SetFocused(is_focused) => {
+                                    focused = is_focused;
+                                }
+                                SetDisplayRefreshRate(refresh_rate) => {
+                                    display_hz = refresh_rate;
+                                }
+                                EndMatch => {
+                                    match_ended_by_embedder = true;
+                                }
+                            }
+                        }
+                        let mut nes_state = nes_state.lock().unwrap();
+                        let mut joypads = *inputs.read().unwrap();
+                        if nes_state.is_online() && practice.take().is_some() {
+                            log::info!("Practice stopped since netplay started");
+                        }
+                        // What the players pressed, not what the latch or practice make of it
+                        if joypads.iter().any(|joypad| joypad.0 != 0) {
+                            last_input = Instant::now();
+                        }
+                        let session_timeout = Settings::current().session_timeout;
+                        if let Some(expired) = session_timeout
+                            .and_then(|timeout| timeout.expired(session_started, last_input))
+                        {
+                            log::info!("Session timed out ({expired}), starting over");
+                            nes_state.go_offline();
+                            practice = None;
+                            frames_since_start = 0;
+                            match_ended_by_embedder = false;
+                            session_started = Instant::now();
+                            last_input = Instant::now();
+                            region = Settings::current_mut().get_nes_region().clone();
+                            nes_state.reset(true);
+                        }
+
+                        if !focused
+                            && Settings::current().pause_when_unfocused
+                            && nes_state.can_pause()
+                        {
+                            if !paused {
+                                log::info!("Window lost focus, pausing");
+                                paused = true;
+                                // Fade out from where the audio was instead of cutting it off
+                                audio_buffer.extend(
+                                    (0..AUDIO_FADE_SAMPLES).rev().map(|i| {
+                                        last_sample * i as f32 / AUDIO_FADE_SAMPLES as f32
+                                    }),
+                                );
                             }
+                            drop(nes_state);
+                            std::thread::sleep(Duration::from_millis(10));
+                            continue;
+                        }
+
+                        rate_counter.tick("Frame");
+                        // The skipped frames are still emulated, so the game and the audio keep their speed
+                        let show_every = if nes_state.is_online() {
+                            1
+                        } else {
+                            battery_saver_interval(
+                                region.to_fps(),
+                                Settings::current().battery_saver_fps,
+                            )
+                        };
+                        frames_since_shown += 1;
+                        let show = frames_since_shown >= show_every;
+                        if show {
+                            frames_since_shown = 0;
+                        }
+                        let mut frame = show.then(|| frame_buffer.push_ref());
+                        if matches!(frame, Some(Err(_))) {
+                            //TODO: If we get in a bad sync with vsync and drop a lot of frames then perhaps we can do something to yank things in place again?
+                            rate_counter.tick("Dropped frame");
+                        }
+                        // Looked at before anything (practice, netplay) gets to change the inputs
+                        let flash = Settings::current()
+                            .latency_test_button
+                            .is_some_and(|button| {
+                                let was_pressed = latency_test_pressed;
+                                latency_test_pressed = joypads[0].is_pressed(button);
+                                latency_test_pressed && !was_pressed
+                            });
+                        // Netplay has to send exactly what was pressed, both sides run the same inputs
+                        if !nes_state.is_online() {
+                            let player_count = Bundle::current().config.player_count();
+                            for joypad in joypads.iter_mut().skip(player_count) {
+                                *joypad = JoypadState(0);
+                            }
+                            let latch_frames = Settings::current().input.latch_frames;
+                            input_latch.apply(latch_frames, &mut joypads);
+                        }
+                        if let Some(practice) = &mut practice {
+                            joypads[1] = practice.next_opponent_input();
+                        }
+                        // Injected inputs would desync a netplay session
+                        if let (Some(intro_skip), false) =
+                            (Settings::current().intro_skip, nes_state.is_online())
+                        {
+                            intro_skip.apply(frames_since_start, &mut joypads[0]);
                         }
-                        nes_state.lock().unwrap().advance(
-                            *inputs.read().unwrap(),
+                        frames_since_start = frames_since_start.saturating_add(1);
+                        crate::crash::before_frame(&*nes_state, &region, joypads);
+                        nes_audio.clear();
+                        nes_state.advance(
+                            joypads,
                             &mut NESBuffers {
-                                video: frame.ok().as_deref_mut(),
-                                audio: Some(&mut audio_buffer),
+                                video: frame
+                                    .as_mut()
+                                    .and_then(|frame| frame.as_mut().ok())
+                                    .map(|frame| &mut **frame),
+                                audio: Some(&mut nes_audio),
                             },
                         );
+                        if flash {
+                            // Drawn over the output only, the emulated state never sees it
+                            if let Some(Ok(frame)) = &mut frame {
+                                frame.fill(255);
+                            }
+                        }
+                        let is_match_over = match_ended_by_embedder
+                            || match_end_watch.is_some_and(|watch| watch.is_met(&*nes_state));
+                        // Only the moment it ends is an event, the watch stays met until the next match starts
+                        if is_match_over && !match_over.swap(true, Ordering::Relaxed) {
+                            let frame = nes_state.confirmed_frame().unwrap_or(nes_state.frame());
+                            log::info!("Match over at frame {frame}");
+                            if let Some(on_match_end) = &on_match_end {
+                                on_match_end(frame);
+                            }
+                        } else if !is_match_over {
+                            match_over.store(false, Ordering::Relaxed);
+                        }
+                        let resampler_quality = Settings::current().audio.resampler_quality;
+                        if resampler.quality() != resampler_quality {
+                            log::debug!("Switching resampler quality to {resampler_quality:?}");
+                            resampler = Resampler::new(resampler_quality);
+                        }
+                        // Stretching is the only part that changes with the speed, the emulated audio is the same every run
+                        resampler.process(
+                            &nes_audio,
+                            1.0 / (nes_state.speed() * timing_factor),
+                            &mut audio_buffer,
+                        );
+                        if paused {
+                            log::info!("Resuming");
+                            paused = false;
+                            for (i, sample) in
+                                audio_buffer.iter_mut().take(AUDIO_FADE_SAMPLES).enumerate()
+                            {
+                                *sample *= i as f32 / AUDIO_FADE_SAMPLES as f32;
+                            }
+                        }
                     }
 
                     if let Some(report) = rate_counter.report() {
@@ -134,16 +461,74 @@ impl Emulator {
                 }
             }
         });
-        Ok((EmulatorGui::new(nes_state, command_tx.clone()), command_tx))
+        Ok((
+            EmulatorGui::new(nes_state, command_tx.clone(), match_over, timing_smoothed),
+            command_tx,
+        ))
     }
 }
 
+/// The bundled ROM started from power-on, wrapped for netplay if the feature is on.
+fn new_state_handler() -> Result<StateHandler> {
+    #[cfg(not(feature = "netplay"))]
+    let nes_state = crate::emulation::LocalNesState::start_rom(
+        &Bundle::current().rom,
+        true,
+        Settings::current_mut().get_nes_region(),
+    )?;
+
+    #[cfg(feature = "netplay")]
+    let nes_state = crate::netplay::NetplayStateHandler::new()?;
+
+    Ok(nes_state)
+}
+
+/// Netplay runs the emulation on the blocking pool of the async runtime, without netplay there is no runtime.
+fn spawn_blocking(f: impl FnOnce() + Send + 'static) {
+    #[cfg(feature = "netplay")]
+    tokio::task::spawn_blocking(f);
+    #[cfg(not(feature = "netplay"))]
+    std::thread::spawn(f);
+}
+
 pub trait NesStateHandler {
     fn advance(&mut self, joypad_state: [JoypadState; MAX_PLAYERS], buffers: &mut NESBuffers);
     fn reset(&mut self, hard: bool);
     fn set_speed(&mut self, speed: f32);
+    fn speed(&self) -> f32;
+    /// The speed in percent, 100 being normal speed
+    fn speed_percent(&self) -> u32 {
+        (self.speed() * 100.0).round() as u32
+    }
+    fn set_speed_percent(&mut self, percent: u32) {
+        self.set_speed(percent as f32 / 100.0);
+    }
     fn save_sram(&self) -> Option<&[u8]>;
+    /// The frame being presented
     fn frame(&self) -> u32;
+    /// The latest frame that can't be rolled back anymore. During netplay it's counted from the start of the session, so both players agree on it.
+    fn confirmed_frame(&self) -> Option<u32>;
+    /// A byte of memory as of the latest frame that can't be rolled back anymore, `None` while there is none.
+    fn peek_confirmed(&self, address: u16) -> Option<u8>;
+    fn load_rom(&mut self, rom: &[u8]) -> Result<()>;
+    /// True while playing with someone else over the network
+    fn is_online(&self) -> bool;
+    /// False when the state has to keep advancing, like while a netplay session is being set up or played
+    fn can_pause(&self) -> bool;
+    /// Restarts the bundled ROM from a saved state
+    fn start_from_state(&mut self, region: &NesRegion, state: &[u8]) -> Result<()>;
+    /// A saved state of the current frame that replays on its own, `None` when the inputs alone don't decide what happens next (like in netplay)
+    fn snapshot(&self) -> Option<Vec<u8>>;
+    /// Ends any netplay session (telling the other player) or attempt at one. Only netplay has anything to leave.
+    fn go_offline(&mut self) {}
+}
+
+/// Checks that the data starts with an iNES (or NES 2.0) header.
+pub fn validate_rom(rom: &[u8]) -> Result<()> {
+    if rom.len() < 16 || &rom[0..4] != b"NES\x1a" {
+        return Err(anyhow!("Not a NES ROM (missing iNES header)"));
+    }
+    Ok(())
 }
 
 #[derive(Clone, Serialize, Deserialize, Hash, Debug, PartialEq)]
@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// How frames are timed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum FramePacing {
+    /// Presents in step with the display refresh (when the bundle enables vsync) and lets the audio queue pace the emulation
+    #[default]
+    Vsync,
+    /// Runs the emulation on its own clock at the frame rate of the region and presents as soon as possible,
+    /// so a 144Hz display still gets 60Hz emulation. Netplay always falls back to the audio clock.
+    FixedTimestep,
+}
+
+/// How close the frame rate follows the console.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum TimingAccuracy {
+    /// The exact frame rate of the region (60.0988Hz for NTSC), which stutters now and then on a 60Hz display. Always used in netplay.
+    #[default]
+    Hardware,
+    /// Runs at the display refresh rate when it's within `MAX_SMOOTHING` of the region (or of a multiple of it), a bit off from the console but without stutter
+    Smooth,
+}
+
+/// Smoothing never changes the speed more than this, 60Hz is 0.16% off from NTSC
+const MAX_SMOOTHING: f32 = 0.01;
+
+impl TimingAccuracy {
+    /// The frame rate to run a region with `region_fps` at, on a display refreshing at `display_hz` (if known)
+    pub fn frame_rate(&self, region_fps: f32, display_hz: Option<f32>) -> f32 {
+        match (self, display_hz) {
+            (TimingAccuracy::Smooth, Some(display_hz)) => {
+                // A 120Hz display shows every frame twice, so 60Hz is what has to match
+                let refreshes_per_frame = (display_hz / region_fps).round().max(1.0);
+                let smoothed = display_hz / refreshes_per_frame;
+                if ((smoothed - region_fps) / region_fps).abs() <= MAX_SMOOTHING {
+                    smoothed
+                } else {
+                    region_fps
+                }
+            }
+            _ => region_fps,
+        }
+    }
+}
+
+/// How many emulated frames go by for every one that's shown, for showing about `target_fps`. `None` shows them all.
+pub fn battery_saver_interval(region_fps: f32, target_fps: Option<u8>) -> u32 {
+    match target_fps {
+        Some(target_fps) if target_fps > 0 => {
+            (region_fps / target_fps as f32).round().max(1.0) as u32
+        }
+        _ => 1,
+    }
+}
+
+// Further behind than this and the clock starts over instead of running frames back to back to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Sleeps until the next frame is due, see [`FramePacing::FixedTimestep`].
+pub struct FixedTimestep {
+    next_frame: Option<Instant>,
+}
+
+impl FixedTimestep {
+    pub fn new() -> Self {
+        Self { next_frame: None }
+    }
+
+    /// Forgets the schedule, the next `wait` returns right away
+    pub fn restart(&mut self) {
+        self.next_frame = None;
+    }
+
+    /// Waits for the next frame of a clock running at `fps`.
+    pub fn wait(&mut self, fps: f32) {
+        let frame_time = Duration::from_secs_f32(1.0 / fps);
+        let now = Instant::now();
+        let next_frame = match self.next_frame {
+            Some(next_frame) if now <= next_frame + MAX_LAG => next_frame,
+            Some(_) => {
+                log::debug!("Frame clock fell behind, starting over");
+                now
+            }
+            None => now,
+        };
+        if let Some(sleep) = next_frame.checked_duration_since(now) {
+            std::thread::sleep(sleep);
+        }
+        // Counting from the schedule and not from when we woke up keeps the rate exact on average
+        self.next_frame = Some(next_frame + frame_time);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use anyhow::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::{LocalNesState, NESBuffers, NesRegion, NesStateHandler};
+use crate::{bundle::Bundle, input::JoypadState, settings::MAX_PLAYERS};
+
+// Hours of inputs, a broken file can't make it allocate more than this
+const MAX_MOVIE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// A recorded play session of the bundled ROM: the state to start from and the joypad inputs for every frame after it.
+#[derive(Serialize, Deserialize)]
+pub struct Movie {
+    pub region: NesRegion,
+    pub state: Vec<u8>,
+    pub inputs: Vec<[u8; MAX_PLAYERS]>,
+}
+
+impl Movie {
+    fn options() -> impl Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_MOVIE_SIZE)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::options()
+            .deserialize_from(BufReader::new(File::open(path)?))
+            .map_err(anyhow::Error::msg)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        Self::options()
+            .serialize_into(BufWriter::new(File::create(path)?), self)
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Plays the movie headless and returns the md5 of the resulting state.
+    pub fn play(&self) -> Result<String> {
+        let mut nes_state = LocalNesState::start_rom(&Bundle::current().rom, false, &self.region)?;
+        nes_state.load_state(&self.state)?;
+
+        for inputs in &self.inputs {
+            nes_state.advance(
+                inputs.map(JoypadState),
+                &mut NESBuffers {
+                    audio: None,
+                    video: None,
+                },
+            );
+        }
+        Ok(format!("{:x}", md5::compute(nes_state.save_state()?)))
+    }
+}
+
+/// Feeds the second player from a movie while the first one is played live.
+pub struct Practice {
+    movie: Movie,
+    frame: usize,
+}
+
+impl Practice {
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, frame: 0 }
+    }
+
+    /// The recorded input of the opponent for the next frame, they let go of everything once the recording ends.
+    pub fn next_opponent_input(&mut self) -> JoypadState {
+        let input = self.movie.inputs.get(self.frame).map(|inputs| inputs[1]);
+        if input.is_none() && self.frame == self.movie.inputs.len() {
+            log::info!("Practice recording ended after {} frames", self.frame);
+        }
+        self.frame += 1;
+        JoypadState(input.unwrap_or(0))
+    }
+}
+
+/// Plays the movie at `movie_path` and checks that it ends up in the state with the md5 `expected_md5`.
+pub fn verify_replay(movie_path: &Path, expected_md5: &str) -> Result<bool> {
+    let movie = Movie::load(movie_path)?;
+    let actual_md5 = movie.play()?;
+    if actual_md5.eq_ignore_ascii_case(expected_md5) {
+        log::info!("Replay verified after {} frames", movie.inputs.len());
+        Ok(true)
+    } else {
+        log::error!(
+            "Replay mismatch after {} frames: expected {expected_md5} but got {actual_md5}",
+            movie.inputs.len()
+        );
+        Ok(false)
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::Display;
+
+use bincode::Options;
+use serde::Deserialize;
+use tetanes_core::cpu::Cpu;
+
+use crate::bundle::Bundle;
+
+/// Saved states start with this, states from before the header (version 0) don't.
+const MAGIC: &[u8; 4] = b"NBST";
+/// Bump this when the serialized state changes in a way old states can't be read as
+pub const SAVE_STATE_VERSION: u16 = 1;
+/// The version of `ReadableFormat`, the high bit keeps it apart from the compact versions
+pub const READABLE_STATE_VERSION: u16 = 0x8001;
+/// No valid state comes close, it keeps a broken length field from allocating everything
+const MAX_STATE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    /// Made by another version of the bundle that this one can't read
+    IncompatibleVersion {
+        found: u16,
+        supported: u16,
+    },
+    Malformed(String),
+}
+
+impl Display for SaveStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveStateError::IncompatibleVersion { found, supported } => write!(
+                f,
+                "The save state is from an incompatible version (version {found}, this version reads up to {supported})"
+            ),
+            SaveStateError::Malformed(reason) => write!(f, "The save state is broken ({reason})"),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+/// How the emulator state is turned into bytes after the header. Everything that stores or compares states
+/// (save states, movies, crash dumps, the netplay confirmed states and desync checks) goes through it.
+/// Both players of a session have to use the same one, their states are compared byte by byte.
+pub trait StateFormat: Send + Sync {
+    /// Written into the header. Has to be unique per format and change whenever its output does, a state is
+    /// only read back by the format with the version it was saved with.
+    fn version(&self) -> u16;
+    /// The same state has to encode to the same bytes every time
+    fn encode(&self, state: &Cpu) -> Result<Vec<u8>, SaveStateError>;
+    fn decode(&self, payload: &[u8]) -> Result<Cpu, SaveStateError>;
+}
+
+/// Fixed size bincode, the default. Version 0 states (from before the header) have the same payload.
+pub struct CompactFormat;
+
+impl CompactFormat {
+    fn options() -> impl Options {
+        // The same encoding as `bincode::serialize`, with a size limit
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_STATE_SIZE)
+    }
+}
+
+impl StateFormat for CompactFormat {
+    fn version(&self) -> u16 {
+        SAVE_STATE_VERSION
+    }
+
+    fn encode(&self, state: &Cpu) -> Result<Vec<u8>, SaveStateError> {
+        Self::options()
+            .serialize(state)
+            .map_err(|e| SaveStateError::Malformed(e.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Cpu, SaveStateError> {
+        Self::options()
+            .deserialize(payload)
+            .map_err(|e| SaveStateError::Malformed(e.to_string()))
+    }
+}
+
+/// YAML, many times the size of the compact format but it can be read and diffed, like when looking into a desync.
+pub struct ReadableFormat;
+
+impl StateFormat for ReadableFormat {
+    fn version(&self) -> u16 {
+        READABLE_STATE_VERSION
+    }
+
+    fn encode(&self, state: &Cpu) -> Result<Vec<u8>, SaveStateError> {
+        serde_yaml::to_string(state)
+            .map(String::into_bytes)
+            .map_err(|e| SaveStateError::Malformed(e.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Cpu, SaveStateError> {
+        serde_yaml::from_slice(payload).map_err(|e| SaveStateError::Malformed(e.to_string()))
+    }
+}
+
+/// The format states are saved in, `save_state_format` in the bundle config. Both players of a session have the same bundle,
+/// so they always agree on it. States of either format load whichever is picked.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum SaveStateFormat {
+    #[default]
+    Compact,
+    Readable,
+}
+
+impl SaveStateFormat {
+    fn format(&self) -> &'static dyn StateFormat {
+        match self {
+            SaveStateFormat::Compact => &CompactFormat,
+            SaveStateFormat::Readable => &ReadableFormat,
+        }
+    }
+}
+
+fn format() -> &'static dyn StateFormat {
+    Bundle::current().config.save_state_format.format()
+}
+
+fn serialize_with(format: &dyn StateFormat, state: &Cpu) -> Result<Vec<u8>, SaveStateError> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&format.version().to_le_bytes());
+    data.extend_from_slice(&format.encode(state)?);
+    Ok(data)
+}
+
+pub fn serialize(state: &Cpu) -> Result<Vec<u8>, SaveStateError> {
+    serialize_with(format(), state)
+}
+
+pub fn deserialize(data: &[u8]) -> Result<Cpu, SaveStateError> {
+    let (version, payload) = match data.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 2 => (u16::from_le_bytes([rest[0], rest[1]]), &rest[2..]),
+        Some(_) => return Err(SaveStateError::Malformed("truncated header".to_string())),
+        None => (0, data),
+    };
+    match version {
+        SAVE_STATE_VERSION => CompactFormat.decode(payload),
+        READABLE_STATE_VERSION => ReadableFormat.decode(payload),
+        // Version 0 has the same payload, only the header is missing
+        0 => CompactFormat.decode(payload),
+        found => Err(SaveStateError::IncompatibleVersion {
+            found,
+            supported: format().version(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bundle::Bundle,
+        emulation::{LocalNesState, NESBuffers, NesStateHandler},
+        input::JoypadState,
+    };
+
+    fn saved_state() -> Vec<u8> {
+        let bundle = Bundle::current();
+        let mut nes_state =
+            LocalNesState::start_rom(&bundle.rom, false, bundle.config.get_default_region())
+                .expect("the bundled ROM to start");
+        for frame in 0..60u8 {
+            nes_state.advance(
+                [JoypadState(frame), JoypadState(0)],
+                &mut NESBuffers {
+                    audio: None,
+                    video: None,
+                },
+            );
+        }
+        nes_state.save_state().expect("the state to save")
+    }
+
+    #[test]
+    fn round_trip_is_byte_stable() {
+        let saved = saved_state();
+        assert!(saved.starts_with(MAGIC));
+        assert_eq!(&saved[4..6], &SAVE_STATE_VERSION.to_le_bytes());
+        let state = deserialize(&saved).expect("the state to load");
+        assert_eq!(serialize(&state).expect("the state to save"), saved);
+    }
+
+    #[test]
+    fn headerless_version_0_states_load() {
+        let saved = saved_state();
+        let version_0 = &saved[MAGIC.len() + 2..];
+        let state = deserialize(version_0).expect("the version 0 state to load");
+        // Saving it again adds the header, the payload stays the same
+        assert_eq!(serialize(&state).expect("the state to save"), saved);
+    }
+
+    #[test]
+    fn readable_states_load_back_the_same() {
+        let state = deserialize(&saved_state()).expect("the state to load");
+        let readable = serialize_with(&ReadableFormat, &state).expect("the state to save");
+        assert_eq!(&readable[4..6], &READABLE_STATE_VERSION.to_le_bytes());
+        assert!(std::str::from_utf8(&readable[6..]).is_ok());
+        let loaded = deserialize(&readable).expect("the readable state to load");
+        assert_eq!(
+            serialize_with(&ReadableFormat, &loaded).expect("the state to save"),
+            readable
+        );
+        assert_eq!(
+            serialize_with(&CompactFormat, &loaded).expect("the state to save"),
+            serialize_with(&CompactFormat, &state).expect("the state to save")
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut saved = saved_state();
+        saved[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            deserialize(&saved),
+            Err(SaveStateError::IncompatibleVersion { found, supported })
+                if found == SAVE_STATE_VERSION + 1 && supported == SAVE_STATE_VERSION
+        ));
+        assert!(matches!(
+            deserialize(&saved[..MAGIC.len() + 1]),
+            Err(SaveStateError::Malformed(_))
+        ));
+    }
+}
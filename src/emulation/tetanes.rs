@@ -9,11 +9,11 @@ use tetanes_core::{
     cpu::Cpu,
     fs,
     input::{FourPlayer, Joypad, Player},
-    mem::RamState,
+    mem::{Access, RamState, Read},
     video::VideoFilter,
 };
 
-use super::{NESBuffers, NesStateHandler, NTSC_PAL, SAMPLE_RATE};
+use super::{save_state, NESBuffers, NesStateHandler, NTSC_PAL, SAMPLE_RATE};
 use crate::{
     bundle::Bundle,
     input::JoypadState,
@@ -23,6 +23,10 @@ use crate::{
 #[derive(Clone)]
 pub struct TetanesNesState {
     control_deck: ControlDeck,
+    // Only the SRAM of the bundled ROM is loaded from and saved to the settings
+    persist_sram: bool,
+    // Not part of the emulated state, the audio is stretched to it afterwards
+    speed: f32,
 }
 
 trait ToTetanesRegion {
@@ -84,11 +88,44 @@ impl TetanesNesState {
         }
 
         control_deck.set_region(region);
-        let mut s = Self { control_deck };
-        s.set_speed(1.0); // Trigger the correct sample rate
+        let mut s = Self {
+            control_deck,
+            persist_sram: load_sram,
+            speed: 1.0,
+        };
+        s.set_sample_rate();
         Ok(s)
     }
 
+    /// The APU always runs at the same sample rate, so the samples only depend on the emulated state.
+    fn set_sample_rate(&mut self) {
+        let apu = &mut self.control_deck.cpu_mut().bus.apu;
+        let sample_rate = match apu.region {
+            // Downsample a tiny bit extra to match the most common screen refresh rate (60hz)
+            NesRegion::Ntsc => SAMPLE_RATE * (crate::emulation::NesRegion::Ntsc.to_fps() / 60.0),
+            _ => SAMPLE_RATE,
+        };
+        apu.filter_chain = FilterChain::new(apu.region, sample_rate);
+        apu.sample_period = Cpu::region_clock_rate(apu.region) / sample_rate;
+    }
+
+    /// Reads the CPU address space without side effects, like a Game Genie sees it.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.control_deck.cpu().peek(address, Access::Dummy)
+    }
+
+    /// A versioned state, see [`save_state`](super::save_state).
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        Ok(save_state::serialize(self.control_deck.cpu())?)
+    }
+
+    /// Fails with a [`SaveStateError`](super::save_state::SaveStateError) for states it can't read, like ones from an incompatible version.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let state = save_state::deserialize(state)?;
+        self.control_deck.load_cpu(state);
+        Ok(())
+    }
+
     pub fn clock_frame_into(&mut self, buffers: &mut NESBuffers) -> Result<usize> {
         #[cfg(feature = "debug")]
         puffin::profile_function!();
@@ -163,23 +200,16 @@ impl TetanesNesState {
 impl NesStateHandler for TetanesNesState {
     fn set_speed(&mut self, speed: f32) {
         let speed = speed.max(0.005);
-        let apu = &mut self.control_deck.cpu_mut().bus.apu;
-        let target_sample_rate = match apu.region {
-            // Downsample a tiny bit extra to match the most common screen refresh rate (60hz)
-            NesRegion::Ntsc => SAMPLE_RATE * (crate::emulation::NesRegion::Ntsc.to_fps() / 60.0),
-            _ => SAMPLE_RATE,
-        };
-
-        let new_sample_rate = target_sample_rate * (1.0 / speed);
-        let new_sample_period = Cpu::region_clock_rate(apu.region) / new_sample_rate;
-
-        if apu.sample_period != new_sample_period {
+        if self.speed != speed {
             log::debug!("Change emulation speed to {speed}x");
-            apu.filter_chain = FilterChain::new(apu.region, new_sample_rate);
-            apu.sample_period = new_sample_period;
+            self.speed = speed;
         }
     }
 
+    fn speed(&self) -> f32 {
+        self.speed
+    }
+
     fn advance(&mut self, joypad_state: [JoypadState; MAX_PLAYERS], buffers: &mut NESBuffers) {
         *self.control_deck.joypad_mut(Player::One) = Joypad::from_bytes((*joypad_state[0]).into());
         *self.control_deck.joypad_mut(Player::Two) = Joypad::from_bytes((*joypad_state[1]).into());
@@ -189,6 +219,9 @@ impl NesStateHandler for TetanesNesState {
     }
 
     fn save_sram(&self) -> Option<&[u8]> {
+        if !self.persist_sram {
+            return None;
+        }
         if let Some(true) = self.control_deck.cart_battery_backed() {
             Some(self.control_deck.sram())
         } else {
@@ -196,6 +229,15 @@ impl NesStateHandler for TetanesNesState {
         }
     }
 
+    fn confirmed_frame(&self) -> Option<u32> {
+        // Nothing is predicted offline
+        Some(self.frame())
+    }
+
+    fn peek_confirmed(&self, address: u16) -> Option<u8> {
+        Some(self.peek(address))
+    }
+
     fn frame(&self) -> u32 {
         self.control_deck.frame_number()
     }
@@ -210,5 +252,37 @@ impl NesStateHandler for TetanesNesState {
         self.control_deck
             .set_region(Settings::current_mut().get_nes_region().to_tetanes_region());
         self.control_deck.reset(kind);
+        self.set_sample_rate();
+    }
+
+    fn load_rom(&mut self, rom: &[u8]) -> Result<()> {
+        super::validate_rom(rom)?;
+        *self = Self::start_rom(rom, false, Settings::current_mut().get_nes_region())?;
+        Ok(())
+    }
+
+    fn is_online(&self) -> bool {
+        false
+    }
+
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    fn start_from_state(
+        &mut self,
+        region: &crate::emulation::NesRegion,
+        state: &[u8],
+    ) -> Result<()> {
+        let mut nes_state = Self::start_rom(&Bundle::current().rom, false, region)?;
+        nes_state.load_state(state)?;
+        *self = nes_state;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        self.save_state()
+            .inspect_err(|e| log::warn!("Could not save a snapshot: {:?}", e))
+            .ok()
     }
 }
@@ -4,6 +4,17 @@ pub trait ToGamepadButton {
     fn to_gamepad_button(&self) -> Option<GamepadButton>;
 }
 
+pub trait ToGamepadAxis {
+    fn to_gamepad_axis(&self) -> Option<GamepadAxis>;
+}
+
+/// The analog axes that can drive the D-pad
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GamepadAxis {
+    LeftX,
+    LeftY,
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub enum GamepadButton {
     A,
@@ -1,4 +1,7 @@
-use super::{buttons::GamepadButton, InputId, JoypadMapping, JoypadState};
+use super::{
+    buttons::{GamepadAxis, GamepadButton},
+    InputId, JoypadMapping, JoypadState,
+};
 use std::collections::HashSet;
 
 pub type JoypadGamepadMapping = JoypadMapping<GamepadButton>;
@@ -7,6 +10,8 @@ pub trait GamepadState {
     fn is_connected(&self) -> bool;
     fn get_pressed_buttons(&self) -> &HashSet<GamepadButton>;
     fn toogle_button(&mut self, button: &GamepadButton, on: bool);
+    /// `value` goes from -1.0 to 1.0, `deadzone` from 0.0 to 1.0
+    fn move_axis(&mut self, axis: &GamepadAxis, value: f32, deadzone: f32, diagonal_threshold: f32);
 }
 
 pub trait Gamepads {
@@ -31,6 +36,39 @@ pub enum GamepadEvent {
         which: InputId,
         button: GamepadButton,
     },
+    AxisMotion {
+        which: InputId,
+        axis: GamepadAxis,
+        value: f32,
+    },
+}
+
+/// The D-pad buttons a stick position counts as. Inside the deadzone it's neutral, outside it's one of eight directions.
+/// See `InputSettings::stick_diagonal_threshold` for `diagonal_threshold`.
+pub fn stick_to_dpad(
+    x: f32,
+    y: f32,
+    deadzone: f32,
+    diagonal_threshold: f32,
+) -> HashSet<GamepadButton> {
+    let mut buttons = HashSet::new();
+    let magnitude = (x * x + y * y).sqrt();
+    if magnitude <= deadzone {
+        return buttons;
+    }
+    let threshold = magnitude * diagonal_threshold;
+    if x < -threshold {
+        buttons.insert(GamepadButton::DPadLeft);
+    } else if x > threshold {
+        buttons.insert(GamepadButton::DPadRight);
+    }
+    // Positive y is down, like SDL reports it
+    if y < -threshold {
+        buttons.insert(GamepadButton::DPadUp);
+    } else if y > threshold {
+        buttons.insert(GamepadButton::DPadDown);
+    }
+    buttons
 }
 
 pub trait ToGamepadEvent {
@@ -59,6 +59,7 @@ impl InputsGui {
         input_settings: &mut InputSettings,
         player: usize,
         mapping_request: &mut Option<MapRequest>,
+        rom_id: &str,
     ) {
         ui.label(format!("Player {}", player + 1));
         let selected_text = input_settings
@@ -78,6 +79,22 @@ impl InputsGui {
                 }
             });
 
+        if let crate::input::InputConfigurationKind::Gamepad(_) =
+            input_settings.get_selected_configuration(player).kind
+        {
+            let id = input_settings.selected[player].clone();
+            let mut deadzone = input_settings.stick_deadzone(&id);
+            ui.horizontal(|ui| {
+                ui.label("Stick deadzone");
+                if ui
+                    .add(egui::Slider::new(&mut deadzone, 0..=90).suffix("%"))
+                    .changed()
+                {
+                    input_settings.stick_deadzones.insert(id, deadzone);
+                }
+            });
+        }
+
         let input_configuration = input_settings.get_selected_configuration_mut(player);
         Grid::new(format!("joypadmap_grid_{}", player))
             .num_columns(2)
@@ -96,6 +113,37 @@ impl InputsGui {
                         );
                     });
             });
+
+        // Not tied to the input, it changes what the game gets whichever input is selected
+        egui::CollapsingHeader::new("NES buttons")
+            .id_source(format!("button-remap-{player}"))
+            .show(ui, |ui| {
+                let mut remap = input_settings.button_remap(rom_id, player);
+                Grid::new(format!("button_remap_grid_{}", player))
+                    .num_columns(2)
+                    .striped(true)
+                    .show(ui, |ui| {
+                        use JoypadButton::*;
+                        for button in [Up, Down, Left, Right, Select, Start, B, A] {
+                            ui.label(format!("{button}"));
+                            let sent_as = remap.lookup(&button);
+                            let mut selected = sent_as.unwrap_or(button);
+                            egui::ComboBox::from_id_source(format!(
+                                "button-remap-{player}-{button:?}"
+                            ))
+                            .selected_text(format!("{selected}"))
+                            .show_ui(ui, |ui| {
+                                for target in [Up, Down, Left, Right, Select, Start, B, A] {
+                                    ui.selectable_value(&mut selected, target, format!("{target}"));
+                                }
+                            });
+                            // Sent as itself is the same as not remapped
+                            *sent_as = (selected != button).then_some(selected);
+                            ui.end_row();
+                        }
+                    });
+                input_settings.set_button_remap(rom_id, player, remap);
+            });
     }
 
     fn button_map_ui(
@@ -173,6 +221,7 @@ impl GuiComponent for InputsGui {
                     input_settings,
                     0,
                     &mut self.mapping_request,
+                    &instance.rom_id,
                 );
             });
             ui.vertical(|ui| {
@@ -183,10 +232,18 @@ impl GuiComponent for InputsGui {
                     input_settings,
                     1,
                     &mut self.mapping_request,
+                    &instance.rom_id,
                 );
             });
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Hold presses for").on_hover_text(
+                "Short taps still reach the game when frames are dropped. Not used in netplay.",
+            );
+            ui.add(egui::Slider::new(&mut input_settings.latch_frames, 0..=8).suffix(" frames"));
+        });
+
         self.inputs
             .remap_configuration(&mut self.mapping_request, input_settings);
     }
@@ -31,7 +31,7 @@ pub enum KeyEvent {
     ModifiersChanged(Modifiers),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum JoypadButton {
     Up = 0b00010000,
     Down = 0b00100000,
@@ -128,6 +128,78 @@ where
     }
 }
 
+impl<KeyType> Default for JoypadMapping<KeyType> {
+    fn default() -> Self {
+        Self {
+            up: None,
+            down: None,
+            left: None,
+            right: None,
+
+            select: None,
+            start: None,
+
+            b: None,
+            a: None,
+        }
+    }
+}
+
+/// Which NES button each NES button is sent as, like A and B swapped. Buttons that aren't mapped are sent as themselves.
+/// It's applied to the local input before it goes anywhere, so in netplay it only changes what this player sends.
+pub type ButtonRemap = JoypadMapping<JoypadButton>;
+
+impl ButtonRemap {
+    pub fn apply(&self, joypad_state: JoypadState) -> JoypadState {
+        JoypadState(
+            [
+                (JoypadButton::Up, self.up),
+                (JoypadButton::Down, self.down),
+                (JoypadButton::Left, self.left),
+                (JoypadButton::Right, self.right),
+                (JoypadButton::Select, self.select),
+                (JoypadButton::Start, self.start),
+                (JoypadButton::B, self.b),
+                (JoypadButton::A, self.a),
+            ]
+            .into_iter()
+            .filter(|(button, _)| joypad_state.is_pressed(*button))
+            .fold(0_u8, |acc, (button, sent_as)| {
+                acc | sent_as.unwrap_or(button) as u8
+            }),
+        )
+    }
+}
+
+/// Keeps a pressed button held for a minimum number of frames, see `InputSettings::latch_frames`.
+#[derive(Default)]
+pub struct InputLatch {
+    // Per player and button, how many frames the button has been sent as pressed
+    held_for: [[u8; 8]; MAX_PLAYERS],
+}
+
+impl InputLatch {
+    /// Called once per frame with the inputs after the bindings and remaps. With 0 frames it changes nothing.
+    pub fn apply(&mut self, frames: u8, joypads: &mut [JoypadState; MAX_PLAYERS]) {
+        for (joypad, held_for) in joypads.iter_mut().zip(&mut self.held_for) {
+            for (bit, held_for) in held_for.iter_mut().enumerate() {
+                let mask = 1 << bit;
+                if joypad.0 & mask != 0 || (*held_for > 0 && *held_for < frames) {
+                    joypad.0 |= mask;
+                    *held_for = held_for.saturating_add(1);
+                } else {
+                    *held_for = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Identifies a ROM in the settings, for the settings that are per game
+pub fn rom_id(rom: &[u8]) -> String {
+    format!("{:x}", md5::compute(rom))
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct JoypadState(pub u8);
 
@@ -172,6 +244,8 @@ pub struct Inputs {
     keyboards: Keyboards,
     gamepads: GamepadImpl,
     pub joypads: [JoypadState; MAX_PLAYERS],
+    /// The ROM that is playing, see [`rom_id`]. Picks the button remaps.
+    pub rom_id: String,
 }
 
 impl Inputs {
@@ -182,6 +256,7 @@ impl Inputs {
             keyboards,
             gamepads,
             joypads: [JoypadState(0), JoypadState(0)],
+            rom_id: rom_id(&Bundle::current().rom),
         }
     }
 
@@ -202,8 +277,8 @@ impl Inputs {
         let pad2 =
             self.get_joypad_for_input_configuration(input_settings.get_selected_configuration(1));
 
-        self.joypads[0] = pad1;
-        self.joypads[1] = pad2;
+        self.joypads[0] = input_settings.button_remap(&self.rom_id, 0).apply(pad1);
+        self.joypads[1] = input_settings.button_remap(&self.rom_id, 1).apply(pad2);
     }
 
     pub fn get_joypad(&self, player: usize) -> JoypadState {
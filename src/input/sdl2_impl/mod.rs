@@ -1,16 +1,23 @@
-use super::buttons::ToGamepadButton;
+use super::buttons::{GamepadAxis, ToGamepadAxis, ToGamepadButton};
+use super::gamepad::stick_to_dpad;
 use super::{buttons::GamepadButton, InputId, JoypadState};
 use super::{InputConfiguration, ToInputId};
+use crate::bundle::{Bundle, BUNDLED_GAMEPAD_DATABASE};
 use crate::input::{self, InputConfigurationKind};
 use crate::settings::Settings;
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 use sdl2::{controller::GameController, GameControllerSubsystem};
 
 use super::gamepad::{GamepadEvent, GamepadState, Gamepads, JoypadGamepadMapping, ToGamepadEvent};
 
 pub struct Sdl2GamepadState {
+    // Both the real buttons and the D-pad buttons the stick is pushed towards
     pub pressed_buttons: HashSet<GamepadButton>,
+    buttons: HashSet<GamepadButton>,
+    stick: (f32, f32),
+    stick_buttons: HashSet<GamepadButton>,
     game_controller: GameController,
 }
 
@@ -18,9 +25,16 @@ impl Sdl2GamepadState {
     pub fn new(game_controller: GameController) -> Self {
         Self {
             pressed_buttons: HashSet::new(),
+            buttons: HashSet::new(),
+            stick: (0.0, 0.0),
+            stick_buttons: HashSet::new(),
             game_controller,
         }
     }
+
+    fn update_pressed_buttons(&mut self) {
+        self.pressed_buttons = &self.buttons | &self.stick_buttons;
+    }
 }
 
 impl ToInputId for u32 {
@@ -40,10 +54,27 @@ impl GamepadState for Sdl2GamepadState {
 
     fn toogle_button(&mut self, button: &GamepadButton, pressed: bool) {
         if pressed {
-            self.pressed_buttons.insert(*button);
+            self.buttons.insert(*button);
         } else {
-            self.pressed_buttons.remove(button);
+            self.buttons.remove(button);
         }
+        self.update_pressed_buttons();
+    }
+
+    fn move_axis(
+        &mut self,
+        axis: &GamepadAxis,
+        value: f32,
+        deadzone: f32,
+        diagonal_threshold: f32,
+    ) {
+        match axis {
+            GamepadAxis::LeftX => self.stick.0 = value,
+            GamepadAxis::LeftY => self.stick.1 = value,
+        }
+        let (x, y) = self.stick;
+        self.stick_buttons = stick_to_dpad(x, y, deadzone, diagonal_threshold);
+        self.update_pressed_buttons();
     }
 }
 pub struct Sdl2Gamepads {
@@ -100,6 +131,18 @@ impl Gamepads for Sdl2Gamepads {
                     log::warn!("Button up on unmapped gamepad {:?}", which);
                 }
             }
+            GamepadEvent::AxisMotion { which, axis, value } => {
+                let (deadzone, diagonal_threshold) = {
+                    let input_settings = &Settings::current().input;
+                    (
+                        input_settings.stick_deadzone(&Self::to_gamepad_id(which)) as f32 / 100.0,
+                        input_settings.stick_diagonal_threshold,
+                    )
+                };
+                if let Some(gamepad_state) = self.get_gamepad(which.clone()) {
+                    gamepad_state.move_axis(axis, *value, deadzone, diagonal_threshold);
+                }
+            }
             _ => (),
         }
     }
@@ -110,12 +153,52 @@ impl Sdl2Gamepads {
     }
 
     pub fn new(game_controller_subsystem: GameControllerSubsystem) -> Self {
+        Self::load_gamepad_databases(&game_controller_subsystem);
         Sdl2Gamepads {
             game_controller_subsystem,
             all: HashMap::new(),
         }
     }
 
+    /// Adds mappings to the ones SDL knows, the bundled database first so the files of the player win for the same gamepad (by GUID).
+    /// Gamepads that are in none of them can't be used, SDL only reports the gamepads it has a mapping for.
+    fn load_gamepad_databases(game_controller_subsystem: &GameControllerSubsystem) {
+        let bundled = BUNDLED_GAMEPAD_DATABASE
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter(|mapping| {
+                game_controller_subsystem
+                    .add_mapping(mapping)
+                    .inspect_err(|e| log::warn!("Skipping bundled gamepad mapping: {e}"))
+                    .is_ok()
+            })
+            .count();
+        if bundled > 0 {
+            log::info!("Loaded {bundled} bundled gamepad mappings");
+        }
+
+        for path in [
+            Path::new("gamecontrollerdb.txt").to_path_buf(),
+            Bundle::current().settings_path.join("gamecontrollerdb.txt"),
+        ] {
+            if path.exists() {
+                match game_controller_subsystem.load_mappings(&path) {
+                    Ok(count) => log::info!("Loaded {count} gamepad mappings from {:?}", path),
+                    Err(e) => log::warn!("Could not load the gamepad mappings in {:?}: {e}", path),
+                }
+            }
+        }
+
+        for id in 0..game_controller_subsystem.num_joysticks().unwrap_or(0) {
+            if !game_controller_subsystem.is_game_controller(id) {
+                log::warn!(
+                    "Joystick {id} has no gamepad mapping and can't be used, add it to a gamecontrollerdb.txt"
+                );
+            }
+        }
+    }
+
     fn get_gamepad(&mut self, id: InputId) -> Option<&mut Box<dyn GamepadState>> {
         self.all.get_mut(&Self::to_gamepad_id(&id))
     }
@@ -185,6 +268,23 @@ impl ToGamepadEvent for sdl2::event::Event {
                     which: which.to_input_id(),
                     button,
                 }),
+            sdl2::event::Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => axis.to_gamepad_axis().map(|axis| GamepadEvent::AxisMotion {
+                which: which.to_input_id(),
+                axis,
+                value: (*value as f32 / i16::MAX as f32).max(-1.0),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl ToGamepadAxis for sdl2::controller::Axis {
+    fn to_gamepad_axis(&self) -> Option<GamepadAxis> {
+        match self {
+            sdl2::controller::Axis::LeftX => Some(GamepadAxis::LeftX),
+            sdl2::controller::Axis::LeftY => Some(GamepadAxis::LeftY),
             _ => None,
         }
     }
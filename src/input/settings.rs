@@ -1,16 +1,38 @@
 use super::MAX_PLAYERS;
-use crate::input::{gamepad::JoypadGamepadMapping, InputConfiguration, InputId, Inputs};
+use crate::input::{
+    gamepad::JoypadGamepadMapping, ButtonRemap, InputConfiguration, InputId, Inputs,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, hash::Hash};
 
+pub const DEFAULT_STICK_DEADZONE: u8 = 25;
+/// sin(22.5°), splits the stick into eight equally sized directions
+pub const DEFAULT_STICK_DIAGONAL_THRESHOLD: f32 = 0.382_683_43;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputSettings {
     pub selected: [InputId; MAX_PLAYERS],
     pub configurations: BTreeMap<InputId, InputConfiguration>,
     pub default_gamepad_mapping: JoypadGamepadMapping,
+    /// Analog stick deadzone in % per gamepad, gamepads not in here use `DEFAULT_STICK_DEADZONE`
+    #[serde(default)]
+    pub stick_deadzones: BTreeMap<InputId, u8>,
+    /// How far (as a part of how far the stick is pushed) the stick has to go sideways to also press that direction, lower makes diagonals easier
+    #[serde(default = "InputSettings::default_stick_diagonal_threshold")]
+    pub stick_diagonal_threshold: f32,
+    /// NES button remaps per player, by ROM (see `rom_id`). On top of the bindings of whatever input is selected.
+    #[serde(default)]
+    pub button_remaps: BTreeMap<String, [ButtonRemap; MAX_PLAYERS]>,
+    /// Holds every press for at least this many frames so short taps aren't lost when frames are dropped, 0 turns it off. Never used in netplay.
+    #[serde(default)]
+    pub latch_frames: u8,
 }
 
 impl InputSettings {
+    fn default_stick_diagonal_threshold() -> f32 {
+        DEFAULT_STICK_DIAGONAL_THRESHOLD
+    }
+
     pub fn get_or_create_config(
         &mut self,
         id: InputId,
@@ -26,6 +48,33 @@ impl InputSettings {
         self.configurations.get_mut(&self.selected[idx]).unwrap()
     }
 
+    pub fn stick_deadzone(&self, id: &InputId) -> u8 {
+        self.stick_deadzones
+            .get(id)
+            .copied()
+            .unwrap_or(DEFAULT_STICK_DEADZONE)
+            .min(100)
+    }
+
+    pub fn button_remap(&self, rom_id: &str, player: usize) -> ButtonRemap {
+        self.button_remaps
+            .get(rom_id)
+            .map(|remaps| remaps[player])
+            .unwrap_or_default()
+    }
+
+    /// Only touches the settings when the remap changes, a ROM left without remaps is dropped again
+    pub fn set_button_remap(&mut self, rom_id: &str, player: usize, remap: ButtonRemap) {
+        if self.button_remap(rom_id, player) == remap {
+            return;
+        }
+        let remaps = self.button_remaps.entry(rom_id.to_string()).or_default();
+        remaps[player] = remap;
+        if remaps.iter().all(|remap| *remap == ButtonRemap::default()) {
+            self.button_remaps.remove(rom_id);
+        }
+    }
+
     pub(crate) fn reset_selected_disconnected_inputs(&mut self, inputs: &Inputs) {
         let input_conf = self.get_selected_configuration(0);
         if !inputs.is_connected(input_conf) {
@@ -48,5 +97,9 @@ impl Hash for InputSettings {
             k.hash(state);
             v.hash(state);
         }
+        self.stick_deadzones.hash(state);
+        self.stick_diagonal_threshold.to_bits().hash(state);
+        self.button_remaps.hash(state);
+        self.latch_frames.hash(state);
     }
 }
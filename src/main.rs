@@ -25,11 +25,13 @@ use emulation::{NES_HEIGHT, NES_WIDTH_4_3};
 use window::create_window;
 use winit::event::{Event, StartCause, WindowEvent};
 use winit::event_loop::EventLoop;
+use winit::platform::run_on_demand::EventLoopExtRunOnDemand;
 
 use crate::main_view::gui::GuiEvent;
 
 mod audio;
 mod bundle;
+mod crash;
 mod emulation;
 mod fps;
 mod gui;
@@ -38,13 +40,37 @@ mod integer_scaling;
 mod main_view;
 #[cfg(feature = "netplay")]
 mod netplay;
+mod rom_hashes;
+mod rom_info;
 mod settings;
+mod storage;
 mod window;
 
-#[tokio::main(worker_threads = 1)]
-async fn main() {
+fn main() {
     init_logger();
+    crash::install_panic_hook();
+
+    // Only netplay needs an async runtime, the renderer setup can be driven by a plain executor
+    #[cfg(not(feature = "netplay"))]
+    futures::executor::block_on(start());
 
+    #[cfg(feature = "netplay")]
+    match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime.block_on(start()),
+        Err(e) => {
+            let e = anyhow::Error::from(e).context("Could not start the async runtime");
+            log::error!("nes-bundler failed to start :(\n{:?}", e);
+            show_error_screen(&e, false);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn start() {
     #[cfg(feature = "netplay")]
     if std::env::args()
         .collect::<String>()
@@ -56,21 +82,122 @@ async fn main() {
         std::process::exit(0);
     }
 
+    let args: Vec<String> = std::env::args().collect();
+    #[cfg(feature = "netplay")]
+    if let Some(idx) = args.iter().position(|arg| arg == "--benchmark-rollback") {
+        let frames = args
+            .get(idx + 1)
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(600);
+        let rollback_depth = args.get(idx + 2).and_then(|a| a.parse().ok()).unwrap_or(7);
+        if let Err(e) = netplay::benchmark::benchmark_rollback(frames, rollback_depth) {
+            log::error!("Benchmark failed: {:?}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    #[cfg(feature = "netplay")]
+    if let Some(idx) = args
+        .iter()
+        .position(|arg| arg == "--benchmark-confirmed-states")
+    {
+        let states = args.get(idx + 1).and_then(|a| a.parse().ok()).unwrap_or(32);
+        let interval = args.get(idx + 2).and_then(|a| a.parse().ok()).unwrap_or(16);
+        if let Err(e) = netplay::benchmark::benchmark_confirmed_states(states, interval) {
+            log::error!("Benchmark failed: {:?}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(idx) = args.iter().position(|arg| arg == "--verify-replay") {
+        let (Some(movie_path), Some(expected_md5)) = (args.get(idx + 1), args.get(idx + 2)) else {
+            eprintln!("Usage: --verify-replay <movie file> <expected md5>");
+            std::process::exit(2);
+        };
+        match emulation::replay::verify_replay(std::path::Path::new(movie_path), expected_md5) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                log::error!("Failed to verify replay: {:?}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
     log::info!("NES Bundler is starting!");
 
-    if let Err(e) = run().await {
-        log::error!("nes-bundler failed to run :(\n{:?}", e)
+    let mut event_loop = match EventLoop::new() {
+        Ok(event_loop) => event_loop,
+        Err(e) => {
+            let e = anyhow::Error::from(e);
+            log::error!("nes-bundler failed to start :(\n{:?}", e);
+            show_error_screen(&e, false);
+            std::process::exit(1);
+        }
+    };
+    while let Err(e) = run(&mut event_loop).await {
+        log::error!("nes-bundler failed to run :(\n{:?}", e);
+        if !show_error_screen(&e, true) {
+            std::process::exit(1);
+        }
+        log::info!("Retrying after error");
     }
     std::process::exit(0);
 }
 
-async fn run() -> anyhow::Result<()> {
-    let event_loop = EventLoop::new()?;
+/// In Hz, `None` when the platform doesn't tell
+fn display_refresh_rate(window: &winit::window::Window) -> Option<f32> {
+    window
+        .current_monitor()
+        .and_then(|monitor| monitor.refresh_rate_millihertz())
+        .map(|millihertz| millihertz as f32 / 1000.0)
+}
+
+/// Shows the error in a message box, since stderr isn't visible on all platforms. Returns true if the user wants to retry.
+fn show_error_screen(error: &anyhow::Error, can_retry: bool) -> bool {
+    use sdl2::messagebox::{
+        show_message_box, ButtonData, ClickedButton, MessageBoxButtonFlag, MessageBoxFlag,
+    };
+    const QUIT: i32 = 0;
+    const RETRY: i32 = 1;
+
+    let mut buttons = vec![ButtonData {
+        flags: MessageBoxButtonFlag::ESCAPEKEY_DEFAULT,
+        button_id: QUIT,
+        text: "Quit",
+    }];
+    if can_retry {
+        buttons.push(ButtonData {
+            flags: MessageBoxButtonFlag::RETURNKEY_DEFAULT,
+            button_id: RETRY,
+            text: "Retry",
+        });
+    }
+    match show_message_box(
+        MessageBoxFlag::ERROR,
+        &buttons,
+        &format!("{} failed", Bundle::current().config.name),
+        &format!("Something went wrong and the game had to stop.\n\n{error:#}"),
+        None,
+        None,
+    ) {
+        Ok(ClickedButton::CustomButton(button)) => button.button_id == RETRY,
+        Ok(ClickedButton::CloseButton) => false,
+        Err(e) => {
+            log::error!("Could not show the error: {:?}", e);
+            false
+        }
+    }
+}
+
+async fn run(event_loop: &mut EventLoop<()>) -> anyhow::Result<()> {
     let window = Arc::new(create_window(
         &Bundle::current().config.name,
         MINIMUM_INTEGER_SCALING_SIZE,
         Size::new(NES_WIDTH_4_3, NES_HEIGHT),
-        &event_loop,
+        event_loop,
     )?);
 
     // Needed because: https://github.com/libsdl-org/SDL/issues/5380#issuecomment-1071626081
@@ -91,6 +218,7 @@ async fn run() -> anyhow::Result<()> {
         sdl_context.game_controller().map_err(anyhow::Error::msg)?,
     ));
     let audio_tx = audio.stream.start()?;
+    let audio_handover = audio.stream.handover();
 
     let renderer = Renderer::new(window.clone()).await?;
 
@@ -101,10 +229,28 @@ async fn run() -> anyhow::Result<()> {
     let shared_inputs = Arc::new(RwLock::new([JoypadState(0); MAX_PLAYERS]));
     let frame_buffer = BufferPool::new();
     let (mut emulator_gui, emulator_tx) = emulator
-        .start_thread(audio_tx, shared_inputs.clone(), frame_buffer.clone())
+        .start_thread(
+            audio_tx,
+            audio_handover,
+            shared_inputs.clone(),
+            frame_buffer.clone(),
+        )
         .await?;
 
-    let mut main_view = MainView::new(renderer, emulator_tx);
+    if let Some(idx) = std::env::args().position(|arg| arg == "--practice") {
+        match std::env::args()
+            .nth(idx + 1)
+            .map(|path| emulation::replay::Movie::load(std::path::Path::new(&path)))
+        {
+            Some(Ok(movie)) => {
+                let _ = emulator_tx.send(emulation::EmulatorCommand::Practice(movie));
+            }
+            Some(Err(e)) => log::error!("Could not load practice movie: {:?}", e),
+            None => log::error!("Usage: --practice <movie file>"),
+        }
+    }
+
+    let mut main_view = MainView::new(renderer, emulator_tx.clone());
 
     let mouse_hide_timeout = Duration::from_secs(1);
     let mut last_mouse_touch = Instant::now()
@@ -112,7 +258,8 @@ async fn run() -> anyhow::Result<()> {
         .expect("there to be an instant `mouse_hide_timeout` seconds in the past");
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
-    event_loop.run(|winit_event, control_flow| {
+    // Runs on demand so a failed run can be retried with the same event loop
+    event_loop.run_on_demand(|winit_event, control_flow| {
         let mut need_render = false;
         use crate::window::Fullscreen;
         match &winit_event {
@@ -120,6 +267,9 @@ async fn run() -> anyhow::Result<()> {
                 if Bundle::current().config.start_in_fullscreen {
                     window.toggle_fullscreen();
                 }
+                let _ = emulator_tx.send(emulation::EmulatorCommand::SetDisplayRefreshRate(
+                    display_refresh_rate(&window),
+                ));
             }
             Event::WindowEvent {
                 event: window_event,
@@ -138,6 +288,17 @@ async fn run() -> anyhow::Result<()> {
                     WindowEvent::MouseInput { .. } | WindowEvent::CursorMoved { .. } => {
                         last_mouse_touch = Instant::now();
                     }
+                    // The window might be on another display now
+                    WindowEvent::Moved(_) | WindowEvent::ScaleFactorChanged { .. } => {
+                        let _ =
+                            emulator_tx.send(emulation::EmulatorCommand::SetDisplayRefreshRate(
+                                display_refresh_rate(&window),
+                            ));
+                    }
+                    WindowEvent::Focused(focused) => {
+                        audio_gui.audio.stream.set_focused(*focused);
+                        let _ = emulator_tx.send(emulation::EmulatorCommand::SetFocused(*focused));
+                    }
                     _ => {}
                 }
                 main_view.handle_window_event(
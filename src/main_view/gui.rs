@@ -1,4 +1,5 @@
 use std::{
+    path::Path,
     sync::{mpsc::Sender, Arc, OnceLock, RwLock},
     time::{Duration, Instant},
 };
@@ -11,10 +12,13 @@ use winit::dpi::LogicalSize;
 use crate::{
     audio::gui::AudioGui,
     bundle::Bundle,
-    emulation::{gui::EmulatorGui, EmulatorCommand},
+    emulation::{gui::EmulatorGui, validate_rom, EmulatorCommand},
     gui::{esc_pressed, MenuButton},
-    input::{gamepad::GamepadEvent, gui::InputsGui, KeyEvent},
+    input::{gamepad::GamepadEvent, gui::InputsGui, rom_id, KeyEvent},
+    rom_hashes,
+    rom_info::RomInfo,
     settings::Settings,
+    storage,
 };
 
 pub trait ToGuiEvent {
@@ -56,6 +60,7 @@ pub struct MainGui {
     start_time: Instant,
     window: Arc<winit::window::Window>,
     emulator_tx: Sender<EmulatorCommand>,
+    bundled_rom_id: String,
 }
 
 impl MainGui {
@@ -83,6 +88,60 @@ impl MainGui {
             start_time: Instant::now(),
             window,
             emulator_tx,
+            bundled_rom_id: rom_id(&Bundle::current().rom),
+        }
+    }
+
+    /// Returns the id of the ROM when it's loading, see [`rom_id`]
+    pub fn load_rom(&self, path: &Path) -> Option<String> {
+        match rom_hashes::read_rom(path)
+            .and_then(|(rom, rom_id)| validate_rom(&rom).map(|_| (rom, rom_id)))
+        {
+            Ok((rom, rom_id)) => {
+                log::info!("Loading ROM {:?}", path);
+                // Tried anyway, the header could be wrong about a ROM that runs fine
+                match RomInfo::parse(&rom, rom_id.clone()) {
+                    Ok(info) => {
+                        log::info!("ROM {:?} is {info}", path);
+                        for warning in info.warnings() {
+                            log::warn!("ROM {:?}: {warning}", path);
+                        }
+                    }
+                    Err(e) => log::warn!("ROM {:?} has an odd header: {:?}", path, e),
+                }
+                let _ = self.emulator_tx.send(EmulatorCommand::LoadRom(rom));
+                Some(rom_id)
+            }
+            Err(e) => {
+                log::warn!("Could not load ROM {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    /// What's stored per ROM, with a button to clear each of them
+    fn storage_ui(&self, ui: &mut Ui) {
+        let per_rom = storage::stored_per_rom();
+        if per_rom.is_empty() {
+            ui.label("Nothing is stored for any game");
+        }
+        for (rom_id, stored) in per_rom {
+            ui.horizontal(|ui| {
+                if rom_id == self.bundled_rom_id {
+                    ui.label(&Bundle::current().config.name);
+                } else {
+                    ui.label(format!("ROM {}", &rom_id[..rom_id.len().min(8)]))
+                        .on_hover_text(&rom_id);
+                }
+                for data in stored {
+                    if ui
+                        .button(format!("Clear {} ({} bytes)", data.kind, data.size))
+                        .clicked()
+                    {
+                        storage::clear(&rom_id, Some(data.kind));
+                    }
+                }
+            });
         }
     }
 
@@ -140,6 +199,9 @@ impl MainGui {
         if !self.visible() && esc_pressed(ctx) {
             Self::set_main_menu_state(MainMenuState::Main);
         }
+        if let Some(text) = emulator_gui.take_clipboard_text() {
+            ctx.output_mut(|o| o.copied_text = text);
+        }
 
         match Self::main_menu_state() {
             MainMenuState::Main => {
@@ -220,6 +282,55 @@ impl MainGui {
                             });
                         }
 
+                        ui.separator();
+                        ui.checkbox(
+                            &mut Settings::current_mut().pause_when_unfocused,
+                            "Pause when the window is in the background",
+                        );
+                        ui.horizontal(|ui| {
+                            use crate::emulation::pacing::FramePacing;
+                            let frame_pacing = &mut Settings::current_mut().frame_pacing;
+                            ui.label("Frame pacing").on_hover_text(
+                                "Vsync follows the refresh of the display. Fixed timestep keeps the console's own frame rate on any display, like 144Hz ones. Netplay always uses the audio clock.",
+                            );
+                            ui.radio_value(frame_pacing, FramePacing::Vsync, "Vsync");
+                            ui.radio_value(
+                                frame_pacing,
+                                FramePacing::FixedTimestep,
+                                "Fixed timestep",
+                            );
+                        });
+                        emulator_gui.timing_ui(ui);
+                        emulator_gui.battery_saver_ui(ui);
+                        emulator_gui.speed_ui(ui);
+                        ui.horizontal(|ui| {
+                            let latency_test_button =
+                                &mut Settings::current_mut().latency_test_button;
+                            ui.label("Latency test flash").on_hover_text(
+                                "Flashes the screen white on the frame the button of player 1 is pressed. Film it to count the frames of input latency.",
+                            );
+                            egui::ComboBox::from_id_source("latency_test_button")
+                                .selected_text(
+                                    latency_test_button
+                                        .map(|button| format!("{button}"))
+                                        .unwrap_or_else(|| "Off".to_string()),
+                                )
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(latency_test_button, None, "Off");
+                                    use crate::input::JoypadButton::*;
+                                    for button in [Up, Down, Left, Right, Select, Start, B, A] {
+                                        ui.selectable_value(
+                                            latency_test_button,
+                                            Some(button),
+                                            format!("{button}"),
+                                        );
+                                    }
+                                });
+                        });
+
+                        egui::CollapsingHeader::new("Stored data")
+                            .show(ui, |ui| self.storage_ui(ui));
+
                         ui.vertical_centered(|ui| {
                             ui.add_space(20.0);
                             if Button::new(RichText::new("Close").font(FontId::proportional(20.0)))
@@ -280,6 +391,22 @@ impl MainGui {
         inputs_gui: &mut InputsGui,
         emulator_gui: &mut EmulatorGui,
     ) {
+        if let GuiEvent::Keyboard(KeyEvent::Pressed(key_code)) = gui_event {
+            let settings = Settings::current();
+            let reset = if Some(*key_code) == settings.soft_reset_key {
+                Some(false)
+            } else if Some(*key_code) == settings.hard_reset_key {
+                Some(true)
+            } else {
+                None
+            };
+            drop(settings);
+            if let Some(hard) = reset {
+                log::info!("{} reset", if hard { "Hard" } else { "Soft" });
+                let _ = self.emulator_tx.send(EmulatorCommand::Reset(hard));
+            }
+        }
+
         let gui_components: &mut [&mut dyn GuiComponent] =
             &mut [audio_gui, inputs_gui, emulator_gui];
 
@@ -80,8 +80,16 @@ impl MainView {
         inputs_gui: &mut InputsGui,
         emulator_gui: &mut EmulatorGui,
     ) {
-        if let winit::event::WindowEvent::Resized(physical_size) = window_event {
-            self.renderer.resize(*physical_size);
+        match window_event {
+            winit::event::WindowEvent::Resized(physical_size) => {
+                self.renderer.resize(*physical_size);
+            }
+            winit::event::WindowEvent::DroppedFile(path) => {
+                if let Some(rom_id) = self.main_gui.load_rom(path) {
+                    inputs_gui.inputs.rom_id = rom_id;
+                }
+            }
+            _ => {}
         }
 
         if !self
@@ -0,0 +1,234 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use ggrs::{Message, NonBlockingSocket, PlayerType, SessionBuilder, SessionState};
+use matchbox_socket::PeerId;
+use uuid::Uuid;
+
+use crate::{
+    bundle::Bundle,
+    emulation::{LocalNesState, NESBuffers, NesStateHandler},
+    input::JoypadState,
+    settings::MAX_PLAYERS,
+};
+
+use super::{
+    confirmed_states::ConfirmedStates,
+    connecting_state::{JoinOrHost, StartMethod, StartState},
+    netplay_session::{GGRSConfig, NetplaySession},
+    opponents::PeerProfile,
+    socket::{Liveness, RejectedPackets},
+    NetplayNesState, PredictionPolicy,
+};
+
+/// Buttons that change every few frames, the same on every run.
+fn synthetic_input(frame: u32, player: usize) -> u8 {
+    ((frame / 8).wrapping_mul(2_654_435_761) >> (player * 8)) as u8
+}
+
+/// Two peers in the same process, what one sends arrives at the other `latency` ticks later.
+pub(super) struct LoopbackSocket {
+    inbox: Arc<Mutex<VecDeque<(u32, PeerId, Vec<u8>)>>>,
+    peer_inbox: Arc<Mutex<VecDeque<(u32, PeerId, Vec<u8>)>>>,
+    pub(super) id: PeerId,
+    // Shared by both ends, the benchmark moves it on once per frame
+    tick: Arc<AtomicU32>,
+    latency: u32,
+}
+
+impl LoopbackSocket {
+    pub(super) fn pair(latency: u32) -> (Self, Self) {
+        let tick = Arc::new(AtomicU32::new(0));
+        let (a, b) = (Arc::default(), Arc::default());
+        let socket = |inbox: &Arc<_>, peer_inbox: &Arc<_>| Self {
+            inbox: Arc::clone(inbox),
+            peer_inbox: Arc::clone(peer_inbox),
+            id: PeerId(Uuid::new_v4()),
+            tick: tick.clone(),
+            latency,
+        };
+        (socket(&a, &b), socket(&b, &a))
+    }
+}
+
+impl NonBlockingSocket<PeerId> for LoopbackSocket {
+    fn send_to(&mut self, msg: &Message, _addr: &PeerId) {
+        // Serialized like on the wire, so encoding is part of what's measured
+        let packet = bincode::serialize(msg).expect("ggrs message to serialize");
+        let arrival = self.tick.load(Ordering::Relaxed) + self.latency;
+        self.peer_inbox
+            .lock()
+            .unwrap()
+            .push_back((arrival, self.id, packet));
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        let tick = self.tick.load(Ordering::Relaxed);
+        let mut inbox = self.inbox.lock().unwrap();
+        let mut messages = Vec::new();
+        while inbox.front().is_some_and(|(arrival, ..)| *arrival <= tick) {
+            if let Some((_, from, packet)) = inbox.pop_front() {
+                messages.push((from, bincode::deserialize(&packet).expect("ggrs message")));
+            }
+        }
+        messages
+    }
+}
+
+/// A session with nothing behind it but `socket`, also for tests that need a connected session
+pub(super) fn loopback_session(
+    socket: LoopbackSocket,
+    remote: PeerId,
+    rollback_depth: usize,
+) -> Result<NetplaySession> {
+    // Like when connecting for real, the player handles go by peer id order
+    let mut players = [
+        (socket.id, PlayerType::Local),
+        (remote, PlayerType::Remote(remote)),
+    ];
+    players.sort_by_key(|(id, _)| *id);
+    let mut builder = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(MAX_PLAYERS)
+        .with_max_prediction_window(rollback_depth + 2)?;
+    for (handle, (_, player)) in players.into_iter().enumerate() {
+        builder = builder.add_player(player, handle)?;
+    }
+    let start_state = StartState {
+        game_state: Arc::new(NetplayNesState::new(LocalNesState::start_rom(
+            &Bundle::current().netplay_rom,
+            false,
+            Bundle::current().config.get_default_region(),
+        )?)),
+        session_id: "benchmark".to_string(),
+        rom_hash: String::new(),
+        queue_tag: None,
+        requeues: None,
+        confirmed_inputs: Default::default(),
+        connect_deadline: None,
+        resume_frames: Default::default(),
+        clock: Default::default(),
+    };
+    Ok(NetplaySession::new(
+        StartMethod::Start(start_state, "benchmark".to_string(), JoinOrHost::Host),
+        builder.start_p2p_session(socket)?,
+        RejectedPackets::default(),
+        Liveness::untracked(),
+        PeerProfile::default(),
+        PredictionPolicy::default(),
+        false,
+        Some(remote),
+    ))
+}
+
+/// Runs two netplay sessions of the netplay ROM against each other over a loopback socket with synthetic inputs. The inputs of the
+/// other player arrive `rollback_depth` frames late, so every frame is predicted and rolled back that far, like on a real connection.
+/// Prints how fast both sessions get through the frames with all the saving, loading and re-simulating that takes.
+pub fn benchmark_rollback(frames: u32, rollback_depth: usize) -> Result<()> {
+    let (socket_a, socket_b) = LoopbackSocket::pair(rollback_depth as u32);
+    let tick = socket_a.tick.clone();
+    let (id_a, id_b) = (socket_a.id, socket_b.id);
+    let mut session_a = loopback_session(socket_a, id_b, rollback_depth)?;
+    let mut session_b = loopback_session(socket_b, id_a, rollback_depth)?;
+    let mut sessions = [&mut session_a, &mut session_b];
+
+    let synchronized = |sessions: &[&mut NetplaySession; 2]| {
+        sessions
+            .iter()
+            .all(|session| matches!(session.p2p_session.current_state(), SessionState::Running))
+    };
+    // The sync handshake only takes a few round trips, anything beyond that means it's stuck
+    for _ in 0..(rollback_depth as u32 + 1) * 100 {
+        if synchronized(&sessions) {
+            break;
+        }
+        tick.fetch_add(1, Ordering::Relaxed);
+        for session in sessions.iter_mut() {
+            session.p2p_session.poll_remote_clients();
+        }
+    }
+    if !synchronized(&sessions) {
+        return Err(anyhow!("The loopback sessions did not synchronize"));
+    }
+    let mappings = sessions.each_ref().map(|session| {
+        session
+            .negotiate_joypad_mapping()
+            .expect("one local and one remote player")
+    });
+
+    let mut rollbacks = 0;
+    let start = Instant::now();
+    for frame in 0..frames {
+        tick.fetch_add(1, Ordering::Relaxed);
+        for (player, (session, mapping)) in sessions.iter_mut().zip(&mappings).enumerate() {
+            session.advance(
+                [JoypadState(synthetic_input(frame, player)), JoypadState(0)],
+                mapping,
+                &mut NESBuffers {
+                    audio: None,
+                    video: None,
+                },
+            )?;
+            rollbacks += session.last_rollback_depth.max(0) as u64;
+            session.last_rollback_depth = 0;
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let [a, b] = sessions.map(|session| session.current_frame());
+    println!(
+        "{frames} frames on two peers {rollback_depth} frames apart in {elapsed:.2}s: {:.1} fps (reached frames {a} and {b}, {rollbacks} frames rolled back)",
+        frames as f64 / elapsed
+    );
+    Ok(())
+}
+
+/// Runs the netplay ROM with synthetic inputs, keeping `states` confirmed states `interval` frames apart with and without compression,
+/// and prints what they take in memory and how long restoring all of them takes.
+pub fn benchmark_confirmed_states(states: usize, interval: u32) -> Result<()> {
+    let interval = interval.max(1);
+    for compress in [false, true] {
+        let mut game_state = NetplayNesState::new(LocalNesState::start_rom(
+            &Bundle::current().netplay_rom,
+            false,
+            Bundle::current().config.get_default_region(),
+        )?);
+        let mut confirmed_states =
+            ConfirmedStates::new(Arc::new(game_state.clone()), states, compress);
+
+        let start = Instant::now();
+        for frame in 0..states as u32 * interval {
+            game_state.advance(
+                [
+                    JoypadState(synthetic_input(frame, 0)),
+                    JoypadState(synthetic_input(frame, 1)),
+                ],
+                &mut NESBuffers {
+                    audio: None,
+                    video: None,
+                },
+            );
+            game_state.frame += 1;
+            if frame % interval == 0 {
+                confirmed_states.push(Arc::new(game_state.clone()));
+            }
+        }
+        let emulated = start.elapsed().as_secs_f64();
+
+        let start = Instant::now();
+        let restored = confirmed_states.newest_first(states).len();
+        let restore = start.elapsed().as_secs_f64() * 1000.0;
+        println!(
+            "{} {states} confirmed states: {:.1} KiB, restored {restored} in {restore:.2}ms ({emulated:.2}s to emulate and store)",
+            if compress { "Compressed" } else { "Uncompressed" },
+            confirmed_states.memory_usage() as f64 / 1024.0,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::time::Instant;
+#[cfg(test)]
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Where the netplay timeouts get the time from, so they can be driven without waiting for real.
+pub trait Clock: Clone + Send + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// The wall clock. It has no state, so using it costs the same as calling `Instant::now` directly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it's told to. Clones share the same time.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// The clock every netplay state reads the time from. It's handed on from state to state (through `StartState::clock`),
+/// so a test that starts with a `ManualClock` drives all the timeouts that follow. Outside of tests the wall clock is
+/// the only variant, reading it is then the same as `Instant::now`.
+#[derive(Debug, Clone, Default)]
+pub enum NetplayClock {
+    #[default]
+    System,
+    #[cfg(test)]
+    Manual(ManualClock),
+}
+
+impl Clock for NetplayClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        match self {
+            NetplayClock::System => SystemClock.now(),
+            #[cfg(test)]
+            NetplayClock::Manual(clock) => clock.now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(clock.now(), start + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn clones_share_the_time() {
+        let clock = ManualClock::new();
+        let netplay_clock = NetplayClock::Manual(clock.clone());
+        let start = netplay_clock.now();
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(
+            netplay_clock.clone().now(),
+            start + Duration::from_millis(500)
+        );
+    }
+}
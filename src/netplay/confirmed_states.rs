@@ -0,0 +1,326 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use anyhow::{anyhow, Result};
+
+use super::NetplayNesState;
+
+/// The most recent confirmed states of a session. Resuming can start from any of them.
+/// With compression only the newest state is kept as is, every older one is stored as the difference to the one after it.
+pub struct ConfirmedStates {
+    newest: Arc<NetplayNesState>,
+    // What `newest` serializes to, only kept with compression
+    newest_raw: Option<Vec<u8>>,
+    // Oldest first
+    older: VecDeque<StoredState>,
+    capacity: usize,
+    compress: bool,
+}
+
+enum StoredState {
+    Live(Arc<NetplayNesState>),
+    /// The serialized state XORed with the serialized state after it, see [`encode_delta`]
+    Delta {
+        frame: i32,
+        delta: Vec<u8>,
+    },
+}
+
+impl ConfirmedStates {
+    pub fn new(first: Arc<NetplayNesState>, capacity: usize, compress: bool) -> Self {
+        let mut confirmed_states = Self {
+            newest: first.clone(),
+            newest_raw: None,
+            older: VecDeque::new(),
+            capacity,
+            compress,
+        };
+        if compress {
+            confirmed_states.newest_raw = Self::serialize(&first);
+        }
+        confirmed_states
+    }
+
+    fn serialize(state: &NetplayNesState) -> Option<Vec<u8>> {
+        state
+            .save_state()
+            .inspect_err(|e| log::warn!("Could not compress a confirmed state: {:?}", e))
+            .ok()
+    }
+
+    fn len(&self) -> usize {
+        self.older.len() + 1
+    }
+
+    /// The newest state both players agreed on
+    pub fn latest(&self) -> &Arc<NetplayNesState> {
+        &self.newest
+    }
+
+    pub fn push(&mut self, state: Arc<NetplayNesState>) {
+        let previous = std::mem::replace(&mut self.newest, state);
+        let previous_raw = self.newest_raw.take();
+        if self.compress {
+            self.newest_raw = Self::serialize(&self.newest);
+        }
+        self.older
+            .push_back(match (previous_raw, &self.newest_raw) {
+                (Some(previous_raw), Some(newest_raw)) => StoredState::Delta {
+                    frame: previous.frame,
+                    delta: encode_delta(&previous_raw, newest_raw),
+                },
+                _ => StoredState::Live(previous),
+            });
+        self.truncate();
+    }
+
+    /// Drops the oldest states beyond `capacity`, the newest one is always kept
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.len() > self.capacity.max(1) {
+            self.older.pop_front();
+        }
+    }
+
+    /// The frames of the states, oldest first
+    pub fn frames(&self) -> Vec<i32> {
+        self.older
+            .iter()
+            .map(|stored| match stored {
+                StoredState::Live(state) => state.frame,
+                StoredState::Delta { frame, .. } => *frame,
+            })
+            .chain(std::iter::once(self.newest.frame))
+            .collect()
+    }
+
+    /// Up to `count` states, newest first. Compressed states are restored on the way.
+    pub fn newest_first(&self, count: usize) -> Vec<Arc<NetplayNesState>> {
+        let mut states = vec![self.newest.clone()];
+        // The serialized form of the last state in `states`, only made when a delta needs it
+        let mut newer_raw = self.newest_raw.clone();
+        for stored in self.older.iter().rev() {
+            if states.len() >= count {
+                break;
+            }
+            let newer = states.last().expect("the newest state to be there");
+            match stored {
+                StoredState::Live(state) => {
+                    states.push(state.clone());
+                    newer_raw = None;
+                }
+                StoredState::Delta { frame, delta } => {
+                    let Some(base) = newer_raw.take().or_else(|| Self::serialize(newer)) else {
+                        break;
+                    };
+                    match self.restore(*frame, delta, &base) {
+                        Ok((state, raw)) => {
+                            states.push(Arc::new(state));
+                            newer_raw = Some(raw);
+                        }
+                        Err(e) => {
+                            log::error!("Could not restore confirmed state {frame}: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        states.truncate(count);
+        states
+    }
+
+    /// The state of `frame` from its delta to the serialized state after it (`base`), along with what it serializes to.
+    /// Only the emulated state is stored, so everything else (like the joypad mapping and the speed) is copied from `newest`.
+    /// That's the same for every state of a session.
+    fn restore(&self, frame: i32, delta: &[u8], base: &[u8]) -> Result<(NetplayNesState, Vec<u8>)> {
+        let raw = decode_delta(delta, base).ok_or_else(|| anyhow!("broken delta"))?;
+        let mut state = self.newest.as_ref().clone();
+        state.load_state(&raw)?;
+        state.frame = frame;
+        Ok((state, raw))
+    }
+
+    /// Roughly what the stored states take, counting a state that isn't compressed as its serialized size
+    pub fn memory_usage(&self) -> usize {
+        let state_size = self
+            .newest_raw
+            .as_ref()
+            .map(|raw| raw.len())
+            .or_else(|| Self::serialize(&self.newest).map(|raw| raw.len()))
+            .unwrap_or(0);
+        self.older
+            .iter()
+            .map(|stored| match stored {
+                StoredState::Live(_) => state_size,
+                StoredState::Delta { delta, .. } => delta.len(),
+            })
+            .sum::<usize>()
+            + state_size
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}
+
+/// XORs `state` with `base` and run-length encodes the zeros, which is most of it for states a few frames apart.
+/// The length of `state` comes first, then pairs of (zero bytes, changed bytes) counts each followed by the changed bytes.
+fn encode_delta(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let byte_at = |i: usize| state[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut delta = Vec::new();
+    write_varint(&mut delta, state.len());
+    let mut i = 0;
+    while i < state.len() {
+        let zeros_start = i;
+        while i < state.len() && byte_at(i) == 0 {
+            i += 1;
+        }
+        let changed_start = i;
+        while i < state.len() && byte_at(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, changed_start - zeros_start);
+        write_varint(&mut delta, i - changed_start);
+        delta.extend((changed_start..i).map(byte_at));
+    }
+    delta
+}
+
+fn decode_delta(delta: &[u8], base: &[u8]) -> Option<Vec<u8>> {
+    let base_at = |i: usize| base.get(i).copied().unwrap_or(0);
+    let (len, mut rest) = read_varint(delta)?;
+    let mut state = Vec::with_capacity(len);
+    while state.len() < len {
+        let (zeros, after_zeros) = read_varint(rest)?;
+        let (changed, after_changed) = read_varint(after_zeros)?;
+        let changed_bytes = after_changed.get(..changed)?;
+        rest = &after_changed[changed..];
+        for _ in 0..zeros {
+            state.push(base_at(state.len()));
+        }
+        for byte in changed_bytes {
+            state.push(byte ^ base_at(state.len()));
+        }
+    }
+    (state.len() == len).then_some(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bundle::Bundle,
+        emulation::{LocalNesState, NESBuffers, NesStateHandler},
+        input::JoypadState,
+    };
+
+    fn round_trip(state: &[u8], base: &[u8]) {
+        let delta = encode_delta(state, base);
+        assert_eq!(decode_delta(&delta, base).as_deref(), Some(state));
+    }
+
+    #[test]
+    fn delta_of_equal_states() {
+        let state = [1, 2, 3, 0, 5];
+        round_trip(&state, &state);
+        // The length and a single run of zeros
+        assert_eq!(encode_delta(&state, &state), vec![5, 5, 0]);
+    }
+
+    #[test]
+    fn delta_of_a_longer_state() {
+        round_trip(&[1, 2, 3, 4, 5, 6], &[1, 2, 9]);
+    }
+
+    #[test]
+    fn delta_of_a_shorter_state() {
+        round_trip(&[1, 7], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn delta_of_empty_states() {
+        round_trip(&[], &[1, 2, 3]);
+        round_trip(&[1, 2, 3], &[]);
+        round_trip(&[], &[]);
+    }
+
+    #[test]
+    fn broken_deltas_are_rejected() {
+        let delta = encode_delta(&[1, 2, 3, 4], &[0, 0, 0, 0]);
+        assert_eq!(decode_delta(&delta[..delta.len() - 1], &[0, 0, 0, 0]), None);
+        assert_eq!(decode_delta(&[], &[]), None);
+    }
+
+    /// Every 10th of 40 frames, the RAM starts out random so both modes need to get the same states
+    fn game_states() -> Vec<Arc<NetplayNesState>> {
+        let bundle = Bundle::current();
+        let mut game_state = NetplayNesState::new(
+            LocalNesState::start_rom(
+                &bundle.netplay_rom,
+                false,
+                bundle.config.get_default_region(),
+            )
+            .expect("the bundled netplay ROM to start"),
+        );
+        let mut states = vec![Arc::new(game_state.clone())];
+        for frame in 1..=40 {
+            game_state.advance(
+                [JoypadState(frame as u8), JoypadState(0)],
+                &mut NESBuffers {
+                    audio: None,
+                    video: None,
+                },
+            );
+            game_state.frame = frame;
+            if frame % 10 == 0 {
+                states.push(Arc::new(game_state.clone()));
+            }
+        }
+        states
+    }
+
+    fn confirmed_states(states: &[Arc<NetplayNesState>], compress: bool) -> ConfirmedStates {
+        let mut confirmed_states = ConfirmedStates::new(states[0].clone(), 4, compress);
+        for state in &states[1..] {
+            confirmed_states.push(state.clone());
+        }
+        confirmed_states
+    }
+
+    #[test]
+    fn compressed_states_restore_the_same() {
+        let states = game_states();
+        let plain = confirmed_states(&states, false).newest_first(4);
+        let compressed = confirmed_states(&states, true).newest_first(4);
+        let frames = |states: &[Arc<NetplayNesState>]| -> Vec<i32> {
+            states.iter().map(|state| state.frame).collect()
+        };
+        assert_eq!(frames(&plain), vec![40, 30, 20, 10]);
+        assert_eq!(frames(&compressed), frames(&plain));
+        for (plain, compressed) in plain.iter().zip(&compressed) {
+            assert_eq!(
+                plain.save_state().unwrap(),
+                compressed.save_state().unwrap()
+            );
+        }
+    }
+}
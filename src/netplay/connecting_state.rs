@@ -1,32 +1,120 @@
 use futures::channel::oneshot::Receiver;
 use futures::{select, FutureExt};
 use futures_timer::Delay;
-use ggrs::{P2PSession, SessionBuilder, SessionState};
-use matchbox_socket::{ChannelConfig, RtcIceServerConfig, WebRtcSocket, WebRtcSocketBuilder};
+use ggrs::{DesyncDetection, P2PSession, SessionBuilder, SessionState};
+use matchbox_socket::{
+    ChannelConfig, PeerId, RtcIceServerConfig, WebRtcSocket, WebRtcSocketBuilder,
+};
 
 use serde::Deserialize;
 use std::fmt::Debug;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 
 use crate::bundle::Bundle;
-use crate::netplay::netplay_state::get_netplay_id;
+use crate::input::JoypadState;
+use crate::netplay::netplay_state::{get_server_netplay_id, NetplayError};
 use crate::settings::{Settings, MAX_PLAYERS};
 
-use super::netplay_session::{GGRSConfig, NetplaySession};
+use super::clock::{Clock, NetplayClock};
+use super::matchmaking;
+use super::netplay_session::{GGRSConfig, NetplaySession, DESYNC_CHECK_INTERVAL};
+use super::opponents::{PeerProfile, PlayerProfile};
+use super::room_probe::RoomProbe;
+use super::session_id::with_match_nonce;
+use super::socket::{
+    agree_on_resume_frame, decode_fast_forward, decode_resume_frames, encode_fast_forward,
+    encode_resume_frames, Features, Hello, Liveness, RejectedPackets, ValidatingSocket,
+    HANDSHAKE_CHANNEL, OLDEST_COMPATIBLE_VERSION,
+};
 
-use super::NetplayNesState;
+use super::{NetplayMode, NetplayNesState, PredictionPolicy};
 
 #[derive(Deserialize, Clone, Debug)]
 pub enum NetplayServerConfiguration {
     Static(StaticNetplayServerConfiguration),
-    //An external server for fetching TURN credentials
-    TurnOn(String),
+    //External servers for fetching TURN credentials
+    TurnOn(TurnOnServers),
+}
+
+/// One TurnOn server or a list of them in order of priority
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum TurnOnServers {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TurnOnServers {
+    pub(super) fn urls(&self) -> Vec<String> {
+        match self {
+            TurnOnServers::One(server) => vec![server.clone()],
+            TurnOnServers::Many(servers) => servers.clone(),
+        }
+    }
+}
+
+async fn fetch_turn_on_config(
+    client: &reqwest::Client,
+    server: &str,
+    netplay_id: &str,
+) -> Result<TurnOnResponse, TurnOnError> {
+    let res = client
+        .get(matchmaking::with_payload(format!("{server}/{netplay_id}")))
+        .send()
+        .await
+        .map_err(|e| TurnOnError {
+            description: format!("Could not connect: {}", e),
+        })?;
+    log::trace!("Received response from TurnOn server: {:?}", res);
+    res.json().await.map_err(|e| TurnOnError {
+        description: format!("Failed to receive response: {}", e),
+    })
+}
+
+/// Tries the servers in order of priority, the first one to answer is used
+pub(super) async fn fetch_first_turn_on_config(
+    client: &reqwest::Client,
+    servers: Vec<String>,
+    netplay_id: &str,
+) -> Result<TurnOnResponse, TurnOnError> {
+    let mut errors = Vec::new();
+    for server in servers {
+        log::debug!("Fetching TurnOn config from server: {}", server);
+        match fetch_turn_on_config(client, &server, netplay_id).await {
+            Ok(res) => {
+                log::info!("Using netplay server {}", server);
+                return Ok(res);
+            }
+            Err(e) => {
+                log::warn!("Netplay server {} failed: {}", server, e.description);
+                errors.push(format!("{server}: {}", e.description));
+            }
+        }
+    }
+    Err(TurnOnError {
+        description: format!("No netplay server reachable ({})", errors.join(", ")),
+    })
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct StaticNetplayServerConfiguration {
     matchbox: MatchboxConfiguration,
     pub ggrs: GGRSConfiguration,
+    /// Anything a custom TurnOn server wants the client to know, see [`matchmaking::reply`]
+    #[serde(default)]
+    matchmaking_reply: Option<serde_yaml::Value>,
+}
+
+impl StaticNetplayServerConfiguration {
+    pub(super) fn matchbox_server(&self) -> &str {
+        &self.matchbox.server
+    }
+
+    pub(super) fn ice_urls(&self) -> &[String] {
+        &self.matchbox.ice.urls
+    }
 }
 
 pub enum ConnectingState {
@@ -38,52 +126,93 @@ pub enum ConnectingState {
     Connected(Box<Connecting<NetplaySession>>),
 
     Retrying(Connecting<Retrying>),
-    Failed(String),
+    Failed(NetplayError),
 }
 
 impl ConnectingState {
-    pub fn connect(start_method: StartMethod) -> Self {
+    /// Every call is its own attempt with its own `connect_timeout_secs`, retries of it share the same deadline
+    pub fn connect(mut start_method: StartMethod) -> Self {
+        let timeout = Bundle::current().config.netplay.connect_timeout();
+        start_method
+            .start_state_mut()
+            .start_connect_timeout(timeout);
         Self::start(start_method)
     }
 
     fn start(start_method: StartMethod) -> Self {
         let reqwest_client = reqwest::Client::new();
+        // A static configuration has no server to reply
+        matchmaking::set_reply(None);
         match &Bundle::current().config.netplay.server {
             NetplayServerConfiguration::Static(conf) => {
                 Self::PeeringUp(Connecting::<PeeringState>::new(conf.clone(), start_method))
             }
 
-            NetplayServerConfiguration::TurnOn(server) => {
-                log::debug!("Fetching TurnOn config from server: {}", server);
-                let netplay_id = get_netplay_id();
-                let req = reqwest_client.get(format!("{server}/{netplay_id}")).send();
+            NetplayServerConfiguration::TurnOn(servers) => {
+                let servers = servers.urls();
+                let netplay_id = get_server_netplay_id();
                 let (sender, result) =
                     futures::channel::oneshot::channel::<Result<TurnOnResponse, TurnOnError>>();
-                tokio::spawn(async move {
-                    if let Err(e) = match req.await {
-                        Ok(res) => {
-                            log::trace!("Received response from TurnOn server: {:?}", res);
-                            sender.send(res.json().await.map_err(|e| TurnOnError {
-                                description: format!("Failed to receive response: {}", e),
-                            }))
-                        }
-                        Err(e) => sender.send(Err(TurnOnError {
-                            description: format!("Could not connect: {}", e),
-                        })),
-                    } {
+                let fetch_task = tokio::spawn(async move {
+                    let response =
+                        fetch_first_turn_on_config(&reqwest_client, servers, &netplay_id).await;
+                    if let Err(e) = sender.send(response) {
                         log::error!("Could not send response: {:?}", e);
                     }
                 });
 
                 Self::LoadingNetplayServerConfiguration(Connecting {
                     start_method,
-                    state: LoadingNetplayServerConfiguration { result },
+                    state: LoadingNetplayServerConfiguration { result, fetch_task },
                 })
             }
         }
     }
 
-    pub fn advance(self) -> ConnectingState {
+    /// Stops any outstanding matchmaking so the server drops us from the room right away
+    /// instead of pairing someone up with a player that already left.
+    pub fn cancel(&mut self) {
+        match self {
+            ConnectingState::LoadingNetplayServerConfiguration(loading) => {
+                log::debug!("Cancelling the netplay server configuration request");
+                loading.state.fetch_task.abort();
+            }
+            ConnectingState::PeeringUp(peering) => peering.state.leave(),
+            ConnectingState::Synchronizing(synchronizing) => {
+                log::debug!("Leaving room while pairing up");
+                synchronizing.state.signalling_task.abort();
+            }
+            ConnectingState::Retrying(retrying) => retrying.state.retry_state.cancel(),
+            _ => {}
+        }
+    }
+
+    pub fn start_method(&self) -> Option<&StartMethod> {
+        match self {
+            ConnectingState::LoadingNetplayServerConfiguration(Connecting {
+                start_method, ..
+            })
+            | ConnectingState::PeeringUp(Connecting { start_method, .. })
+            | ConnectingState::Retrying(Connecting { start_method, .. }) => Some(start_method),
+            ConnectingState::Synchronizing(connecting) => Some(&connecting.start_method),
+            ConnectingState::Connected(connecting) => Some(&connecting.start_method),
+            ConnectingState::Failed(_) => None,
+        }
+    }
+
+    pub fn advance(mut self) -> ConnectingState {
+        let timed_out = match &self {
+            ConnectingState::Connected(_) => None,
+            _ => self
+                .start_method()
+                .and_then(|start_method| start_method.start_state().connect_timed_out()),
+        };
+        if let Some(timeout) = timed_out {
+            let secs = timeout.as_secs() as u16;
+            log::info!("Could not connect within {secs}s, giving up");
+            self.cancel();
+            return ConnectingState::Failed(NetplayError::ConnectTimedOut(secs));
+        }
         match self {
             ConnectingState::LoadingNetplayServerConfiguration(loading) => loading.advance(),
             ConnectingState::PeeringUp(peering) => peering.advance(),
@@ -105,11 +234,12 @@ impl<T> Connecting<T> {
             state,
         }
     }
-    fn into_retrying(self, fail_message: &str) -> Connecting<Retrying> {
+    fn into_retrying(self, reason: NetplayError) -> Connecting<Retrying> {
         Connecting::from(
             Retrying::new(
-                fail_message.to_string(),
+                reason,
                 ConnectingState::start(self.start_method.clone()),
+                self.start_method.start_state().clock.clone(),
             ),
             self,
         )
@@ -118,12 +248,87 @@ impl<T> Connecting<T> {
 
 pub struct LoadingNetplayServerConfiguration {
     pub result: Receiver<Result<TurnOnResponse, TurnOnError>>,
+    fetch_task: JoinHandle<()>,
+}
+
+impl Drop for LoadingNetplayServerConfiguration {
+    fn drop(&mut self) {
+        // Nobody is waiting for the response anymore, like when a retry is replaced by the fallback configuration
+        self.fetch_task.abort();
+    }
+}
+
+/// Joins a room on the matchbox server, the signalling runs until the task is aborted
+pub(super) fn open_socket(
+    conf: &StaticNetplayServerConfiguration,
+    room_name: &str,
+) -> (WebRtcSocket, JoinHandle<()>) {
+    let (username, password) = match &conf.matchbox.ice.credentials {
+        IceCredentials::Password(IcePasswordCredentials { username, password }) => {
+            (Some(username.to_string()), Some(password.to_string()))
+        }
+        IceCredentials::None => (None, None),
+    };
+
+    let (socket, loop_fut) = {
+        let room_url =
+            matchmaking::with_payload(format!("ws://{}/{room_name}", conf.matchbox.server));
+        let ice_server = RtcIceServerConfig {
+            urls: conf.matchbox.ice.urls.clone(),
+            username,
+            credential: password,
+        };
+        log::debug!(
+            "Peering up through WebRTC socket: room_url={:?}, ice_server={:?}",
+            room_url,
+            ice_server
+        );
+        WebRtcSocketBuilder::new(room_url)
+            .ice_server(ice_server)
+            .add_channel(ChannelConfig::unreliable())
+            .add_channel(ChannelConfig::reliable())
+            .build()
+    };
+
+    let loop_fut = loop_fut.fuse();
+    let timeout = Delay::new(Duration::from_millis(100));
+    let signalling_task = tokio::spawn(async move {
+        futures::pin_mut!(loop_fut, timeout);
+        loop {
+            select! {
+                _ = (&mut timeout).fuse() => {
+                    timeout.reset(Duration::from_millis(100));
+                }
+
+                _ = &mut loop_fut => {
+                    break;
+                }
+            }
+        }
+    });
+    (socket, signalling_task)
 }
 
 pub struct PeeringState {
     pub socket: WebRtcSocket,
+    signalling_task: JoinHandle<()>,
     ggrs_config: GGRSConfiguration,
     unlock_url: Option<String>,
+    hello_sent: bool,
+    peer_features: Option<Features>,
+    // From the peer's handshake, see `Liveness::missed_heartbeats`
+    peer_heartbeat_interval: Duration,
+    // Only for private games, see `RoomProbe`
+    probe: Option<RoomProbe>,
+    rom_hash: String,
+    peer_profile: PeerProfile,
+    // Resuming only, how many frames of confirmed inputs we have after the resume state
+    confirmed_inputs: Option<u32>,
+    peer_confirmed_inputs: Option<u32>,
+    // Resuming only, the frame of this attempt and of all the attempts, see `Features::RESUME_FRAMES`
+    resume_frame: Option<i32>,
+    resume_frames: Arc<Vec<i32>>,
+    peer_resume_frames: Option<Vec<i32>>,
 }
 impl PeeringState {
     pub fn new(resp: TurnOnResponse, start_method: StartMethod) -> Self {
@@ -135,17 +340,18 @@ impl PeeringState {
             }
             TurnOnResponse::Full(conf) => conf,
         };
-        let matchbox_server = &conf.matchbox.server;
-
         let room_name = match &start_method {
             StartMethod::Start(StartState { session_id, .. }, ..) => {
                 format!("join_{}", session_id)
             }
-            StartMethod::Resume(StartState {
-                session_id,
-                game_state,
-                ..
-            }) => {
+            StartMethod::Resume(
+                StartState {
+                    session_id,
+                    game_state,
+                    ..
+                },
+                ..,
+            ) => {
                 format!("resume_{}_{}", session_id, game_state.frame)
             }
             StartMethod::MatchWithRandom(StartState { session_id, .. }) => {
@@ -153,65 +359,205 @@ impl PeeringState {
             }
         };
 
-        let (username, password) = match &conf.matchbox.ice.credentials {
-            IceCredentials::Password(IcePasswordCredentials { username, password }) => {
-                (Some(username.to_string()), Some(password.to_string()))
-            }
-            IceCredentials::None => (None, None),
+        let rom_hash = start_method.start_state().rom_hash.clone();
+        let confirmed_inputs = match &start_method {
+            StartMethod::Resume(start_state, ..) => Some(start_state.confirmed_inputs.len() as u32),
+            _ => None,
+        };
+        let resume_frame = match &start_method {
+            StartMethod::Resume(start_state, ..) => Some(start_state.game_state.frame),
+            _ => None,
+        };
+        let resume_frames = start_method.start_state().resume_frames.clone();
+        let (socket, signalling_task) = open_socket(&conf, &room_name);
+        let probe = match &start_method {
+            StartMethod::Start(_, room_name, _) => Some(RoomProbe::new(
+                &conf,
+                room_name,
+                rom_hash.clone(),
+                start_method.start_state().clock.clone(),
+            )),
+            _ => None,
         };
 
-        let (socket, loop_fut) = {
-            let room_url = format!("ws://{matchbox_server}/{room_name}");
-            let ice_server = RtcIceServerConfig {
-                urls: conf.matchbox.ice.urls.clone(),
-                username,
-                credential: password,
+        Self {
+            socket,
+            signalling_task,
+            ggrs_config: conf.ggrs.clone(),
+            unlock_url: maybe_unlock_url,
+            hello_sent: false,
+            peer_features: None,
+            peer_heartbeat_interval: Duration::ZERO,
+            probe,
+            rom_hash,
+            peer_profile: PeerProfile::default(),
+            confirmed_inputs,
+            peer_confirmed_inputs: None,
+            resume_frame,
+            resume_frames,
+            peer_resume_frames: None,
+        }
+    }
+
+    /// Sends our handshake and checks that the peer has the same netplay ROM, `None` until the peer's handshake arrives.
+    /// The features both sides support are kept in `peer_features`. With `Features::FAST_FORWARD` this also waits for the peer's confirmed inputs,
+    /// with `Features::RESUME_FRAMES` for the frames the peer tries to resume from.
+    fn verify_peer(&mut self) -> Option<Result<(), NetplayError>> {
+        let rom_hash = &self.rom_hash;
+        let peers: Vec<PeerId> = self.socket.connected_peers().collect();
+        let channel = self.socket.channel_mut(HANDSHAKE_CHANNEL);
+        if !self.hello_sent {
+            log::debug!("Sending our netplay ROM hash {rom_hash} to {:?}", peers);
+            let hello = Hello::ours(rom_hash.clone()).encode();
+            for peer in &peers {
+                channel.send(hello.clone(), *peer);
+            }
+            self.hello_sent = true;
+        }
+        for (peer, packet) in channel.receive() {
+            if let Some(profile) = PlayerProfile::decode(&packet) {
+                log::debug!("Peer {:?} is {:?}", peer, profile);
+                self.peer_profile.set(profile);
+                continue;
+            }
+            if let Some(frames) = decode_fast_forward(&packet) {
+                log::debug!("Peer {:?} has {frames} frames of confirmed inputs", peer);
+                self.peer_confirmed_inputs = Some(frames);
+                continue;
+            }
+            if let Some(frames) = decode_resume_frames(&packet) {
+                log::debug!("Peer {:?} tries to resume from {frames:?}", peer);
+                self.peer_resume_frames = Some(frames);
+                continue;
+            }
+            let Some(hello) = Hello::decode(&packet) else {
+                continue;
             };
+            if !hello.is_compatible() {
+                log::error!(
+                    "Peer {:?} has protocol version {}, we need at least {OLDEST_COMPATIBLE_VERSION}",
+                    peer,
+                    hello.version
+                );
+                return Some(Err(NetplayError::ProtocolMismatch(hello.version)));
+            }
+            if hello.rom_hash != *rom_hash {
+                log::error!(
+                    "Peer {:?} has netplay ROM {}, ours is {rom_hash}. Make sure both use the same netplay-rom.nes",
+                    peer,
+                    hello.rom_hash
+                );
+                return Some(Err(NetplayError::RomMismatch));
+            }
+            let features = hello.features & Features::ours();
             log::debug!(
-                "Peering up through WebRTC socket: room_url={:?}, ice_server={:?}",
-                room_url,
-                ice_server
+                "Peer {:?} has the same netplay ROM ({rom_hash}), protocol version {} and {:?} in common",
+                peer,
+                hello.version,
+                features
             );
-            WebRtcSocketBuilder::new(room_url)
-                .ice_server(ice_server)
-                .add_channel(ChannelConfig::unreliable())
-                .build()
-        };
-
-        let loop_fut = loop_fut.fuse();
-        let timeout = Delay::new(Duration::from_millis(100));
-        tokio::spawn(async move {
-            futures::pin_mut!(loop_fut, timeout);
-            loop {
-                select! {
-                    _ = (&mut timeout).fuse() => {
-                        timeout.reset(Duration::from_millis(100));
-                    }
-
-                    _ = &mut loop_fut => {
-                        break;
-                    }
+            if features.contains(Features::PROFILE) {
+                if let Some(profile) = PlayerProfile::ours() {
+                    channel.send(profile.encode(), peer);
                 }
             }
-        });
+            if features.contains(Features::FAST_FORWARD) {
+                if let Some(frames) = self.confirmed_inputs {
+                    channel.send(encode_fast_forward(frames), peer);
+                }
+            }
+            if features.contains(Features::RESUME_FRAMES) && self.resume_frame.is_some() {
+                channel.send(encode_resume_frames(&self.resume_frames), peer);
+            }
+            self.peer_features = Some(features);
+            self.peer_heartbeat_interval = hello.heartbeat_interval();
+        }
+        let waiting_for_inputs = self.fast_forwards() && self.peer_confirmed_inputs.is_none();
+        let waiting_for_frames = self.agrees_on_resume_frame() && self.peer_resume_frames.is_none();
+        (self.peer_features.is_some() && !waiting_for_inputs && !waiting_for_frames)
+            .then_some(Ok(()))
+    }
 
-        Self {
-            socket,
-            ggrs_config: conf.ggrs.clone(),
-            unlock_url: maybe_unlock_url,
+    fn agrees_on_resume_frame(&self) -> bool {
+        self.resume_frame.is_some()
+            && self
+                .peer_features
+                .is_some_and(|features| features.contains(Features::RESUME_FRAMES))
+    }
+
+    /// Whether this is the attempt both resume from. Peers from before `Features::RESUME_FRAMES` take the first attempt that connects.
+    fn is_agreed_resume_frame(&self) -> bool {
+        if !self.agrees_on_resume_frame() {
+            return true;
+        }
+        let agreed = self
+            .peer_resume_frames
+            .as_deref()
+            .and_then(|theirs| agree_on_resume_frame(&self.resume_frames, theirs));
+        agreed == self.resume_frame
+    }
+
+    fn fast_forwards(&self) -> bool {
+        self.confirmed_inputs.is_some()
+            && self
+                .peer_features
+                .is_some_and(|features| features.contains(Features::FAST_FORWARD))
+    }
+
+    /// How many frames of confirmed inputs both have, the resumed session starts that many frames after the resume state
+    fn fast_forward_frames(&self) -> usize {
+        if !self.fast_forwards() {
+            return 0;
+        }
+        self.confirmed_inputs
+            .min(self.peer_confirmed_inputs)
+            .unwrap_or(0) as usize
+    }
+
+    fn leave(&mut self) {
+        // A match might have been assigned while we were cancelling, decline it by leaving
+        self.socket.update_peers();
+        for peer in self.socket.connected_peers() {
+            log::debug!("Declining match with peer {:?}", peer);
         }
+        log::debug!("Leaving matchmaking room");
+        self.signalling_task.abort();
     }
 }
 
 pub struct SynchonizingState {
     p2p_session: P2PSession<GGRSConfig>,
+    rejected_packets: RejectedPackets,
+    liveness: Liveness,
+    peer_profile: PeerProfile,
+    prediction: PredictionPolicy,
+    desync_check: bool,
+    remote_peer: Option<PeerId>,
+    signalling_task: JoinHandle<()>,
     pub unlock_url: Option<String>,
     pub start_time: Instant,
 }
 impl SynchonizingState {
-    pub fn new(p2p_session: P2PSession<GGRSConfig>, unlock_url: Option<String>) -> Self {
+    pub fn new(
+        p2p_session: P2PSession<GGRSConfig>,
+        rejected_packets: RejectedPackets,
+        liveness: Liveness,
+        peer_profile: PeerProfile,
+        prediction: PredictionPolicy,
+        desync_check: bool,
+        remote_peer: Option<PeerId>,
+        signalling_task: JoinHandle<()>,
+        unlock_url: Option<String>,
+    ) -> Self {
         SynchonizingState {
             p2p_session,
+            rejected_packets,
+            liveness,
+            peer_profile,
+            prediction,
+            desync_check,
+            remote_peer,
+            signalling_task,
             unlock_url,
             start_time: Instant::now(),
         }
@@ -219,29 +565,120 @@ impl SynchonizingState {
 }
 type RoomName = String;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum JoinOrHost {
     Join,
     Host,
 }
 
+/// How a session came about, it stays the same when the session is resumed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionKind {
+    /// A private game, hosted or joined by room name
+    Private { room_name: String },
+    /// Matched with anyone in the same queue, `None` being the default one
+    Public { queue_tag: Option<String> },
+}
+
 #[derive(Clone, Debug)]
 pub enum StartMethod {
     Start(StartState, RoomName, JoinOrHost),
-    Resume(StartState),
+    // Resuming keeps the role and the kind of the session that was lost
+    Resume(StartState, JoinOrHost, SessionKind),
     MatchWithRandom(StartState),
 }
 
+impl StartMethod {
+    pub fn kind(&self) -> SessionKind {
+        match self {
+            StartMethod::Start(_, room_name, _) => SessionKind::Private {
+                room_name: room_name.clone(),
+            },
+            StartMethod::Resume(_, _, kind) => kind.clone(),
+            StartMethod::MatchWithRandom(start_state) => SessionKind::Public {
+                queue_tag: start_state.queue_tag.clone(),
+            },
+        }
+    }
+
+    pub fn start_state(&self) -> &StartState {
+        match self {
+            StartMethod::Start(start_state, ..)
+            | StartMethod::Resume(start_state, ..)
+            | StartMethod::MatchWithRandom(start_state) => start_state,
+        }
+    }
+
+    /// A random match gets a session id of its own once matched (see `with_match_nonce`), the others keep theirs.
+    /// Resuming keeps the id of the session, so from then on it's only this pair's.
+    fn matched_with(&mut self, peers: &[PeerId]) {
+        if let StartMethod::MatchWithRandom(start_state) = self {
+            start_state.session_id = with_match_nonce(&start_state.session_id, peers);
+        }
+    }
+
+    fn start_state_mut(&mut self) -> &mut StartState {
+        match self {
+            StartMethod::Start(start_state, ..)
+            | StartMethod::Resume(start_state, ..)
+            | StartMethod::MatchWithRandom(start_state) => start_state,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct StartState {
-    pub game_state: NetplayNesState,
+    // Shared since the start method is cloned on every retry and resume attempt
+    pub game_state: Arc<NetplayNesState>,
     pub session_id: String,
+    /// The md5 of the netplay ROM, see `GameRom::netplay_rom_hash`
+    pub rom_hash: String,
+    /// The matchmaking pool of a public game, `None` is the default pool
+    pub queue_tag: Option<String>,
+    /// `Some` for public games, how many times the search was started again after a failed connection
+    pub requeues: Option<u16>,
+    /// The confirmed inputs of the frames right after `game_state`, only kept when resuming with `resume_fast_forward`.
+    /// Cut down to what the peer has too before the session starts.
+    pub confirmed_inputs: Arc<Vec<[JoypadState; MAX_PLAYERS]>>,
+    /// When to give up connecting, set by [`ConnectingState::connect`]. `None` waits for the other player for as long as it takes.
+    pub connect_deadline: Option<ConnectDeadline>,
+    /// The frames of all the resume attempts, empty unless resuming. Both peers resume from the newest one they have in common.
+    pub resume_frames: Arc<Vec<i32>>,
+    /// What every timeout of the connection and the session after it reads the time from
+    pub clock: NetplayClock,
+}
+
+/// See `connect_timeout_secs`
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectDeadline {
+    pub at: Instant,
+    pub timeout: Duration,
+}
+
+impl StartState {
+    fn start_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_deadline = timeout.map(|timeout| ConnectDeadline {
+            at: self.clock.now() + timeout,
+            timeout,
+        });
+    }
+
+    /// The timeout that ran out, if it did
+    fn connect_timed_out(&self) -> Option<Duration> {
+        self.connect_deadline
+            .filter(|deadline| self.clock.now() >= deadline.at)
+            .map(|deadline| deadline.timeout)
+    }
 }
 
 impl Debug for StartState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("StartState")
             .field("session_id", &self.session_id)
+            .field("rom_hash", &self.rom_hash)
+            .field("queue_tag", &self.queue_tag)
+            .field("requeues", &self.requeues)
+            .field("confirmed_inputs", &self.confirmed_inputs.len())
             .finish()
     }
 }
@@ -253,6 +690,7 @@ impl Connecting<LoadingNetplayServerConfiguration> {
         }) {
             Ok(Some(Ok(resp))) => {
                 log::debug!("Got TurnOn config response: {:?}", resp);
+                matchmaking::set_reply(resp.conf().matchmaking_reply.clone());
                 ConnectingState::PeeringUp(Connecting::from(
                     PeeringState::new(resp, self.start_method.clone()),
                     self,
@@ -264,10 +702,10 @@ impl Connecting<LoadingNetplayServerConfiguration> {
                     "Failed to retrieve netplay server configuration: {:?}, retrying...",
                     e
                 );
-                ConnectingState::Retrying(self.into_retrying(&format!(
+                ConnectingState::Retrying(self.into_retrying(NetplayError::ServerError(format!(
                     "Failed to retrieve {} configuration.",
                     Bundle::current().config.vocabulary.netplay.name
-                )))
+                ))))
             }
         }
     }
@@ -282,25 +720,87 @@ impl Connecting<PeeringState> {
     }
 
     fn advance(mut self) -> ConnectingState {
+        if self.state.signalling_task.is_finished() {
+            // Nobody new can find us without it
+            log::error!("The connection to the matchmaking server ended");
+            return ConnectingState::Failed(NetplayError::ServerError(
+                "Lost the connection to the matchmaking server".to_string(),
+            ));
+        }
         let socket = &mut self.state.socket;
         socket.update_peers();
 
         let connected_peers = socket.connected_peers().count();
         if connected_peers >= MAX_PLAYERS {
-            return ConnectingState::Failed("Room is full".to_string());
+            return ConnectingState::Failed(NetplayError::RoomFull);
         }
 
         let remaining = MAX_PLAYERS - (connected_peers + 1);
+        if remaining > 0
+            && self
+                .state
+                .probe
+                .as_mut()
+                .is_some_and(|probe| probe.other_rom_in_room())
+        {
+            self.state.leave();
+            return ConnectingState::Failed(NetplayError::OtherGameInRoom);
+        }
         if remaining == 0 {
+            match self.state.verify_peer() {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    self.state.signalling_task.abort();
+                    return ConnectingState::Failed(e);
+                }
+                None => return ConnectingState::PeeringUp(self),
+            }
+            if !self.state.is_agreed_resume_frame() {
+                // The peer is in this room too and waits just the same, the attempt for the agreed frame connects and cancels both
+                return ConnectingState::PeeringUp(self);
+            }
+            let fast_forward_frames = self.state.fast_forward_frames();
+            if let StartMethod::Resume(start_state, ..) = &mut self.start_method {
+                if fast_forward_frames > 0 {
+                    log::info!("Fast forwarding {fast_forward_frames} frames after resuming");
+                }
+                let inputs = &start_state.confirmed_inputs[..fast_forward_frames];
+                start_state.confirmed_inputs = Arc::new(inputs.to_vec());
+            }
+            let socket = &mut self.state.socket;
+            if let StartMethod::MatchWithRandom(_) = &self.start_method {
+                let Some(our_id) = socket.id() else {
+                    // Without it the pair can't agree on its session id, asking again next frame
+                    log::debug!("Matched, waiting for the server to tell our own peer id");
+                    return ConnectingState::PeeringUp(self);
+                };
+                let peers: Vec<PeerId> = socket.connected_peers().chain([our_id]).collect();
+                self.start_method.matched_with(&peers);
+                log::debug!(
+                    "Matched, the session id is {}",
+                    self.start_method.start_state().session_id
+                );
+            }
             log::debug!("Got all players! Synchonizing...");
             let players = socket.players();
+            let remote_peer = socket.connected_peers().next();
             let ggrs_config = self.state.ggrs_config.clone();
+            let (mode, input_delay) = Bundle::current()
+                .config
+                .netplay
+                .mode_and_input_delay(ggrs_config.input_delay);
+            let max_prediction = match mode {
+                NetplayMode::Rollback => ggrs_config.max_prediction,
+                // Without a prediction window GGRS waits for the confirmed inputs of every frame
+                NetplayMode::Lockstep => 0,
+            };
+            log::debug!("Netplay mode: {mode:?}, input delay: {input_delay}");
             let mut sess_build = SessionBuilder::<GGRSConfig>::new()
                 .with_num_players(MAX_PLAYERS)
-                .with_input_delay(ggrs_config.input_delay)
+                .with_input_delay(input_delay)
                 .with_fps(Settings::current_mut().get_nes_region().to_fps() as usize)
                 .unwrap()
-                .with_max_prediction_window(ggrs_config.max_prediction)
+                .with_max_prediction_window(max_prediction)
                 .expect("ggrs session to configure");
 
             for (i, player) in players.into_iter().enumerate() {
@@ -308,13 +808,51 @@ impl Connecting<PeeringState> {
                     .add_player(player, i)
                     .expect("player to be added to ggrs session");
             }
+            let desync_check = self
+                .state
+                .peer_features
+                .unwrap_or(Features::empty())
+                .contains(Features::DESYNC_CHECK);
+            if desync_check {
+                log::debug!("Checking for desyncs every {DESYNC_CHECK_INTERVAL} frames");
+                sess_build = sess_build.with_desync_detection_mode(DesyncDetection::On {
+                    interval: DESYNC_CHECK_INTERVAL,
+                });
+            }
 
+            let socket = {
+                let netplay_settings = &Settings::current().netplay;
+                ValidatingSocket::new(
+                    self.state.socket,
+                    netplay_settings.jitter_buffer,
+                    netplay_settings.heartbeat_interval(),
+                    self.state.peer_heartbeat_interval,
+                    self.state.peer_features.unwrap_or(Features::empty()),
+                    self.state.peer_profile.clone(),
+                    self.start_method.start_state().clock.clone(),
+                )
+            };
+            let rejected_packets = socket.rejected_packets();
+            let liveness = socket.liveness();
+            let prediction = self
+                .state
+                .peer_features
+                .unwrap_or(Features::empty())
+                .prediction();
+            log::debug!("Predicting {prediction:?} for the other player");
             ConnectingState::Synchronizing(Box::new(Connecting {
                 start_method: self.start_method,
                 state: SynchonizingState::new(
                     sess_build
-                        .start_p2p_session(self.state.socket)
+                        .start_p2p_session(socket)
                         .expect("ggrs session to start"),
+                    rejected_packets,
+                    liveness,
+                    self.state.peer_profile,
+                    prediction,
+                    desync_check,
+                    remote_peer,
+                    self.state.signalling_task,
                     self.state.unlock_url.clone(),
                 ),
             }))
@@ -327,12 +865,31 @@ impl Connecting<PeeringState> {
 impl Connecting<SynchonizingState> {
     fn advance(mut self) -> ConnectingState {
         self.state.p2p_session.poll_remote_clients();
+        if self.state.rejected_packets.over_limit() {
+            log::error!("Peer keeps sending garbage, giving up");
+            self.state.signalling_task.abort();
+            return ConnectingState::Failed(NetplayError::ProtocolError);
+        }
+        if self.state.rejected_packets.flooding() {
+            log::error!("Peer keeps flooding us, giving up");
+            self.state.signalling_task.abort();
+            return ConnectingState::Failed(NetplayError::PeerFlooding);
+        }
         if let SessionState::Running = self.state.p2p_session.current_state() {
             let start_method = self.start_method;
             log::debug!("Synchronized!");
             ConnectingState::Connected(Box::new(Connecting {
                 start_method: start_method.clone(),
-                state: NetplaySession::new(start_method.clone(), self.state.p2p_session),
+                state: NetplaySession::new(
+                    start_method.clone(),
+                    self.state.p2p_session,
+                    self.state.rejected_packets,
+                    self.state.liveness,
+                    self.state.peer_profile,
+                    self.state.prediction,
+                    self.state.desync_check,
+                    self.state.remote_peer,
+                ),
             }))
         } else {
             ConnectingState::Synchronizing(Box::new(self))
@@ -343,30 +900,39 @@ const RETRY_COOLDOWN: Duration = Duration::from_secs(3);
 const MAX_RETRY_ATTEMPTS: u16 = 3;
 
 pub struct Retrying {
+    clock: NetplayClock,
     failed_attempts: u16,
     pub deadline: Instant,
-    pub fail_message: String,
+    pub reason: NetplayError,
     pub retry_state: Box<ConnectingState>, //The state we should resume to after the deadline
 }
 impl Retrying {
-    fn new(fail_message: String, retry_state: ConnectingState) -> Self {
+    fn new(reason: NetplayError, retry_state: ConnectingState, clock: NetplayClock) -> Self {
         Self {
+            deadline: clock.now() + RETRY_COOLDOWN,
+            clock,
             failed_attempts: 1,
-            deadline: Instant::now() + RETRY_COOLDOWN,
-            fail_message,
+            reason,
             retry_state: Box::new(retry_state),
         }
     }
+
+    fn is_due(&self) -> bool {
+        self.clock.now() > self.deadline
+    }
 }
 
 impl Connecting<Retrying> {
     fn advance(self) -> ConnectingState {
-        if Instant::now().gt(&self.state.deadline) {
+        if self.state.is_due() {
             match self.state.retry_state.advance() {
                 ConnectingState::Retrying(mut retrying) => {
                     let failed_attempts = self.state.failed_attempts + 1;
                     if failed_attempts > MAX_RETRY_ATTEMPTS {
-                        log::warn!("All retry attempt failed, using fallback configuration");
+                        log::warn!(
+                            "All retry attempt failed ({}), using fallback configuration",
+                            retrying.state.reason
+                        );
                         ConnectingState::PeeringUp(Connecting::from(
                             PeeringState::new(
                                 TurnOnResponse::Full(StaticNetplayServerConfiguration {
@@ -384,6 +950,7 @@ impl Connecting<Retrying> {
                                         max_prediction: 12,
                                         input_delay: 2,
                                     },
+                                    matchmaking_reply: None,
                                 }),
                                 self.start_method.clone(),
                             ),
@@ -414,6 +981,15 @@ pub enum TurnOnResponse {
     Full(StaticNetplayServerConfiguration),
 }
 
+impl TurnOnResponse {
+    pub(super) fn conf(&self) -> &StaticNetplayServerConfiguration {
+        match self {
+            TurnOnResponse::Basic(BasicConfiguration { conf, .. }) => conf,
+            TurnOnResponse::Full(conf) => conf,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct BasicConfiguration {
     unlock_url: String,
@@ -449,3 +1025,105 @@ pub enum IceCredentials {
     None,
     Password(IcePasswordCredentials),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulation::LocalNesState, netplay::clock::ManualClock};
+
+    fn start_state(clock: &ManualClock) -> StartState {
+        let bundle = Bundle::current();
+        let nes_state = LocalNesState::start_rom(
+            &bundle.netplay_rom,
+            false,
+            bundle.config.get_default_region(),
+        )
+        .expect("the bundled netplay ROM to start");
+        StartState {
+            game_state: Arc::new(NetplayNesState::new(nes_state)),
+            session_id: "test".to_string(),
+            rom_hash: "test".to_string(),
+            queue_tag: None,
+            requeues: None,
+            confirmed_inputs: Default::default(),
+            connect_deadline: None,
+            resume_frames: Default::default(),
+            clock: NetplayClock::Manual(clock.clone()),
+        }
+    }
+
+    #[test]
+    fn retrying_waits_for_the_cooldown() {
+        let clock = ManualClock::new();
+        let retrying = Retrying::new(
+            NetplayError::ServerError("test".into()),
+            ConnectingState::Failed(NetplayError::RoomFull),
+            NetplayClock::Manual(clock.clone()),
+        );
+        assert!(!retrying.is_due());
+        clock.advance(RETRY_COOLDOWN);
+        assert!(!retrying.is_due());
+        clock.advance(Duration::from_millis(1));
+        assert!(retrying.is_due());
+    }
+
+    #[test]
+    fn connecting_gives_up_after_the_connect_timeout() {
+        let clock = ManualClock::new();
+        let timeout = Duration::from_secs(2);
+        // Shorter than the retry cooldown, so the attempt is still waiting to be retried when the time is up
+        assert!(timeout < RETRY_COOLDOWN);
+        let mut start_state = start_state(&clock);
+        start_state.start_connect_timeout(Some(timeout));
+        let mut state = ConnectingState::Retrying(Connecting {
+            start_method: StartMethod::MatchWithRandom(start_state),
+            state: Retrying::new(
+                NetplayError::ServerError("test".into()),
+                ConnectingState::Failed(NetplayError::RoomFull),
+                NetplayClock::Manual(clock.clone()),
+            ),
+        });
+
+        let step = Duration::from_millis(100);
+        for _ in 0..(timeout.as_millis() / step.as_millis()) {
+            state = state.advance();
+            assert!(matches!(state, ConnectingState::Retrying(_)));
+            clock.advance(step);
+        }
+        match state.advance() {
+            ConnectingState::Failed(reason) => {
+                assert_eq!(reason, NetplayError::ConnectTimedOut(2))
+            }
+            _ => panic!("expected the connection to time out"),
+        }
+    }
+
+    #[test]
+    fn a_resume_keeps_the_id_of_the_match() {
+        let clock = ManualClock::new();
+        let peers = [PeerId(uuid::Uuid::new_v4()), PeerId(uuid::Uuid::new_v4())];
+        let mut start_method = StartMethod::MatchWithRandom(start_state(&clock));
+        start_method.matched_with(&peers);
+        let matched_id = start_method.start_state().session_id.clone();
+        assert_ne!(matched_id, "test");
+
+        let mut resume_state = start_state(&clock);
+        resume_state.session_id = matched_id.clone();
+        let mut resume = StartMethod::Resume(
+            resume_state,
+            JoinOrHost::Host,
+            SessionKind::Public { queue_tag: None },
+        );
+        resume.matched_with(&peers);
+        assert_eq!(resume.start_state().session_id, matched_id);
+    }
+
+    #[test]
+    fn no_connect_timeout_waits_forever() {
+        let clock = ManualClock::new();
+        let mut start_state = start_state(&clock);
+        start_state.start_connect_timeout(None);
+        clock.advance(Duration::from_secs(60 * 60));
+        assert_eq!(start_state.connect_timed_out(), None);
+    }
+}
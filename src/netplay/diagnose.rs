@@ -0,0 +1,245 @@
+use std::{
+    fmt::Display,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+
+use crate::{bundle::Bundle, emulation::validate_rom};
+
+use super::{
+    connecting_state::{fetch_first_turn_on_config, NetplayServerConfiguration},
+    netplay_state::{get_server_netplay_id, GameRom},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+// Used when the server configuration has less than two STUN servers to compare
+const FALLBACK_STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+
+#[derive(Debug, Clone)]
+pub enum CheckResult {
+    Passed(String),
+    Failed(String),
+}
+
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckResult::Passed(message) | CheckResult::Failed(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum NatType {
+    /// The same public address is used towards every peer, so a direct connection should work
+    EndpointIndependent(SocketAddr),
+    /// Every destination gets its own public port, peers can't reach us without a relay
+    Symmetric,
+    Unknown(String),
+}
+
+/// The outcome of the netplay connection checks, see [`diagnose`].
+#[derive(Debug, Clone)]
+pub struct Diagnosis {
+    pub server: CheckResult,
+    pub nat: NatType,
+    pub netplay_rom: CheckResult,
+}
+
+impl Diagnosis {
+    /// One line per check, worded as advice for the player.
+    pub fn summary(&self) -> Vec<String> {
+        let server = match &self.server {
+            CheckResult::Passed(message) => format!("✔ {message}"),
+            CheckResult::Failed(message) => {
+                format!("✖ {message}; check your internet connection and firewall")
+            }
+        };
+        let nat = match &self.nat {
+            NatType::EndpointIndependent(addr) => {
+                format!("✔ Your NAT allows direct connections (public address {addr})")
+            }
+            NatType::Symmetric => "✖ Your NAT is symmetric; a relay is required".to_string(),
+            NatType::Unknown(reason) => format!("? Could not detect your NAT type: {reason}"),
+        };
+        let netplay_rom = match &self.netplay_rom {
+            CheckResult::Passed(message) => format!("✔ {message}"),
+            CheckResult::Failed(message) => {
+                format!("✖ {message}; the other player will be rejected")
+            }
+        };
+        vec![server, nat, netplay_rom]
+    }
+}
+
+/// Checks what usually makes a netplay connection fail: an unreachable server, a NAT that needs a relay, and a broken netplay ROM.
+pub async fn diagnose(rom: Arc<GameRom>) -> Diagnosis {
+    let netplay_rom = check_netplay_rom(&rom);
+    let (server, stun_servers) = check_server().await;
+    let nat = tokio::task::spawn_blocking(move || detect_nat_type(&stun_servers))
+        .await
+        .unwrap_or_else(|e| NatType::Unknown(format!("{e}")));
+    let diagnosis = Diagnosis {
+        server,
+        nat,
+        netplay_rom,
+    };
+    log::info!("Netplay diagnosis: {:?}", diagnosis);
+    diagnosis
+}
+
+fn check_netplay_rom(rom: &GameRom) -> CheckResult {
+    match validate_rom(&rom.netplay_rom) {
+        Ok(()) => CheckResult::Passed(format!(
+            "Netplay ROM present (hash {})",
+            rom.netplay_rom_hash
+        )),
+        Err(e) => CheckResult::Failed(format!("Netplay ROM is invalid: {e}")),
+    }
+}
+
+/// Returns the result along with the STUN servers of the configuration, if it could be loaded.
+async fn check_server() -> (CheckResult, Vec<String>) {
+    let conf = match &Bundle::current().config.netplay.server {
+        NetplayServerConfiguration::Static(conf) => conf.clone(),
+        NetplayServerConfiguration::TurnOn(servers) => {
+            match fetch_first_turn_on_config(
+                &reqwest::Client::new(),
+                servers.urls(),
+                &get_server_netplay_id(),
+            )
+            .await
+            {
+                Ok(resp) => resp.conf().clone(),
+                Err(e) => return (CheckResult::Failed(e.description), vec![]),
+            }
+        }
+    };
+
+    let stun_servers = conf
+        .ice_urls()
+        .iter()
+        .filter_map(|url| url.strip_prefix("stun:"))
+        .map(|server| server.to_string())
+        .collect();
+
+    let matchbox_server = conf.matchbox_server().to_string();
+    let result =
+        tokio::task::spawn_blocking(move || connect(&matchbox_server).map(|_| matchbox_server))
+            .await
+            .map_err(|e| anyhow!("{e}"))
+            .and_then(|r| r);
+    let result = match result {
+        Ok(server) => CheckResult::Passed(format!("Netplay server {server} is reachable")),
+        Err(e) => CheckResult::Failed(format!("Netplay server is unreachable: {e}")),
+    };
+    (result, stun_servers)
+}
+
+fn connect(server: &str) -> Result<()> {
+    let addr = resolve(server)?;
+    TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    Ok(())
+}
+
+fn resolve(server: &str) -> Result<SocketAddr> {
+    server
+        .to_socket_addrs()?
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| anyhow!("Could not resolve {server}"))
+}
+
+/// Asks two STUN servers for our public address from the same local port.
+/// If they see different ports the NAT maps every destination separately (symmetric).
+fn detect_nat_type(stun_servers: &[String]) -> NatType {
+    let mut servers: Vec<&str> = stun_servers.iter().map(|s| s.as_str()).collect();
+    if servers.len() < 2 {
+        servers = FALLBACK_STUN_SERVERS.to_vec();
+    }
+    let socket = match UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| socket.set_read_timeout(Some(STUN_TIMEOUT)).map(|_| socket))
+    {
+        Ok(socket) => socket,
+        Err(e) => return NatType::Unknown(format!("{e}")),
+    };
+
+    let mut mapped_addresses = vec![];
+    for server in servers.iter().take(2) {
+        match stun_binding(&socket, server) {
+            Ok(addr) => mapped_addresses.push(addr),
+            Err(e) => return NatType::Unknown(format!("{server}: {e}")),
+        }
+    }
+    if mapped_addresses[0] == mapped_addresses[1] {
+        NatType::EndpointIndependent(mapped_addresses[0])
+    } else {
+        NatType::Symmetric
+    }
+}
+
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Sends a STUN (RFC 5389) binding request and returns the address the server saw us coming from.
+fn stun_binding(socket: &UdpSocket, server: &str) -> Result<SocketAddr> {
+    let mut transaction_id = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut transaction_id);
+
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    socket.send_to(&request, resolve(server)?)?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf)?;
+        let response = &buf[..len];
+        // Skip anything that isn't the answer to this request
+        if len >= 20
+            && u16::from_be_bytes([response[0], response[1]]) == STUN_BINDING_RESPONSE
+            && response[8..20] == transaction_id
+        {
+            return parse_mapped_address(&response[20..])
+                .ok_or_else(|| anyhow!("No mapped address in the response"));
+        }
+    }
+}
+
+fn parse_mapped_address(mut attributes: &[u8]) -> Option<SocketAddr> {
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        // Only IPv4 is asked for, the family is the second byte of the value
+        if value.len() >= 8 && value[1] == 0x01 {
+            let port = u16::from_be_bytes([value[2], value[3]]);
+            let ip = u32::from_be_bytes([value[4], value[5], value[6], value[7]]);
+            match kind {
+                STUN_ATTR_XOR_MAPPED_ADDRESS => {
+                    return Some(SocketAddr::from((
+                        (ip ^ STUN_MAGIC_COOKIE).to_be_bytes(),
+                        port ^ (STUN_MAGIC_COOKIE >> 16) as u16,
+                    )));
+                }
+                STUN_ATTR_MAPPED_ADDRESS => {
+                    return Some(SocketAddr::from((ip.to_be_bytes(), port)));
+                }
+                _ => {}
+            }
+        }
+        // Attributes are padded to 4 bytes
+        let padded_len = (len + 3) & !3;
+        attributes = attributes.get(4 + padded_len..)?;
+    }
+    None
+}
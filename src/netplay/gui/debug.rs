@@ -6,7 +6,7 @@ use std::{
 use ggrs::NetworkStats;
 
 use crate::{
-    netplay::{netplay_state::NetplayState, NetplayStateHandler},
+    netplay::{netplay_state::NetplayState, transitions::TransitionLog, NetplayStateHandler},
     settings::MAX_PLAYERS,
 };
 
@@ -58,6 +58,17 @@ impl NetplayGui {
             };
         }
     }
+    pub(crate) fn transitions_ui(ui: &mut egui::Ui, transitions: &TransitionLog) {
+        ui.collapsing("Transitions", |ui| {
+            for transition in transitions.iter() {
+                ui.monospace(transition.to_string());
+            }
+            if ui.button("Copy timeline").clicked() {
+                ui.output_mut(|o| o.copied_text = transitions.timeline());
+            }
+        });
+    }
+
     pub(crate) fn stats_ui(ui: &mut egui::Ui, stats: &NetplayStats, player: usize) {
         if !stats.get_ping().is_empty() {
             ui.label(format!("Player {player}"));
@@ -1,19 +1,27 @@
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use egui::{Align, Button, Color32, FontId, Label, RichText, TextEdit, Ui, Widget};
+use futures::channel::oneshot::Receiver;
 use serde::Deserialize;
 
 use crate::{
     bundle::Bundle,
     emulation::LocalNesState,
     gui::{esc_pressed, MenuButton},
-    main_view::gui::{MainGui, MainMenuState},
+    input::KeyEvent,
+    main_view::gui::{GuiEvent, MainGui, MainMenuState},
     netplay::{connecting_state::StartMethod, netplay_state::MAX_ROOM_NAME_LEN},
+    settings::Settings,
 };
 
 use super::{
-    connecting_state::{Connecting, SynchonizingState},
-    netplay_state::{Connected, Netplay, NetplayState},
+    connecting_state::{Connecting, SessionKind, SynchonizingState},
+    diagnose::{diagnose, Diagnosis},
+    netplay_session::NetplaySession,
+    netplay_state::{sanitize_room_name, Connected, GameRom, Netplay, NetplayState, Resuming},
     ConnectingState, NetplayStateHandler,
 };
 #[cfg(feature = "debug")]
@@ -36,6 +44,17 @@ pub struct NetplayGui {
     pub stats: [debug::NetplayStats; crate::settings::MAX_PLAYERS],
     room_name: Option<String>,
     last_screen: Option<&'static str>,
+    show_stats: bool,
+    /// Set by the diagnostics key until the report has been copied, see `diagnostics_report`
+    pub copy_diagnostics: bool,
+    diagnosis: Option<DiagnosisState>,
+    /// Offers to leave once the match has ended, see `MatchEndWatch`
+    pub match_over: bool,
+}
+
+enum DiagnosisState {
+    Running(Receiver<Diagnosis>),
+    Done(Vec<String>),
 }
 
 impl NetplayGui {
@@ -45,8 +64,51 @@ impl NetplayGui {
             stats: [debug::NetplayStats::new(), debug::NetplayStats::new()],
             room_name: None,
             last_screen: None,
+            show_stats: false,
+            copy_diagnostics: false,
+            diagnosis: None,
+            match_over: false,
         }
     }
+
+    fn ui_diagnosis(&mut self, ui: &mut Ui, rom: &Arc<GameRom>) {
+        if let Some(DiagnosisState::Running(result)) = &mut self.diagnosis {
+            match result.try_recv() {
+                Ok(Some(diagnosis)) => {
+                    self.diagnosis = Some(DiagnosisState::Done(diagnosis.summary()))
+                }
+                Ok(None) => {}
+                Err(_) => self.diagnosis = None,
+            }
+        }
+        match &self.diagnosis {
+            Some(DiagnosisState::Running(_)) => {
+                ui.vertical_centered(|ui| {
+                    ui.label("Diagnosing connection...");
+                });
+            }
+            Some(DiagnosisState::Done(summary)) => {
+                ui.vertical_centered(|ui| {
+                    for line in summary {
+                        ui.label(line);
+                    }
+                });
+            }
+            None => {
+                ui.vertical_centered(|ui| {
+                    if ui_button("Diagnose connection").ui(ui).clicked() {
+                        let (sender, result) = futures::channel::oneshot::channel();
+                        let rom = rom.clone();
+                        tokio::spawn(async move {
+                            let _ = sender.send(diagnose(rom).await);
+                        });
+                        self.diagnosis = Some(DiagnosisState::Running(result));
+                    }
+                });
+            }
+        }
+        ui.end_row();
+    }
 }
 
 fn ui_text_small(text: impl Into<String>, color: Color32) -> RichText {
@@ -72,6 +134,57 @@ impl NetplayGui {
         }
         None
     }
+    pub fn handle_event(&mut self, gui_event: &GuiEvent) {
+        if let GuiEvent::Keyboard(KeyEvent::Pressed(key_code)) = gui_event {
+            let netplay_settings = &Settings::current().netplay;
+            if *key_code == netplay_settings.stats_overlay_key {
+                self.show_stats = !self.show_stats;
+            }
+            if *key_code == netplay_settings.diagnostics_key {
+                self.copy_diagnostics = true;
+            }
+        }
+    }
+
+    /// Like "P2: 45ms, P3: —", a dash for players that haven't answered yet
+    fn peer_pings_text(netplay_session: &NetplaySession) -> String {
+        netplay_session
+            .peer_pings()
+            .into_iter()
+            .map(|(slot, ping)| match ping {
+                Some(ping) => format!("P{}: {ping}ms", slot + 1),
+                None => format!("P{}: —", slot + 1),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn stats_message(netplay_state_handler: &NetplayStateHandler) -> Option<String> {
+        match &netplay_state_handler.netplay {
+            Some(NetplayState::Connected(netplay)) => {
+                let netplay_session = &netplay.state.netplay_session;
+                Some(match netplay_session.remote_stats() {
+                    Some(stats) => format!(
+                        "{}, frame {} (confirmed {}), rollback {}, ahead {}, behind {}/{}",
+                        Self::peer_pings_text(netplay_session),
+                        netplay_session.current_frame(),
+                        netplay_session.confirmed_frame(),
+                        stats.rollback_depth,
+                        stats.frames_ahead,
+                        stats.local_frames_behind,
+                        stats.remote_frames_behind
+                    ),
+                    None => format!(
+                        "{}, waiting for stats...",
+                        Self::peer_pings_text(netplay_session)
+                    ),
+                })
+            }
+            // While resuming, "Trying to reconnect..." takes the place of the stats
+            _ => None,
+        }
+    }
+
     pub fn messages(&self, netplay_state_handler: &NetplayStateHandler) -> Option<Vec<String>> {
         if matches!(MainGui::main_menu_state(), MainMenuState::Netplay) {
             // No need to show messages when the netplay menu is already showing status
@@ -82,15 +195,59 @@ impl NetplayGui {
             match &netplay_state_handler.netplay {
                 // Connecting is a modal state, you can't see any messages when in the netplay UI anyway
                 Some(NetplayState::Connecting(_)) => None,
-                Some(NetplayState::Resuming(_)) => Some("Trying to reconnect...".to_string()),
+                Some(NetplayState::Resuming(resuming)) => Self::reconnecting_message(resuming),
                 _ => None,
             }
-            .iter()
+            .into_iter()
+            .chain(if self.show_stats {
+                Self::stats_message(netplay_state_handler)
+            } else {
+                None
+            })
             .map(|msg| format!("{} - {msg}", self.name().expect("a name")))
             .collect(),
         )
     }
 
+    /// `None` when the overlay is turned off, counts down when there's a grace window
+    fn reconnecting_message(resuming: &Netplay<Resuming>) -> Option<String> {
+        if !Settings::current().netplay.reconnect_overlay {
+            return None;
+        }
+        Some(match resuming.state.deadline {
+            Some(deadline) => format!(
+                "Reconnecting… ({}s)",
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f32()
+                    .ceil()
+            ),
+            None => "Reconnecting…".to_string(),
+        })
+    }
+
+    /// Instead of the ways to start a game, the bundle doesn't let this ROM be played online
+    fn ui_netplay_disabled(
+        &mut self,
+        ui: &mut Ui,
+        netplay_disconnected: Netplay<LocalNesState>,
+    ) -> NetplayState {
+        ui.vertical_centered(|ui| {
+            ui.label(format!(
+                "This game can't be played with {}, it can only be played here.",
+                Bundle::current().config.vocabulary.netplay.name
+            ));
+        });
+        ui.end_row();
+        ui.vertical_centered(|ui| {
+            if ui_button("Close").ui(ui).clicked() || esc_pressed(ui.ctx()) {
+                MainGui::set_main_menu_state(MainMenuState::Main);
+            }
+        });
+        self.last_screen = Some("NETPLAY DISABLED");
+        NetplayState::Disconnected(netplay_disconnected)
+    }
+
     fn ui_disconnected(
         &mut self,
         ui: &mut Ui,
@@ -136,7 +293,7 @@ impl NetplayGui {
 
                     if ui
                         .add_enabled(
-                            !room_name.is_empty(),
+                            sanitize_room_name(room_name).is_some(),
                             Button::new(RichText::new("Join").font(FontId::proportional(30.0))),
                         )
                         .clicked()
@@ -160,15 +317,30 @@ impl NetplayGui {
                 .inner;
             ui.end_row();
 
-            if room_name.len() > 4 {
-                *room_name = room_name[..MAX_ROOM_NAME_LEN.into()].to_string();
-            }
+            // Whatever is typed or pasted, only what makes a valid room name is kept
+            room_name.retain(|c| c.is_ascii_alphanumeric());
+            room_name.truncate(MAX_ROOM_NAME_LEN.into());
             *room_name = room_name.to_uppercase();
 
             if enter_pressed_in_room_input {
                 action = Some(Action::Join(room_name.clone()));
             }
 
+            let recent_room_names = Settings::current().netplay.recent_room_names();
+            if !recent_room_names.is_empty() {
+                ui.vertical_centered(|ui| {
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label("Recent:");
+                        for recent_room_name in recent_room_names {
+                            if ui.button(&recent_room_name).clicked() {
+                                *room_name = recent_room_name;
+                            }
+                        }
+                    });
+                });
+                ui.end_row();
+            }
+
             ui.end_row();
             ui.vertical_centered(|ui| {
                 if ui_button("Cancel").ui(ui).clicked() || esc_pressed(ui.ctx()) {
@@ -203,6 +375,26 @@ impl NetplayGui {
             });
             ui.end_row();
 
+            ui.vertical_centered(|ui| {
+                let queue_tag = &mut Settings::current_mut().netplay.queue_tag;
+                let mut text = queue_tag.clone().unwrap_or_default();
+                ui.horizontal(|ui| {
+                    ui.label("Queue");
+                    if ui
+                        .add(
+                            TextEdit::singleline(&mut text)
+                                .hint_text("default")
+                                .desired_width(120.0),
+                        )
+                        .on_hover_text("Only players in the same queue are matched. Leave empty for the default queue.")
+                        .changed()
+                    {
+                        *queue_tag = (!text.is_empty()).then_some(text);
+                    }
+                });
+            });
+            ui.end_row();
+
             ui.vertical_centered(|ui| {
                 if MenuButton::new("HOST PRIVATE GAME").ui(ui).clicked() {
                     action = Some(Action::Host);
@@ -216,9 +408,68 @@ impl NetplayGui {
                 }
             });
             ui.end_row();
+            ui.vertical_centered(|ui| {
+                ui.checkbox(
+                    &mut Settings::current_mut().netplay.go_offline_on_peer_drop,
+                    "Continue offline if the other player drops",
+                );
+            });
+            ui.end_row();
+            ui.vertical_centered(|ui| {
+                ui.checkbox(
+                    &mut Settings::current_mut().netplay.anonymous,
+                    "Connect anonymously",
+                )
+                .on_hover_text(
+                    "Use a new id for every connection. Reconnects and unlocks can't be tied to earlier connections.",
+                );
+            });
+            ui.end_row();
+            ui.vertical_centered(|ui| {
+                let netplay_settings = &mut Settings::current_mut().netplay;
+                let mut text = netplay_settings.player_name_text().to_string();
+                ui.add_enabled_ui(!netplay_settings.anonymous, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Name");
+                        if ui
+                            .add(
+                                TextEdit::singleline(&mut text)
+                                    .hint_text("none")
+                                    .desired_width(120.0),
+                            )
+                            .on_hover_text("What the other player sees you as. Not sent when connecting anonymously.")
+                            .changed()
+                        {
+                            netplay_settings.set_player_name(&text);
+                        }
+                    });
+                });
+            });
+            ui.end_row();
+            let recent_opponents = Settings::current().netplay.recent_opponents().to_vec();
+            if !recent_opponents.is_empty() {
+                ui.vertical_centered(|ui| {
+                    ui.collapsing("Recent opponents", |ui| {
+                        for opponent in &recent_opponents {
+                            ui.label(opponent.name.as_deref().unwrap_or("Unnamed"))
+                                .on_hover_text(opponent.id.as_str());
+                        }
+                    });
+                });
+                ui.end_row();
+            }
+            ui.vertical_centered(|ui| {
+                ui.checkbox(
+                    &mut Settings::current_mut().netplay.stats_log,
+                    "Log connection stats to a file",
+                );
+            });
+            ui.end_row();
+            self.ui_diagnosis(ui, netplay_disconnected.rom());
             ui.vertical_centered(|ui| {
                 if ui_button("Close").ui(ui).clicked() || esc_pressed(ui.ctx()) {
                     self.room_name = None;
+                    self.diagnosis = None;
                     MainGui::set_main_menu_state(MainMenuState::Main);
                 }
             });
@@ -228,11 +479,19 @@ impl NetplayGui {
             if let Some(action) = action {
                 match action {
                     Action::Find => {
+                        let queue_tag = Settings::current().netplay.queue_tag.clone();
                         return netplay_disconnected
-                            .find_game()
+                            .find_game(queue_tag.as_deref())
                             .expect("to be able to find a game");
                     }
-                    Action::Join => self.room_name = Some(String::new()),
+                    Action::Join => {
+                        self.room_name = Some(
+                            Settings::current()
+                                .netplay
+                                .last_room_name()
+                                .unwrap_or_default(),
+                        )
+                    }
                     Action::Host => {
                         return netplay_disconnected
                             .host_game()
@@ -314,6 +573,12 @@ impl NetplayGui {
                         ))
                         .ui(ui);
                     });
+                    ui.end_row();
+                    ui.vertical_centered(|ui| {
+                        if ui.button("Copy code").clicked() {
+                            ui.output_mut(|o| o.copied_text = room_name.clone());
+                        }
+                    });
                 }
                 StartMethod::MatchWithRandom(_) => {
                     ui.vertical_centered(|ui| {
@@ -335,7 +600,7 @@ impl NetplayGui {
                         .ui(ui);
                     });
                 }
-                StartMethod::Resume(_) => {
+                StartMethod::Resume(..) => {
                     //This is used internally during the `NetplayState::Resuming` state
                 }
             },
@@ -378,7 +643,7 @@ impl NetplayGui {
                 ui.end_row();
 
                 ui.vertical_centered(|ui| {
-                    Label::new(ui_text_small(reason, MenuButton::ACTIVE_COLOR)).ui(ui);
+                    Label::new(ui_text_small(reason.to_string(), MenuButton::ACTIVE_COLOR)).ui(ui);
                 });
             }
             // NOTE: This captures retrying and connected. Let's just show "CONNECTING" during that state
@@ -433,15 +698,78 @@ impl NetplayGui {
         });
         ui.end_row();
 
+        ui.vertical_centered(|ui| {
+            let role = if netplay_connected.state.is_host() {
+                "YOU ARE THE HOST"
+            } else {
+                "YOU ARE THE GUEST"
+            };
+            Label::new(ui_text_small(role, MenuButton::ACTIVE_COLOR))
+                .selectable(false)
+                .ui(ui);
+        });
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            ui.label(Self::peer_pings_text(
+                &netplay_connected.state.netplay_session,
+            ));
+        });
+        ui.end_row();
+
+        match netplay_connected.state.kind() {
+            SessionKind::Private { room_name } => {
+                ui.vertical_centered(|ui| {
+                    ui.label(format!("Room: {room_name}"));
+                });
+                ui.end_row();
+            }
+            SessionKind::Public {
+                queue_tag: Some(queue_tag),
+            } => {
+                ui.vertical_centered(|ui| {
+                    ui.label(format!("Queue: {queue_tag}"));
+                });
+                ui.end_row();
+            }
+            SessionKind::Public { queue_tag: None } => {}
+        }
+
+        if Bundle::current().config.player_count() < crate::settings::MAX_PLAYERS {
+            ui.vertical_centered(|ui| {
+                ui.label(
+                    RichText::new(
+                        "This game only reads player 1, the inputs of player 2 won't do anything",
+                    )
+                    .color(Color32::DARK_RED),
+                );
+            });
+            ui.end_row();
+        }
+
         #[allow(dead_code)] // Some actions are only triggered by certain features
         enum Action {
             FakeDisconnect,
             Disconnect,
         }
 
+        if self.match_over {
+            ui.vertical_centered(|ui| {
+                Label::new(MenuButton::ui_text("MATCH OVER", MenuButton::ACTIVE_COLOR))
+                    .selectable(false)
+                    .ui(ui);
+            });
+            ui.end_row();
+        }
+
         let mut action = None;
         ui.vertical_centered(|ui| {
-            if ui_button("Disconnect").ui(ui).clicked() {
+            let label = if self.match_over {
+                "Leave"
+            } else {
+                "Disconnect"
+            };
+            if ui_button(label).ui(ui).clicked() {
                 action = Some(Action::Disconnect);
             }
         });
@@ -468,11 +796,19 @@ impl NetplayGui {
         if let Some(action) = action {
             match action {
                 Action::FakeDisconnect => {
-                    log::debug!("Manually resuming connection (faking a lost connection)");
-                    return NetplayState::Resuming(netplay_connected.resume());
+                    // Goes through the same error handling as a real lost connection on the next advance
+                    #[cfg(feature = "debug")]
+                    {
+                        let mut netplay_connected = netplay_connected;
+                        netplay_connected
+                            .state
+                            .netplay_session
+                            .simulate_disconnect();
+                        return NetplayState::Connected(netplay_connected);
+                    }
                 }
                 Action::Disconnect => {
-                    return NetplayState::Disconnected(netplay_connected.disconnect());
+                    return NetplayState::Disconnected(netplay_connected.leave());
                 }
             }
         }
@@ -480,10 +816,15 @@ impl NetplayGui {
     }
 
     pub fn ui(&mut self, ui: &mut Ui, netplay_state_handler: &mut NetplayStateHandler) {
-        let netplay = &mut netplay_state_handler.netplay;
-        *netplay = Some(match netplay.take().unwrap() {
+        let netplay = netplay_state_handler.netplay.take().unwrap();
+        let from = netplay.name();
+        let new_state = match netplay {
             NetplayState::Disconnected(netplay_disconnected) => {
-                self.ui_disconnected(ui, netplay_disconnected)
+                if netplay_state_handler.is_netplay_disabled() {
+                    self.ui_netplay_disabled(ui, netplay_disconnected)
+                } else {
+                    self.ui_disconnected(ui, netplay_disconnected)
+                }
             }
             NetplayState::Connecting(netplay_connecting) => {
                 self.ui_connecting(ui, netplay_connecting)
@@ -511,17 +852,34 @@ impl NetplayGui {
                     NetplayState::Resuming(netplay_resuming)
                 }
             }
-            NetplayState::Failed(netplay_failed) => {
+            NetplayState::Failed(mut netplay_failed) => {
                 ui.label(format!(
                     "Failed to connect: {}",
                     netplay_failed.state.reason
                 ));
+                if let Some(requeue) = &netplay_failed.state.requeue {
+                    ui.label(format!(
+                        "Searching again… ({}/{})",
+                        requeue.attempt,
+                        Settings::current().netplay.auto_requeue_attempts
+                    ));
+                    if ui.button("Stop searching").clicked() {
+                        netplay_failed.stop_requeue();
+                    }
+                }
                 if ui.button("Ok").clicked() || esc_pressed(ui.ctx()) {
                     NetplayState::Disconnected(netplay_failed.disconnect())
                 } else {
                     NetplayState::Failed(netplay_failed)
                 }
             }
+        };
+        netplay_state_handler.transitions.record(from, &new_state);
+        netplay_state_handler.netplay = Some(new_state);
+
+        #[cfg(feature = "debug")]
+        ui.vertical_centered(|ui| {
+            Self::transitions_ui(ui, netplay_state_handler.transitions());
         });
     }
 
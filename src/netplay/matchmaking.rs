@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine};
+
+/// Extra data for a custom matchmaking server, like a skill rating or preferred rules. Opaque to us, it's only passed along.
+struct Matchmaking {
+    payload: Option<Vec<u8>>,
+    reply: Option<serde_yaml::Value>,
+}
+
+static MATCHMAKING: Mutex<Matchmaking> = Mutex::new(Matchmaking {
+    payload: None,
+    reply: None,
+});
+
+/// Sent with every following matchmaking request (including retries, resumes and requeues) until it's changed, `None` sends nothing.
+#[allow(dead_code)] // For embedders with their own matchmaking server
+pub fn set_payload(payload: Option<Vec<u8>>) {
+    MATCHMAKING.lock().unwrap().payload = payload;
+}
+
+/// Adds the payload to a matchmaking url as the `payload` query parameter (base64url without padding), the url is left as is without one.
+pub(super) fn with_payload(url: String) -> String {
+    match &MATCHMAKING.lock().unwrap().payload {
+        Some(payload) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{url}{separator}payload={}", b64.encode(payload))
+        }
+        None => url,
+    }
+}
+
+/// What the TurnOn server sent back in `matchmaking_reply`, if anything. Kept until the next matchmaking request.
+#[allow(dead_code)] // For embedders with their own matchmaking server
+pub fn reply() -> Option<serde_yaml::Value> {
+    MATCHMAKING.lock().unwrap().reply.clone()
+}
+
+pub(super) fn set_reply(reply: Option<serde_yaml::Value>) {
+    if reply.is_some() {
+        log::debug!("Matchmaking reply: {:?}", reply);
+    }
+    MATCHMAKING.lock().unwrap().reply = reply;
+}
@@ -1,30 +1,67 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
 use crate::{
-    emulation::{LocalNesState, NESBuffers, NesStateHandler},
-    input::JoypadState,
-    settings::MAX_PLAYERS,
+    bundle::Bundle,
+    emulation::{LocalNesState, NESBuffers, NesRegion, NesStateHandler},
+    input::{keys::KeyCode, rom_id, JoypadState},
+    settings::{Settings, MAX_PLAYERS},
 };
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
 use self::{
-    connecting_state::{ConnectingState, NetplayServerConfiguration, StartMethod, StartState},
-    netplay_state::{Netplay, NetplayState},
+    connecting_state::{
+        ConnectingState, NetplayServerConfiguration, SessionKind, StartMethod, StartState,
+        TurnOnServers,
+    },
+    netplay_session::{ConfirmedFrameHook, NetplaySessionStats},
+    netplay_state::{GameRom, Netplay, NetplayState},
+    opponents::{RecentOpponent, MAX_RECENT_OPPONENTS},
+    transitions::TransitionLog,
 };
 
+pub mod benchmark;
+mod clock;
+mod confirmed_states;
 mod connecting_state;
+mod diagnose;
 pub mod gui;
+pub mod matchmaking;
 mod netplay_session;
 mod netplay_state;
+pub mod opponents;
+pub mod report;
+mod room_probe;
+mod session_id;
+mod socket;
+mod spectator;
+mod stats_log;
+mod transitions;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum JoypadMapping {
     P1,
     P2,
 }
 
 impl JoypadMapping {
+    /// The controller the local player gets, from the GGRS player handles. The handles come from the peer ids sorted the same way
+    /// by both players, so they always end up on different controllers whoever joined first.
+    /// `None` unless there's exactly one local and one remote handle and they're different.
+    fn negotiate(local_handles: &[usize], remote_handles: &[usize]) -> Option<Self> {
+        match (local_handles, remote_handles) {
+            ([local], [remote]) if local != remote => Some(if local < remote {
+                JoypadMapping::P1
+            } else {
+                JoypadMapping::P2
+            }),
+            _ => None,
+        }
+    }
+
     fn map(
         &self,
         joypad_state: [JoypadState; MAX_PLAYERS],
@@ -49,14 +86,435 @@ impl JoypadMapping {
     }
 }
 
+/// Rollback predicts the inputs of the other player and corrects when they turn out wrong, lockstep waits for them every frame.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum NetplayMode {
+    #[default]
+    Rollback,
+    Lockstep,
+}
+
+/// What rollback assumes the other player pressed on frames their input hasn't arrived for yet.
+/// Both sides have to agree, it's only `Neutral` when both players picked it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum PredictionPolicy {
+    /// Keeps pressing whatever they pressed last, right for held buttons like running
+    #[default]
+    RepeatLast,
+    /// No buttons, fewer misfires in games where a mispredicted press does something drastic
+    Neutral,
+}
+
+/// Which player of a random match is the host. Both peers decide it from the player slots, which are in peer id order on both sides.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum RandomMatchHost {
+    #[default]
+    LowestPeerId,
+    HighestPeerId,
+}
+
+impl RandomMatchHost {
+    pub fn host_slot(&self) -> usize {
+        match self {
+            RandomMatchHost::LowestPeerId => 0,
+            RandomMatchHost::HighestPeerId => MAX_PLAYERS - 1,
+        }
+    }
+}
+
+/// Ends a session whose connection stays worse than this for `secs` in a row, it's not playable anyway.
+/// It's part of the bundle so both players judge with the same limits, each from their own stats.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct PoorConnectionLimit {
+    /// Round trip time in milliseconds
+    #[serde(default)]
+    pub max_ping_ms: Option<u16>,
+    /// How many frames the last rollback went back
+    #[serde(default)]
+    pub max_rollback_frames: Option<u8>,
+    /// How long it has to stay over a limit, anything shorter is a spike
+    pub secs: u16,
+}
+
+impl PoorConnectionLimit {
+    pub fn is_exceeded(&self, stats: &NetplaySessionStats) -> bool {
+        self.max_ping_ms
+            .is_some_and(|max_ping_ms| stats.ping > max_ping_ms as u128)
+            || self.max_rollback_frames.is_some_and(|max_rollback_frames| {
+                stats.rollback_depth > max_rollback_frames as i32
+            })
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct NetplayBuildConfiguration {
     pub netplay_id: Option<String>,
     pub server: NetplayServerConfiguration,
+    /// The mode to play this game in unless the player picked another one
+    #[serde(default)]
+    pub default_mode: NetplayMode,
+    /// Input delay in frames to use instead of the one from the server configuration, unless the player picked another one
+    #[serde(default)]
+    pub default_input_delay: Option<usize>,
+    /// How the ids of games on the matchmaking server are built
+    #[serde(default)]
+    pub session_ids: session_id::SessionIdScheme,
+    /// Who gets to be the host when neither player created the game
+    #[serde(default)]
+    pub random_match_host: RandomMatchHost,
+    /// The `rom_id`s of ROMs that can't be played online, like games that desync or only have one player. They still play offline.
+    #[serde(default)]
+    pub disabled_roms: Vec<String>,
+    /// `None` never ends a session for being too slow
+    #[serde(default)]
+    pub poor_connection_limit: Option<PoorConnectionLimit>,
+    /// How long every connection attempt waits for the other player, `None` waits for as long as it takes
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u16>,
+    /// Compare the states of both players every second and end the session when they differ instead of playing on out of sync.
+    /// Only for ROMs that clear the RAM before using it, what's in it at power-on isn't the same for both players.
+    #[serde(default)]
+    pub desync_check: bool,
+}
+
+impl NetplayBuildConfiguration {
+    pub fn connect_timeout(&self) -> Option<std::time::Duration> {
+        self.connect_timeout_secs
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+    }
+
+    pub fn is_rom_disabled(&self, rom_id: &str) -> bool {
+        self.disabled_roms.iter().any(|disabled| disabled == rom_id)
+    }
+
+    /// The mode and input delay to connect with, the player settings come before the bundle defaults.
+    pub fn mode_and_input_delay(&self, server_input_delay: usize) -> (NetplayMode, usize) {
+        let settings = &Settings::current().netplay;
+        (
+            settings.mode.unwrap_or(self.default_mode),
+            settings
+                .input_delay
+                .or(self.default_input_delay)
+                .unwrap_or(server_input_delay),
+        )
+    }
+}
+
+const DEFAULT_TURN_ON_SERVER: &str = "https://netplay.tech/get-config";
+
+impl NetplayBuildConfiguration {
+    pub fn builder() -> NetplayBuildConfigurationBuilder {
+        NetplayBuildConfigurationBuilder::default()
+    }
+}
+
+impl Default for NetplayBuildConfiguration {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Builds a netplay configuration in code, anything not set gets the same default as when it's left out of the config.
+#[derive(Default)]
+pub struct NetplayBuildConfigurationBuilder {
+    netplay_id: Option<String>,
+    server: Option<NetplayServerConfiguration>,
+    default_mode: NetplayMode,
+    default_input_delay: Option<usize>,
+    session_ids: session_id::SessionIdScheme,
+    random_match_host: RandomMatchHost,
+    disabled_roms: Vec<String>,
+    poor_connection_limit: Option<PoorConnectionLimit>,
+    connect_timeout_secs: Option<u16>,
+    desync_check: bool,
+}
+
+#[allow(dead_code)] // The setters are for configuring netplay in code, the bundle config is deserialized
+impl NetplayBuildConfigurationBuilder {
+    pub fn netplay_id(mut self, netplay_id: impl Into<String>) -> Self {
+        self.netplay_id = Some(netplay_id.into());
+        self
+    }
+
+    pub fn server(mut self, server: NetplayServerConfiguration) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    pub fn turn_on_servers(self, servers: Vec<String>) -> Self {
+        self.server(NetplayServerConfiguration::TurnOn(TurnOnServers::Many(
+            servers,
+        )))
+    }
+
+    pub fn default_mode(mut self, mode: NetplayMode) -> Self {
+        self.default_mode = mode;
+        self
+    }
+
+    pub fn default_input_delay(mut self, input_delay: usize) -> Self {
+        self.default_input_delay = Some(input_delay);
+        self
+    }
+
+    pub fn session_ids(mut self, session_ids: session_id::SessionIdScheme) -> Self {
+        self.session_ids = session_ids;
+        self
+    }
+
+    pub fn random_match_host(mut self, random_match_host: RandomMatchHost) -> Self {
+        self.random_match_host = random_match_host;
+        self
+    }
+
+    pub fn disable_rom(mut self, rom_id: impl Into<String>) -> Self {
+        self.disabled_roms.push(rom_id.into());
+        self
+    }
+
+    pub fn poor_connection_limit(mut self, limit: PoorConnectionLimit) -> Self {
+        self.poor_connection_limit = Some(limit);
+        self
+    }
+
+    pub fn connect_timeout_secs(mut self, secs: u16) -> Self {
+        self.connect_timeout_secs = Some(secs);
+        self
+    }
+
+    pub fn desync_check(mut self, desync_check: bool) -> Self {
+        self.desync_check = desync_check;
+        self
+    }
+
+    pub fn build(self) -> NetplayBuildConfiguration {
+        NetplayBuildConfiguration {
+            netplay_id: self.netplay_id,
+            server: self.server.unwrap_or_else(|| {
+                NetplayServerConfiguration::TurnOn(TurnOnServers::One(
+                    DEFAULT_TURN_ON_SERVER.to_string(),
+                ))
+            }),
+            default_mode: self.default_mode,
+            default_input_delay: self.default_input_delay,
+            session_ids: self.session_ids,
+            random_match_host: self.random_match_host,
+            disabled_roms: self.disabled_roms,
+            poor_connection_limit: self.poor_connection_limit,
+            connect_timeout_secs: self.connect_timeout_secs,
+            desync_check: self.desync_check,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct NetplaySettings {
+    /// Keep playing alone from the last confirmed state when the peer drops instead of trying to resume
+    #[serde(default)]
+    pub go_offline_on_peer_drop: bool,
+    #[serde(default)]
+    pub jitter_buffer: socket::JitterBufferDepth,
+    /// Toggles the connection stats overlay
+    #[serde(default = "NetplaySettings::default_stats_overlay_key")]
+    pub stats_overlay_key: KeyCode,
+    /// Send a throwaway id to the netplay server on every connect instead of the persisted `netplay_id`
+    #[serde(default)]
+    pub anonymous: bool,
+    /// Write the connection stats of every session to a CSV file in the settings directory
+    #[serde(default)]
+    pub stats_log: bool,
+    /// Skip ahead without showing the frames when this many frames behind the other player, instead of having them slow down until we catch up
+    #[serde(default)]
+    pub catch_up_skip_threshold: Option<u16>,
+    /// Overrides the netplay mode of the bundle
+    #[serde(default)]
+    pub mode: Option<NetplayMode>,
+    /// Overrides the input delay (in frames) of the bundle and the server
+    #[serde(default)]
+    pub input_delay: Option<usize>,
+    /// What to predict for the other player, used from the next session on
+    #[serde(default)]
+    pub prediction: PredictionPolicy,
+    /// How many confirmed states a session keeps, capped to what fits in memory
+    #[serde(default = "NetplaySettings::default_confirmed_states")]
+    pub confirmed_states: usize,
+    /// How many of the latest confirmed frames to try resuming from at the same time after a drop
+    #[serde(default = "NetplaySettings::default_resume_attempts")]
+    pub resume_attempts: u8,
+    /// Store the older confirmed states as differences to the newer ones, less memory for some CPU on every confirmed frame
+    #[serde(default)]
+    pub compress_confirmed_states: bool,
+    /// How far behind the players a spectator sees the game, in seconds
+    #[serde(default = "NetplaySettings::default_spectator_delay_secs")]
+    pub spectator_delay_secs: u16,
+    /// How many times to search for another random opponent after a public game fails, 0 turns it off
+    #[serde(default)]
+    pub auto_requeue_attempts: u16,
+    /// The matchmaking queue to find public games in, for example "beginner". Empty is the default queue.
+    #[serde(default)]
+    pub queue_tag: Option<String>,
+    /// How often to tell the other player we're still there, in milliseconds
+    #[serde(default = "NetplaySettings::default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u16,
+    /// After this many heartbeats in a row are missing the connection is considered lost
+    #[serde(default = "NetplaySettings::default_heartbeat_miss_threshold")]
+    pub heartbeat_miss_threshold: u8,
+    /// Give up reconnecting after this many seconds, `None` keeps trying until cancelled
+    #[serde(default)]
+    pub reconnect_grace_secs: Option<u16>,
+    /// Shows "Reconnecting…" over the frozen game while reconnecting
+    #[serde(default = "NetplaySettings::default_reconnect_overlay")]
+    pub reconnect_overlay: bool,
+    /// After resuming, play the confirmed inputs both players still have to get back to the frame the connection was lost on.
+    /// Only used when both players turn it on.
+    #[serde(default)]
+    pub resume_fast_forward: bool,
+    /// Private rooms joined lately, most recent first. Use `remember_room_name` to add to it.
+    #[serde(default)]
+    recent_room_names: Vec<String>,
+    /// Copies a netplay diagnostics report to the clipboard, see `diagnostics_report`
+    #[serde(default = "NetplaySettings::default_diagnostics_key")]
+    pub diagnostics_key: KeyCode,
+    /// Put the netplay id in the diagnostics report, it's left out by default since the server knows us by it
+    #[serde(default)]
+    pub diagnostics_include_netplay_id: bool,
+    /// What the other player sees us as, see `player_name`
+    #[serde(default)]
+    player_name: Option<String>,
+    /// Who we played with lately, most recent first. Use `remember_opponent` to add to it.
+    #[serde(default)]
+    recent_opponents: Vec<RecentOpponent>,
+}
+
+const MAX_RECENT_ROOM_NAMES: usize = 5;
+
+impl NetplaySettings {
+    fn default_stats_overlay_key() -> KeyCode {
+        KeyCode::F3
+    }
+    fn default_diagnostics_key() -> KeyCode {
+        KeyCode::F4
+    }
+    fn default_confirmed_states() -> usize {
+        8
+    }
+    fn default_resume_attempts() -> u8 {
+        2
+    }
+    fn default_spectator_delay_secs() -> u16 {
+        30
+    }
+    fn default_heartbeat_interval_ms() -> u16 {
+        250
+    }
+    fn default_heartbeat_miss_threshold() -> u8 {
+        8
+    }
+    fn default_reconnect_overlay() -> bool {
+        true
+    }
+
+    pub fn heartbeat_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.heartbeat_interval_ms.max(1) as u64)
+    }
+
+    /// The recently joined room names, most recent first. Anything in the settings file that isn't a valid room name is left out.
+    pub fn recent_room_names(&self) -> Vec<String> {
+        self.recent_room_names
+            .iter()
+            .filter_map(|name| netplay_state::sanitize_room_name(name))
+            .take(MAX_RECENT_ROOM_NAMES)
+            .collect()
+    }
+
+    pub fn last_room_name(&self) -> Option<String> {
+        self.recent_room_names().into_iter().next()
+    }
+
+    /// Moves the room name first in the recent room names, dropping the oldest one if there are too many.
+    pub fn remember_room_name(&mut self, room_name: &str) {
+        if let Some(room_name) = netplay_state::sanitize_room_name(room_name) {
+            let mut recent_room_names = self.recent_room_names();
+            recent_room_names.retain(|name| *name != room_name);
+            recent_room_names.insert(0, room_name);
+            recent_room_names.truncate(MAX_RECENT_ROOM_NAMES);
+            self.recent_room_names = recent_room_names;
+        }
+    }
+
+    /// Our name towards the other player, `None` if there is no valid one set
+    pub fn player_name(&self) -> Option<String> {
+        self.player_name
+            .as_deref()
+            .and_then(opponents::sanitize_player_name)
+    }
+
+    /// Kept as typed, `player_name` cleans it up when it's sent
+    pub fn set_player_name(&mut self, name: &str) {
+        self.player_name = (!name.is_empty()).then(|| name.to_string());
+    }
+
+    pub fn player_name_text(&self) -> &str {
+        self.player_name.as_deref().unwrap_or_default()
+    }
+
+    /// The opponents of the latest sessions, most recent first. Nothing is remembered in anonymous mode.
+    pub fn recent_opponents(&self) -> &[RecentOpponent] {
+        &self.recent_opponents
+    }
+
+    /// Moves the opponent first in the recent opponents, dropping the oldest one if there are too many.
+    pub fn remember_opponent(&mut self, opponent: RecentOpponent) {
+        if self.anonymous {
+            return;
+        }
+        self.recent_opponents
+            .retain(|recent| recent.id != opponent.id);
+        self.recent_opponents.insert(0, opponent);
+        self.recent_opponents.truncate(MAX_RECENT_OPPONENTS);
+    }
+}
+
+impl Default for NetplaySettings {
+    fn default() -> Self {
+        Self {
+            go_offline_on_peer_drop: false,
+            jitter_buffer: Default::default(),
+            stats_overlay_key: Self::default_stats_overlay_key(),
+            anonymous: false,
+            stats_log: false,
+            catch_up_skip_threshold: None,
+            mode: None,
+            input_delay: None,
+            prediction: PredictionPolicy::default(),
+            confirmed_states: Self::default_confirmed_states(),
+            resume_attempts: Self::default_resume_attempts(),
+            compress_confirmed_states: false,
+            spectator_delay_secs: Self::default_spectator_delay_secs(),
+            auto_requeue_attempts: 0,
+            queue_tag: None,
+            heartbeat_interval_ms: Self::default_heartbeat_interval_ms(),
+            heartbeat_miss_threshold: Self::default_heartbeat_miss_threshold(),
+            reconnect_grace_secs: None,
+            reconnect_overlay: Self::default_reconnect_overlay(),
+            resume_fast_forward: false,
+            recent_room_names: Vec::new(),
+            diagnostics_key: Self::default_diagnostics_key(),
+            diagnostics_include_netplay_id: false,
+            player_name: None,
+            recent_opponents: Vec::new(),
+        }
+    }
 }
 
 pub struct NetplayStateHandler {
     netplay: Option<NetplayState>,
+    on_confirmed_frame: Option<ConfirmedFrameHook>,
+    transitions: TransitionLog,
+    // What's played, kept so that disconnecting doesn't go back to the bundled ROM
+    rom: Arc<GameRom>,
+    // The `rom_id` of the ROM that's played offline, see `NetplayBuildConfiguration::disabled_roms`
+    rom_id: String,
 }
 
 #[derive(Clone)]
@@ -91,13 +549,20 @@ impl DerefMut for NetplayNesState {
 
 impl NesStateHandler for NetplayStateHandler {
     fn advance(&mut self, joypad_state: [JoypadState; MAX_PLAYERS], buffers: &mut NESBuffers) {
-        if let Some(new_state) = self
-            .netplay
-            .take()
-            .map(|netplay| netplay.advance(joypad_state, buffers))
-        {
+        if let Some(netplay) = self.netplay.take() {
+            let from = netplay.name();
+            let new_state = netplay.advance(joypad_state, buffers);
+            self.transitions.record(from, &new_state);
             self.netplay = Some(new_state);
         }
+        // Hand the hook to whatever session we're in, it's newly created after every (re)connect
+        if let Some(NetplayState::Connected(netplay)) = &mut self.netplay {
+            netplay
+                .state
+                .netplay_session
+                .on_confirmed_frame
+                .clone_from(&self.on_confirmed_frame);
+        }
     }
 
     fn save_sram(&self) -> Option<&[u8]> {
@@ -108,6 +573,31 @@ impl NesStateHandler for NetplayStateHandler {
         }
     }
 
+    fn confirmed_frame(&self) -> Option<u32> {
+        match &self.netplay {
+            // The session frame and not the emulator's, so both players get the same number
+            Some(NetplayState::Connected(s)) => {
+                Some(s.state.netplay_session.confirmed_frame().max(0) as u32)
+            }
+            Some(NetplayState::Disconnected(s)) => s.state.confirmed_frame(),
+            _ => None,
+        }
+    }
+
+    fn peek_confirmed(&self, address: u16) -> Option<u8> {
+        match &self.netplay {
+            // Predicted frames might be rolled back, only what both players agree on counts
+            Some(NetplayState::Connected(s)) => Some(
+                s.state
+                    .netplay_session
+                    .latest_confirmed_game_state()
+                    .peek(address),
+            ),
+            Some(NetplayState::Disconnected(s)) => s.state.peek_confirmed(address),
+            _ => None,
+        }
+    }
+
     fn frame(&self) -> u32 {
         match &self.netplay {
             Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.frame(),
@@ -118,25 +608,183 @@ impl NesStateHandler for NetplayStateHandler {
 
     fn set_speed(&mut self, speed: f32) {
         match &mut self.netplay {
-            Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.set_speed(speed),
+            // The session paces itself to stay in sync with the other player
+            Some(NetplayState::Connected(_)) => {
+                log::debug!("Ignoring speed change to {speed}x while connected")
+            }
             Some(NetplayState::Disconnected(s)) => s.state.set_speed(speed),
             _ => {}
         }
     }
 
+    fn speed(&self) -> f32 {
+        match &self.netplay {
+            Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.speed(),
+            Some(NetplayState::Disconnected(s)) => s.state.speed(),
+            _ => 1.0,
+        }
+    }
+
     fn reset(&mut self, hard: bool) {
         match &mut self.netplay {
-            Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.reset(hard),
+            // Only resetting our side would desync the session
+            Some(NetplayState::Connected(_)) => {
+                log::info!("Ignoring reset while connected to another player")
+            }
             Some(NetplayState::Disconnected(s)) => s.state.reset(hard),
             _ => {}
         }
     }
+
+    fn load_rom(&mut self, rom: &[u8]) -> Result<()> {
+        crate::emulation::validate_rom(rom)?;
+        let region = Settings::current_mut().get_nes_region().clone();
+        let nes_state = LocalNesState::start_rom(rom, false, &region)?;
+        self.rom = Arc::new(GameRom::loaded(rom, region));
+        self.rom_id = rom_id(rom);
+
+        // The session is tied to the ROM, so anything in progress has to go first
+        let from = self.netplay.as_ref().map(|netplay| netplay.name());
+        match self.netplay.take() {
+            Some(NetplayState::Disconnected(_)) | None => {}
+            Some(NetplayState::Connecting(mut netplay)) => {
+                log::info!("Cancelling connection to load a new ROM");
+                netplay.state.cancel();
+            }
+            Some(NetplayState::Resuming(mut netplay)) => {
+                log::info!("Cancelling resume to load a new ROM");
+                netplay.state.cancel();
+            }
+            Some(NetplayState::Connected(netplay)) => {
+                log::info!("Leaving the session to load a new ROM");
+                // Like `Netplay::<Connected>::leave`, so the other player doesn't wait for us to come back
+                netplay.state.netplay_session.leave();
+            }
+            Some(NetplayState::Failed(_)) => {}
+        }
+        let new_state = NetplayState::Disconnected(Netplay::from(nes_state, self.rom.clone()));
+        if let Some(from) = from {
+            self.transitions.record(from, &new_state);
+        }
+        self.netplay = Some(new_state);
+        Ok(())
+    }
+
+    fn is_online(&self) -> bool {
+        matches!(self.netplay, Some(NetplayState::Connected(_)))
+    }
+
+    fn can_pause(&self) -> bool {
+        // Connecting and resuming are driven by `advance`, so only a disconnected game can be paused
+        matches!(self.netplay, Some(NetplayState::Disconnected(_)))
+    }
+
+    fn start_from_state(&mut self, region: &NesRegion, state: &[u8]) -> Result<()> {
+        match &mut self.netplay {
+            Some(NetplayState::Disconnected(netplay)) => {
+                netplay.state.start_from_state(region, state)
+            }
+            _ => Err(anyhow!("Can't restart the game during netplay")),
+        }
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        // What happens in a session depends on the other player too
+        match &self.netplay {
+            Some(NetplayState::Disconnected(s)) => s.state.snapshot(),
+            _ => None,
+        }
+    }
+
+    fn go_offline(&mut self) {
+        if let Some(netplay) = self.netplay.take() {
+            let from = netplay.name();
+            let new_state = NetplayState::Disconnected(match netplay {
+                NetplayState::Disconnected(netplay) => netplay,
+                NetplayState::Connecting(netplay) => netplay.cancel(),
+                NetplayState::Connected(netplay) => netplay.leave(),
+                NetplayState::Resuming(netplay) => netplay.cancel(),
+                NetplayState::Failed(netplay) => netplay.disconnect(),
+            });
+            self.transitions.record(from, &new_state);
+            self.netplay = Some(new_state);
+        }
+    }
 }
 
 impl NetplayStateHandler {
     pub fn new() -> Result<Self> {
+        let rom = Arc::new(GameRom::bundled());
         Ok(NetplayStateHandler {
-            netplay: Some(NetplayState::Disconnected(Netplay::new()?)),
+            netplay: Some(NetplayState::Disconnected(Netplay::new(rom.clone())?)),
+            on_confirmed_frame: None,
+            transitions: TransitionLog::new(),
+            rom_id: rom_id(&rom.rom),
+            rom,
         })
     }
+
+    /// The ROM that's played, the bundled one until another is loaded
+    pub fn rom(&self) -> &GameRom {
+        &self.rom
+    }
+
+    /// True when the bundle doesn't let the loaded ROM be played online
+    pub fn is_netplay_disabled(&self) -> bool {
+        Bundle::current()
+            .config
+            .netplay
+            .is_rom_disabled(&self.rom_id)
+    }
+
+    /// Every change between the netplay states since the start, see [`TransitionLog::timeline`] for a bug report friendly version
+    pub fn transitions(&self) -> &TransitionLog {
+        &self.transitions
+    }
+
+    /// Whether we're the host of the session, `None` while not connected. Both players always agree on it, see `Connected::is_host`.
+    #[allow(dead_code)] // For embedders with host-only features
+    pub fn is_host(&self) -> Option<bool> {
+        match &self.netplay {
+            Some(NetplayState::Connected(netplay)) => Some(netplay.state.is_host()),
+            _ => None,
+        }
+    }
+
+    /// Sets a hook that is called for every newly confirmed frame while connected.
+    #[allow(dead_code)] // For integrations that sync progress elsewhere
+    pub fn set_on_confirmed_frame(&mut self, hook: Option<ConfirmedFrameHook>) {
+        self.on_confirmed_frame = hook;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn players_get_different_controllers() {
+        assert_eq!(
+            JoypadMapping::negotiate(&[0], &[1]),
+            Some(JoypadMapping::P1)
+        );
+        assert_eq!(
+            JoypadMapping::negotiate(&[1], &[0]),
+            Some(JoypadMapping::P2)
+        );
+    }
+
+    #[test]
+    fn equal_handles_have_no_mapping() {
+        assert_eq!(JoypadMapping::negotiate(&[0], &[0]), None);
+        assert_eq!(JoypadMapping::negotiate(&[1], &[1]), None);
+    }
+
+    #[test]
+    fn wrong_player_count_has_no_mapping() {
+        assert_eq!(JoypadMapping::negotiate(&[], &[1]), None);
+        assert_eq!(JoypadMapping::negotiate(&[0], &[]), None);
+        assert_eq!(JoypadMapping::negotiate(&[0, 1], &[2]), None);
+        assert_eq!(JoypadMapping::negotiate(&[0], &[1, 2]), None);
+    }
 }
@@ -1,13 +1,24 @@
-use ggrs::{Config, GgrsRequest, P2PSession};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+
+use ggrs::{Config, GgrsRequest, InputStatus, P2PSession};
 use matchbox_socket::PeerId;
 
 use crate::{
     emulation::{NESBuffers, NesStateHandler},
     input::JoypadState,
-    settings::MAX_PLAYERS,
+    settings::{Settings, MAX_PLAYERS},
 };
 
-use super::{connecting_state::StartMethod, JoypadMapping, NetplayNesState};
+use super::{
+    confirmed_states::ConfirmedStates,
+    connecting_state::StartMethod,
+    opponents::{PeerProfile, PlayerProfile},
+    socket::{Liveness, RejectedPackets},
+    JoypadMapping, NetplayNesState, PredictionPolicy,
+};
 
 #[derive(Debug)]
 pub struct GGRSConfig;
@@ -17,31 +28,257 @@ impl Config for GGRSConfig {
     type Address = PeerId;
 }
 
+/// A game state that has been committed as confirmed, handed to the [`ConfirmedFrameHook`].
+pub struct ConfirmedFrame {
+    pub frame: i32,
+    state: Arc<NetplayNesState>,
+}
+
+impl ConfirmedFrame {
+    /// The md5 of the saved state. Only computed when asked for since it serializes the whole state.
+    #[allow(dead_code)] // Only used by hooks that want it
+    pub fn state_hash(&self) -> anyhow::Result<String> {
+        Ok(format!("{:x}", md5::compute(self.state.save_state()?)))
+    }
+}
+
+/// Called on the emulation thread every time a new confirmed state is committed, never for predicted frames.
+pub type ConfirmedFrameHook = Arc<dyn Fn(&ConfirmedFrame) + Send + Sync>;
+
 pub struct NetplaySession {
     pub p2p_session: P2PSession<GGRSConfig>,
     pub game_state: NetplayNesState,
     pub last_handled_frame: i32,
-    pub last_confirmed_game_states: [NetplayNesState; 2],
+    pub confirmed_states: ConfirmedStates,
+    // The inputs every frame was last advanced with, in NES port order, back to the oldest confirmed state
+    input_history: VecDeque<(i32, [JoypadState; MAX_PLAYERS])>,
+    pub last_rollback_depth: i32,
+    pub on_confirmed_frame: Option<ConfirmedFrameHook>,
+    pub remote_peer: Option<PeerId>,
+    /// When this many frames behind the peer, simulate the missing frames at once without showing them
+    pub catch_up_skip_threshold: Option<u16>,
+    rejected_packets: RejectedPackets,
+    liveness: Liveness,
+    peer_profile: PeerProfile,
+    /// Agreed on in the handshake, both sides have to predict the same
+    pub prediction: PredictionPolicy,
+    // Both sides have `Features::DESYNC_CHECK`, so the states get checksums for GGRS to compare
+    desync_check: bool,
+    desynced_at: Option<i32>,
+    /// Missing this many heartbeats in a row means the connection is gone even if GGRS hasn't noticed yet
+    heartbeat_miss_threshold: u32,
+    #[cfg(feature = "debug")]
+    simulated_disconnect: bool,
+}
+
+/// At least the two latest confirmed states are kept, resuming needs them
+const MIN_CONFIRMED_STATES: usize = 2;
+/// What the confirmed states may use in total, a large buffer setting gets capped to fit
+const CONFIRMED_STATES_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+// Keeps a single catch-up from stalling the emulation for too long
+const MAX_SKIPPED_FRAMES: i32 = 120;
+
+/// How many frames apart the states are compared with `Features::DESYNC_CHECK`, about a second
+pub const DESYNC_CHECK_INTERVAL: u32 = 60;
+
+pub struct NetplaySessionStats {
+    pub ping: u128,
+    pub frames_ahead: i32,
+    pub local_frames_behind: i32,
+    pub remote_frames_behind: i32,
+    pub rollback_depth: i32,
 }
 
 impl NetplaySession {
-    pub fn new(start_method: StartMethod, p2p_session: P2PSession<GGRSConfig>) -> Self {
-        let mut game_state = match &start_method {
-            StartMethod::Start(start_state, ..)
-            | StartMethod::Resume(start_state)
-            | StartMethod::MatchWithRandom(start_state) => start_state.clone().game_state,
-        };
+    pub fn new(
+        start_method: StartMethod,
+        p2p_session: P2PSession<GGRSConfig>,
+        rejected_packets: RejectedPackets,
+        liveness: Liveness,
+        peer_profile: PeerProfile,
+        prediction: PredictionPolicy,
+        desync_check: bool,
+        remote_peer: Option<PeerId>,
+    ) -> Self {
+        let start_state = start_method.start_state();
+        let mut game_state = start_state.game_state.as_ref().clone();
+        // Both play the same confirmed inputs, so both end up on the same frame
+        for joypads in start_state.confirmed_inputs.iter() {
+            game_state.advance(
+                *joypads,
+                &mut NESBuffers {
+                    audio: None,
+                    video: None,
+                },
+            );
+        }
         //Start counting from 0 to be in sync with ggrs frame counter.
         game_state.frame = 0;
+        let confirmed_game_state = Arc::new(game_state.clone());
+        let confirmed_states = {
+            let netplay_settings = &Settings::current().netplay;
+            ConfirmedStates::new(
+                confirmed_game_state,
+                Self::bounded_confirmed_states(&game_state, netplay_settings.confirmed_states),
+                netplay_settings.compress_confirmed_states,
+            )
+        };
 
         Self {
             p2p_session,
-            game_state: game_state.clone(),
-            last_confirmed_game_states: [game_state.clone(), game_state],
+            game_state,
+            confirmed_states,
+            input_history: VecDeque::new(),
             last_handled_frame: -1,
+            last_rollback_depth: 0,
+            on_confirmed_frame: None,
+            remote_peer,
+            catch_up_skip_threshold: Settings::current().netplay.catch_up_skip_threshold,
+            rejected_packets,
+            liveness,
+            peer_profile,
+            prediction,
+            desync_check,
+            desynced_at: None,
+            heartbeat_miss_threshold: Settings::current().netplay.heartbeat_miss_threshold as u32,
+            #[cfg(feature = "debug")]
+            simulated_disconnect: false,
         }
     }
 
+    // The size of a saved state is a good enough estimate of what a state takes in memory
+    fn bounded_confirmed_states(game_state: &NetplayNesState, wanted: usize) -> usize {
+        let max = match game_state.save_state() {
+            Ok(state) => {
+                (CONFIRMED_STATES_MEMORY_BUDGET / state.len().max(1)).max(MIN_CONFIRMED_STATES)
+            }
+            Err(e) => {
+                log::warn!("Could not measure the size of a state, keeping the minimum amount of confirmed states: {:?}", e);
+                MIN_CONFIRMED_STATES
+            }
+        };
+        let bounded = wanted.clamp(MIN_CONFIRMED_STATES, max);
+        if bounded != wanted {
+            log::info!("Keeping {bounded} confirmed states instead of {wanted}");
+        }
+        bounded
+    }
+
+    /// Changes how many confirmed states are kept, within the same bounds as the setting. The oldest states are dropped if there are too many.
+    #[allow(dead_code)] // For features that want a deeper history during a session
+    pub fn set_confirmed_states_capacity(&mut self, capacity: usize) {
+        self.confirmed_states
+            .set_capacity(Self::bounded_confirmed_states(&self.game_state, capacity));
+    }
+
+    /// The session frame being presented, it might still be rolled back
+    pub fn current_frame(&self) -> i32 {
+        self.game_state.frame
+    }
+
+    /// The newest frame both players agree on, the same on both sides
+    pub fn confirmed_frame(&self) -> i32 {
+        self.latest_confirmed_game_state().frame
+    }
+
+    /// The newest state both players agreed on
+    pub fn latest_confirmed_game_state(&self) -> &Arc<NetplayNesState> {
+        self.confirmed_states.latest()
+    }
+
+    /// The inputs of every confirmed frame from `frame` on, for fast forwarding a resume from the state of that frame.
+    /// Empty when they're not kept that far back.
+    pub fn confirmed_inputs_from(&self, frame: i32) -> Vec<[JoypadState; MAX_PLAYERS]> {
+        let confirmed = self.p2p_session.confirmed_frame();
+        let Some(start) = self.input_history.iter().position(|(f, _)| *f == frame) else {
+            return Vec::new();
+        };
+        self.input_history
+            .iter()
+            .skip(start)
+            .take_while(|(f, _)| *f <= confirmed)
+            .map(|(_, joypads)| *joypads)
+            .collect()
+    }
+
+    /// Makes the next `advance` fail as if the peer was lost, to exercise the same error handling as a real drop.
+    #[cfg(feature = "debug")]
+    pub fn simulate_disconnect(&mut self) {
+        log::debug!("Simulating a lost connection");
+        self.simulated_disconnect = true;
+    }
+
+    /// True when the peer has sent so much garbage that it can't be trusted to stay in sync.
+    pub fn is_peer_misbehaving(&self) -> bool {
+        self.rejected_packets.over_limit()
+    }
+
+    /// True when the peer kept sending far more messages than needed, see `RejectedPackets::flooding`.
+    pub fn is_peer_flooding(&self) -> bool {
+        self.rejected_packets.flooding()
+    }
+
+    /// The first frame the state of the peer differed from ours at, only checked with `Features::DESYNC_CHECK`
+    pub fn desynced_at(&self) -> Option<i32> {
+        self.desynced_at
+    }
+
+    /// The peer said it left, as opposed to just going quiet
+    pub fn has_peer_left(&self) -> bool {
+        self.liveness.peer_left()
+    }
+
+    /// Who the peer says they are, `None` until their profile arrives or if they don't send one (anonymous or older peers)
+    pub fn peer_profile(&self) -> Option<PlayerProfile> {
+        self.peer_profile.get()
+    }
+
+    /// Lets the peer know we're leaving on purpose once the session is dropped
+    pub fn leave(&self) {
+        self.liveness.leave();
+    }
+
+    /// Stats for the connection to the remote player, if GGRS has any yet.
+    pub fn remote_stats(&self) -> Option<NetplaySessionStats> {
+        let local_player_handles = self.p2p_session.local_player_handles();
+        (0..MAX_PLAYERS)
+            .filter(|handle| !local_player_handles.contains(handle))
+            .find_map(|handle| self.p2p_session.network_stats(handle).ok())
+            .map(|stats| NetplaySessionStats {
+                ping: stats.ping,
+                frames_ahead: self.p2p_session.frames_ahead(),
+                local_frames_behind: stats.local_frames_behind,
+                remote_frames_behind: stats.remote_frames_behind,
+                rollback_depth: self.last_rollback_depth,
+            })
+    }
+
+    /// The round trip time to each remote player by player slot (0 being P1), `None` until GGRS has measured it.
+    pub fn peer_pings(&self) -> BTreeMap<usize, Option<u128>> {
+        let local_player_handles = self.p2p_session.local_player_handles();
+        (0..MAX_PLAYERS)
+            .filter(|handle| !local_player_handles.contains(handle))
+            .map(|handle| {
+                (
+                    handle,
+                    self.p2p_session
+                        .network_stats(handle)
+                        .ok()
+                        .map(|stats| stats.ping),
+                )
+            })
+            .collect()
+    }
+
+    /// See [`JoypadMapping::negotiate`]
+    pub fn negotiate_joypad_mapping(&self) -> Option<JoypadMapping> {
+        JoypadMapping::negotiate(
+            &self.p2p_session.local_player_handles(),
+            &self.p2p_session.remote_player_handles(),
+        )
+    }
+
     pub fn get_local_player_idx(&self) -> usize {
         //There should be only one.
         *self
@@ -60,7 +297,11 @@ impl NetplaySession {
         #[cfg(feature = "debug")]
         puffin::profile_function!();
 
-        let local_player_idx = self.get_local_player_idx();
+        #[cfg(feature = "debug")]
+        if self.simulated_disconnect {
+            return Err(anyhow::anyhow!("Lost peer (simulated)"));
+        }
+
         let sess = &mut self.p2p_session;
 
         {
@@ -69,12 +310,75 @@ impl NetplaySession {
             sess.poll_remote_clients();
         }
 
+        let missed_heartbeats = self.liveness.missed_heartbeats();
+        if missed_heartbeats >= self.heartbeat_miss_threshold {
+            return Err(anyhow::anyhow!(
+                "Lost peer (missed {missed_heartbeats} heartbeats)"
+            ));
+        }
+
         for event in sess.events() {
-            if let ggrs::GgrsEvent::Disconnected { addr } = event {
-                return Err(anyhow::anyhow!("Lost peer {:?}", addr));
+            match event {
+                ggrs::GgrsEvent::Disconnected { addr } => {
+                    return Err(anyhow::anyhow!("Lost peer {:?}", addr));
+                }
+                ggrs::GgrsEvent::DesyncDetected {
+                    frame,
+                    local_checksum,
+                    remote_checksum,
+                    ..
+                } => {
+                    log::error!("Desync at frame {frame}, our checksum is {local_checksum:x} and the peer's {remote_checksum:x}");
+                    self.desynced_at.get_or_insert(frame);
+                }
+                _ => {}
             }
         }
 
+        self.advance_frame(joypad_state, joypad_mapping, buffers)?;
+
+        let sess = &mut self.p2p_session;
+        let frames_behind = -sess.frames_ahead();
+        if matches!(self.catch_up_skip_threshold, Some(threshold) if frames_behind > threshold as i32)
+        {
+            // Rather a jump than having the peer slow down for a long time while we catch up
+            log::info!("{frames_behind} frames behind, skipping ahead");
+            for _ in 0..frames_behind.min(MAX_SKIPPED_FRAMES) {
+                self.advance_frame(
+                    joypad_state,
+                    joypad_mapping,
+                    &mut NESBuffers {
+                        audio: None,
+                        video: None,
+                    },
+                )?;
+            }
+        }
+
+        let sess = &mut self.p2p_session;
+        if sess.frames_ahead() > 0 {
+            log::debug!(
+                "Frames ahead: {:?}, slowing down emulation",
+                sess.frames_ahead()
+            );
+            //https://www.desmos.com/calculator/zbntsowijd
+            let speed = 0.8_f32.max(1.0 - 0.1 * (0.2 * sess.frames_ahead() as f32).powf(2.0));
+            self.game_state.set_speed(speed);
+        } else {
+            self.game_state.set_speed(1.0)
+        }
+        Ok(())
+    }
+
+    fn advance_frame(
+        &mut self,
+        joypad_state: [JoypadState; MAX_PLAYERS],
+        joypad_mapping: &JoypadMapping,
+        buffers: &mut NESBuffers,
+    ) -> anyhow::Result<()> {
+        let local_player_idx = self.get_local_player_idx();
+        let sess = &mut self.p2p_session;
+
         for handle in sess.local_player_handles() {
             sess.add_local_input(handle, *joypad_state[0])?;
         }
@@ -87,11 +391,25 @@ impl NetplaySession {
                     match request {
                         GgrsRequest::LoadGameState { cell, frame } => {
                             log::debug!("Loading (frame {:?})", frame);
+                            self.last_rollback_depth = self.game_state.frame - frame;
                             self.game_state = cell.load().expect("ggrs state to load");
                         }
                         GgrsRequest::SaveGameState { cell, frame } => {
                             assert_eq!(self.game_state.frame, frame);
-                            cell.save(frame, Some(self.game_state.clone()), None);
+                            // GGRS only compares the checksums of every `DESYNC_CHECK_INTERVAL`th frame
+                            let checksum =
+                                if self.desync_check && frame % DESYNC_CHECK_INTERVAL as i32 == 0 {
+                                    self.game_state
+                                        .save_state()
+                                        .inspect_err(|e| {
+                                            log::warn!("Could not checksum frame {frame}: {:?}", e)
+                                        })
+                                        .ok()
+                                        .map(|state| u128::from_le_bytes(md5::compute(state).0))
+                                } else {
+                                    None
+                                };
+                            cell.save(frame, Some(self.game_state.clone()), checksum);
                         }
                         GgrsRequest::AdvanceFrame { inputs } => {
                             let is_replay = self.game_state.frame <= self.last_handled_frame;
@@ -99,22 +417,52 @@ impl NetplaySession {
                                 audio: None,
                                 video: None,
                             };
-                            self.game_state.advance(
-                                joypad_mapping.map(
-                                    [JoypadState(inputs[0].0), JoypadState(inputs[1].0)],
-                                    local_player_idx,
-                                ),
-                                if is_replay { no_buffers } else { buffers },
-                            );
+                            // Rolled back and replayed with the real input once it arrives
+                            let neutral = self.prediction == PredictionPolicy::Neutral;
+                            let input = |(input, status): (u8, InputStatus)| {
+                                JoypadState(match status {
+                                    InputStatus::Predicted if neutral => 0,
+                                    _ => input,
+                                })
+                            };
+                            let joypads = joypad_mapping
+                                .map([input(inputs[0]), input(inputs[1])], local_player_idx);
+                            // A replay overwrites what was predicted for this frame and the ones after it
+                            while self
+                                .input_history
+                                .back()
+                                .is_some_and(|(frame, _)| *frame >= self.game_state.frame)
+                            {
+                                self.input_history.pop_back();
+                            }
+                            self.input_history
+                                .push_back((self.game_state.frame, joypads));
+                            self.game_state
+                                .advance(joypads, if is_replay { no_buffers } else { buffers });
 
                             if !is_replay {
                                 //This is not a replay
                                 self.last_handled_frame = self.game_state.frame;
-                                if self.game_state.frame % (sess.max_prediction() * 2) as i32 == 0 {
-                                    self.last_confirmed_game_states = [
-                                        self.last_confirmed_game_states[1].clone(),
-                                        self.game_state.clone(),
-                                    ];
+                                // The prediction window is 0 in lockstep
+                                if self.game_state.frame % (sess.max_prediction().max(1) * 2) as i32
+                                    == 0
+                                {
+                                    let confirmed_game_state = Arc::new(self.game_state.clone());
+                                    self.confirmed_states.push(confirmed_game_state.clone());
+                                    let oldest = self.confirmed_states.frames()[0];
+                                    while self
+                                        .input_history
+                                        .front()
+                                        .is_some_and(|(frame, _)| *frame < oldest)
+                                    {
+                                        self.input_history.pop_front();
+                                    }
+                                    if let Some(on_confirmed_frame) = &self.on_confirmed_frame {
+                                        on_confirmed_frame(&ConfirmedFrame {
+                                            frame: confirmed_game_state.frame,
+                                            state: confirmed_game_state,
+                                        });
+                                    }
                                 }
                             }
 
@@ -127,18 +475,6 @@ impl NetplaySession {
                 log::warn!("Frame {} skipped: {:?}", self.game_state.frame, e)
             }
         }
-
-        if sess.frames_ahead() > 0 {
-            log::debug!(
-                "Frames ahead: {:?}, slowing down emulation",
-                sess.frames_ahead()
-            );
-            //https://www.desmos.com/calculator/zbntsowijd
-            let speed = 0.8_f32.max(1.0 - 0.1 * (0.2 * sess.frames_ahead() as f32).powf(2.0));
-            self.game_state.set_speed(speed);
-        } else {
-            self.game_state.set_speed(1.0)
-        }
         Ok(())
     }
 }
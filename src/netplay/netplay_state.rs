@@ -1,18 +1,25 @@
-use std::time::Instant;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use uuid::Uuid;
 
 use crate::{
     bundle::Bundle,
-    emulation::{LocalNesState, NESBuffers, NesStateHandler},
+    emulation::{LocalNesState, NESBuffers, NesRegion, NesStateHandler},
     input::JoypadState,
     settings::{Settings, MAX_PLAYERS},
 };
 
 use super::{
-    connecting_state::JoinOrHost, netplay_session::NetplaySession, ConnectingState, JoypadMapping,
-    StartMethod, StartState,
+    clock::{Clock, NetplayClock},
+    connecting_state::JoinOrHost,
+    netplay_session::NetplaySession,
+    opponents::RecentOpponent,
+    stats_log::StatsLog,
+    ConnectingState, SessionKind, StartMethod, StartState,
 };
 
 pub enum NetplayState {
@@ -23,11 +30,155 @@ pub enum NetplayState {
     Failed(Netplay<Failed>),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetplayError {
+    RoomFull,
+    /// The peer kept sending packets that aren't valid GGRS messages
+    ProtocolError,
+    /// The peer runs another netplay ROM, the session would desync
+    RomMismatch,
+    /// The peer ended the session on purpose, there's nobody to resume with
+    PeerLeft,
+    /// Someone waits for a private game with the same room name but another game, see `RoomProbe`
+    OtherGameInRoom,
+    /// Reconnecting took longer than `reconnect_grace_secs`
+    ReconnectTimedOut,
+    /// The peer kept sending more messages than anyone needs, see `SideChannelLimiter`
+    PeerFlooding,
+    /// The ping or rollbacks stayed over the `poor_connection_limit` of the bundle
+    ConnectionTooPoor,
+    /// Nobody showed up within `connect_timeout_secs` (in seconds)
+    ConnectTimedOut(u16),
+    /// The peer runs a protocol version (the one given) we can't play with, see `OLDEST_COMPATIBLE_VERSION`
+    ProtocolMismatch(u16),
+    /// The state of the peer differed from ours at the given frame, see `NetplayBuildConfiguration::desync_check`
+    DesyncDetected(i32),
+    /// The TurnOn or matchmaking server couldn't be used, with what went wrong
+    ServerError(String),
+}
+
+impl NetplayError {
+    /// Another try might work out, unlike when the peers can't play together at all
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NetplayError::RoomFull
+            | NetplayError::PeerLeft
+            | NetplayError::ConnectionTooPoor
+            | NetplayError::ConnectTimedOut(_)
+            | NetplayError::ServerError(_) => true,
+            NetplayError::ProtocolError
+            | NetplayError::RomMismatch
+            | NetplayError::OtherGameInRoom
+            | NetplayError::ReconnectTimedOut
+            | NetplayError::PeerFlooding
+            | NetplayError::ProtocolMismatch(_)
+            | NetplayError::DesyncDetected(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetplayError::RoomFull => write!(f, "Room is full"),
+            NetplayError::ProtocolError => write!(f, "Protocol error"),
+            NetplayError::RomMismatch => {
+                write!(f, "The other player has a different version of the game")
+            }
+            NetplayError::PeerLeft => write!(f, "The other player left"),
+            NetplayError::OtherGameInRoom => {
+                write!(f, "The other player in this room has a different game")
+            }
+            NetplayError::ReconnectTimedOut => write!(f, "Could not reconnect in time"),
+            NetplayError::PeerFlooding => write!(f, "The other player is flooding the connection"),
+            NetplayError::ConnectionTooPoor => write!(f, "The connection was too poor to play"),
+            NetplayError::ConnectTimedOut(secs) => {
+                write!(f, "Timed out after {secs}s waiting for peers")
+            }
+            NetplayError::ProtocolMismatch(_) => {
+                write!(
+                    f,
+                    "The other player has an older version that can't play with this one"
+                )
+            }
+            NetplayError::DesyncDetected(frame) => {
+                write!(f, "The game went out of sync at frame {frame}")
+            }
+            NetplayError::ServerError(description) => write!(f, "{description}"),
+        }
+    }
+}
+
 pub struct Failed {
-    pub reason: String,
+    pub reason: NetplayError,
+    /// Set when a public game failed and another opponent will be searched for, see `auto_requeue_attempts`
+    pub requeue: Option<Requeue>,
+}
+
+/// Gives the server a moment to drop us from the old room before showing up in the queue again
+const REQUEUE_DELAY: Duration = Duration::from_secs(2);
+
+pub struct Requeue {
+    queue_tag: Option<String>,
+    /// Starting from 1
+    pub attempt: u16,
+    at: Instant,
+    clock: NetplayClock,
+}
+
+impl Requeue {
+    fn is_due(&self) -> bool {
+        self.clock.now() >= self.at
+    }
+}
+
+impl Failed {
+    /// `requeues`, `queue_tag` and `clock` are the ones of the start state, see `StartState::requeues`
+    fn new(
+        reason: NetplayError,
+        requeues: Option<u16>,
+        queue_tag: Option<String>,
+        clock: NetplayClock,
+    ) -> Self {
+        let max_attempts = Settings::current().netplay.auto_requeue_attempts;
+        let requeue = requeues
+            .filter(|requeues| reason.is_transient() && *requeues < max_attempts)
+            .map(|requeues| Requeue {
+                queue_tag,
+                attempt: requeues + 1,
+                at: clock.now() + REQUEUE_DELAY,
+                clock,
+            })
+            .inspect(|requeue| {
+                log::info!(
+                    "Public game failed ({reason}), searching again ({}/{max_attempts})",
+                    requeue.attempt
+                )
+            });
+        Self { reason, requeue }
+    }
 }
 
 impl NetplayState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NetplayState::Disconnected(_) => "Disconnected",
+            NetplayState::Connecting(_) => "Connecting",
+            NetplayState::Connected(_) => "Connected",
+            NetplayState::Resuming(_) => "Resuming",
+            NetplayState::Failed(_) => "Failed",
+        }
+    }
+
+    /// Why the state was entered, for the states that have one
+    pub fn reason(&self) -> Option<String> {
+        match self {
+            NetplayState::Resuming(netplay) => Some(netplay.state.reason.clone()),
+            NetplayState::Failed(netplay) => Some(netplay.state.reason.to_string()),
+            _ => None,
+        }
+    }
+
     pub fn advance(
         self,
         joypad_state: [JoypadState; MAX_PLAYERS],
@@ -64,44 +215,142 @@ impl NetplayState {
 
 pub struct Netplay<T> {
     pub state: T,
+    // What's played offline again after disconnecting, and what a session plays
+    rom: Arc<GameRom>,
 }
 unsafe impl<T> Send for Netplay<T> {}
 
 impl<T> Netplay<T> {
-    fn from(state: T) -> Self {
-        Self { state }
+    pub(super) fn from(state: T, rom: Arc<GameRom>) -> Self {
+        Self { state, rom }
     }
 
+    pub fn rom(&self) -> &Arc<GameRom> {
+        &self.rom
+    }
+
+    /// Drops whatever is going on without telling anyone, a connected session should use [`Netplay::<Connected>::leave`] instead
     pub fn disconnect(self) -> Netplay<LocalNesState> {
         log::debug!("Disconnecting");
-        Netplay::new().expect("disconnect to work")
+        Netplay::new(self.rom).expect("disconnect to work")
     }
 }
 
 pub struct Connected {
     pub netplay_session: NetplaySession,
     session_id: String,
+    /// The matchmaking queue this public game was found in
+    pub queue_tag: Option<String>,
+    // `Some` for public games, see `StartState::requeues`
+    requeues: Option<u16>,
+    role: JoinOrHost,
     pub start_time: Instant,
+    stats_log: Option<StatsLog>,
+    // The peer's profile can arrive a bit after the session started, it's remembered once it's there
+    opponent_remembered: bool,
+    // Since when the connection has been over the `poor_connection_limit`
+    poor_since: Option<Instant>,
+    kind: SessionKind,
+    resumed: bool,
+    clock: NetplayClock,
+}
+
+impl Connected {
+    /// The host is the one who created the private game. For public games it's the player in the first slot, which both peers agree on.
+    pub fn is_host(&self) -> bool {
+        self.role == JoinOrHost::Host
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.requeues.is_some()
+    }
+
+    /// A private game by room name or a public one, the same as when it first connected if it was resumed since
+    pub fn kind(&self) -> &SessionKind {
+        &self.kind
+    }
+
+    /// True when this session was resumed after the connection was lost
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
 }
 
 pub struct Resuming {
-    attempt1: ConnectingState,
-    attempt2: ConnectingState,
+    // One attempt per candidate frame, newest first
+    attempts: Vec<ConnectingState>,
+    /// What went wrong with the connection
+    pub reason: String,
+    pub start_time: Instant,
+    /// When to give up, see `reconnect_grace_secs`
+    pub deadline: Option<Instant>,
+    clock: NetplayClock,
 }
+
 impl Resuming {
-    fn new(netplay: &mut Netplay<Connected>) -> Self {
+    fn new(netplay: &mut Netplay<Connected>, reason: String) -> Self {
         let netplay_session = &netplay.state.netplay_session;
+        if Settings::current().netplay.anonymous {
+            log::info!("Resuming in anonymous mode, the server will see a new id and can't tie this to the previous connection (or its unlock)");
+        }
 
         let session_id = netplay.state.session_id.clone();
+        let rom_hash = netplay.rom.netplay_rom_hash.clone();
+        let role = netplay.state.role.clone();
+        let kind = netplay.state.kind.clone();
+        let queue_tag = netplay.state.queue_tag.clone();
+        let requeues = netplay.state.requeues;
+        let clock = netplay.state.clock.clone();
+        // Bounded by the confirmed states there are, which are few right after connecting
+        let attempts = Settings::current().netplay.resume_attempts.max(1) as usize;
+        let fast_forward = Settings::current().netplay.resume_fast_forward;
+        let game_states = netplay_session.confirmed_states.newest_first(attempts);
+        // Every attempt tells the peer about all of them, so both end up in the room of the same frame
+        let resume_frames = Arc::new(
+            game_states
+                .iter()
+                .map(|game_state| game_state.frame)
+                .collect::<Vec<_>>(),
+        );
         Self {
-            attempt1: ConnectingState::connect(StartMethod::Resume(StartState {
-                game_state: netplay_session.last_confirmed_game_states[1].clone(),
-                session_id: session_id.clone(),
-            })),
-            attempt2: ConnectingState::connect(StartMethod::Resume(StartState {
-                game_state: netplay_session.last_confirmed_game_states[0].clone(),
-                session_id,
-            })),
+            attempts: game_states
+                .into_iter()
+                .map(|game_state| {
+                    let confirmed_inputs = if fast_forward {
+                        netplay_session.confirmed_inputs_from(game_state.frame)
+                    } else {
+                        Vec::new()
+                    };
+                    ConnectingState::connect(StartMethod::Resume(
+                        StartState {
+                            game_state,
+                            session_id: session_id.clone(),
+                            rom_hash: rom_hash.clone(),
+                            queue_tag: queue_tag.clone(),
+                            requeues,
+                            confirmed_inputs: Arc::new(confirmed_inputs),
+                            connect_deadline: None,
+                            resume_frames: resume_frames.clone(),
+                            clock: clock.clone(),
+                        },
+                        role.clone(),
+                        kind.clone(),
+                    ))
+                })
+                .collect(),
+            reason,
+            start_time: clock.now(),
+            deadline: Settings::current()
+                .netplay
+                .reconnect_grace_secs
+                .map(|secs| clock.now() + Duration::from_secs(secs as u64)),
+            clock,
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        for attempt in &mut self.attempts {
+            attempt.cancel();
         }
     }
 }
@@ -112,16 +361,81 @@ pub fn get_netplay_id() -> String {
         .to_string()
 }
 
+/// The id to identify us with towards the netplay server, a fresh one for every connect in anonymous mode.
+pub fn get_server_netplay_id() -> String {
+    if Settings::current().netplay.anonymous {
+        Uuid::new_v4().to_string()
+    } else {
+        get_netplay_id()
+    }
+}
+
 pub const MAX_ROOM_NAME_LEN: u8 = 4;
 
+const MAX_QUEUE_TAG_LEN: usize = 16;
+
+/// Lowercases the tag and checks that it's a valid queue tag (up to `MAX_QUEUE_TAG_LEN` letters, digits and dashes).
+/// An empty tag is the default queue.
+pub fn sanitize_queue_tag(queue_tag: &str) -> Option<String> {
+    let queue_tag = queue_tag.trim().to_lowercase();
+    (!queue_tag.is_empty()
+        && queue_tag.len() <= MAX_QUEUE_TAG_LEN
+        && queue_tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-'))
+    .then_some(queue_tag)
+}
+
+/// Uppercases the name and checks that it's a valid room name (1 to `MAX_ROOM_NAME_LEN` letters and digits).
+pub fn sanitize_room_name(room_name: &str) -> Option<String> {
+    let room_name = room_name.trim().to_uppercase();
+    (!room_name.is_empty()
+        && room_name.len() <= MAX_ROOM_NAME_LEN.into()
+        && room_name.chars().all(|c| c.is_ascii_alphanumeric()))
+    .then_some(room_name)
+}
+
+/// What's played, the bundled ROM until another one is loaded (see `NetplayStateHandler::load_rom`).
+pub struct GameRom {
+    /// Played offline
+    pub rom: Vec<u8>,
+    pub region: NesRegion,
+    /// Played online. The bundle can have a netplay build of its game, a loaded ROM is played online as is.
+    pub netplay_rom: Vec<u8>,
+    /// The md5 of `netplay_rom`, it's part of the room names and both players compare it before starting
+    pub netplay_rom_hash: String,
+}
+
+impl GameRom {
+    pub fn bundled() -> Self {
+        let bundle = Bundle::current();
+        Self::new(
+            bundle.rom.clone(),
+            Settings::current_mut().get_nes_region().clone(),
+            bundle.netplay_rom.clone(),
+        )
+    }
+
+    pub fn loaded(rom: &[u8], region: NesRegion) -> Self {
+        Self::new(rom.to_vec(), region, rom.to_vec())
+    }
+
+    fn new(rom: Vec<u8>, region: NesRegion, netplay_rom: Vec<u8>) -> Self {
+        Self {
+            rom,
+            region,
+            netplay_rom_hash: format!("{:x}", md5::compute(&netplay_rom)),
+            netplay_rom,
+        }
+    }
+}
+
 impl Netplay<LocalNesState> {
-    pub fn new() -> Result<Self> {
+    /// Starts `rom` from power-on, offline
+    pub fn new(rom: Arc<GameRom>) -> Result<Self> {
         Ok(Self {
-            state: LocalNesState::start_rom(
-                &Bundle::current().rom,
-                true,
-                Settings::current_mut().get_nes_region(),
-            )?,
+            state: LocalNesState::start_rom(&rom.rom, true, &rom.region)?,
+            rom,
         })
     }
 
@@ -135,49 +449,93 @@ impl Netplay<LocalNesState> {
         self.join_or_host(&room_name, JoinOrHost::Host)
     }
 
+    /// The name goes through `sanitize_room_name`, so " ab1 " joins room "AB1". Names that aren't valid after that are an error.
     pub fn join_game(self, room_name: &str) -> Result<NetplayState> {
-        self.join_or_host(&room_name.to_uppercase(), JoinOrHost::Join)
+        let room_name = sanitize_room_name(room_name).ok_or_else(|| {
+            anyhow!("Invalid room name {room_name:?}, it has to be 1 to {MAX_ROOM_NAME_LEN} letters or digits")
+        })?;
+        Settings::current_mut()
+            .netplay
+            .remember_room_name(&room_name);
+        self.join_or_host(&room_name, JoinOrHost::Join)
     }
 
     fn join_or_host(self, room_name: &str, join_or_host: JoinOrHost) -> Result<NetplayState> {
-        let netplay_rom = &Bundle::current().netplay_rom;
-        let session_id = format!("{}_{:x}", room_name, md5::compute(netplay_rom));
+        let rom_hash = self.rom.netplay_rom_hash.clone();
+        let session_id = Bundle::current()
+            .config
+            .netplay
+            .session_ids
+            .private_game_id(room_name, &rom_hash);
         let nes_state = LocalNesState::start_rom(
-            netplay_rom,
+            &self.rom.netplay_rom,
             false,
             Bundle::current().config.get_default_region(),
         )?;
         Ok(self.start(StartMethod::Start(
             StartState {
-                game_state: super::NetplayNesState::new(nes_state),
+                game_state: Arc::new(super::NetplayNesState::new(nes_state)),
                 session_id,
+                rom_hash,
+                queue_tag: None,
+                requeues: None,
+                confirmed_inputs: Default::default(),
+                connect_deadline: None,
+                resume_frames: Default::default(),
+                clock: NetplayClock::default(),
             },
             room_name.to_string(),
             join_or_host,
         )))
     }
 
-    pub fn find_game(self) -> Result<NetplayState> {
-        let netplay_rom = &Bundle::current().netplay_rom;
-        let rom_hash = md5::compute(netplay_rom);
+    /// Matches with anyone looking for a game in the same queue, players without a queue tag share the default queue.
+    pub fn find_game(self, queue_tag: Option<&str>) -> Result<NetplayState> {
+        self.find_game_in(
+            queue_tag.and_then(sanitize_queue_tag),
+            0,
+            NetplayClock::default(),
+        )
+    }
 
-        // TODO: When resuming using this session id there might be collisions, but it's unlikely.
-        //       Should be fixed though.
-        let session_id = format!("{:x}", rom_hash);
+    fn find_game_in(
+        self,
+        queue_tag: Option<String>,
+        requeues: u16,
+        clock: NetplayClock,
+    ) -> Result<NetplayState> {
+        let rom_hash = self.rom.netplay_rom_hash.clone();
+
+        // The tag only picks the pool, the ROM hash handshake is the same in every queue
+        let session_id = Bundle::current()
+            .config
+            .netplay
+            .session_ids
+            .public_game_id(queue_tag.as_deref(), &rom_hash);
         let nes_state = LocalNesState::start_rom(
-            netplay_rom,
+            &self.rom.netplay_rom,
             false,
             Bundle::current().config.get_default_region(),
         )?;
         Ok(self.start(StartMethod::MatchWithRandom(StartState {
-            game_state: super::NetplayNesState::new(nes_state),
+            game_state: Arc::new(super::NetplayNesState::new(nes_state)),
             session_id,
+            rom_hash,
+            queue_tag,
+            requeues: Some(requeues),
+            confirmed_inputs: Default::default(),
+            connect_deadline: None,
+            resume_frames: Default::default(),
+            clock,
         })))
     }
 
     pub fn start(self, start_method: StartMethod) -> NetplayState {
         log::debug!("Starting: {:?}", start_method);
-        NetplayState::Connecting(Netplay::from(ConnectingState::connect(start_method)))
+        NetplayState::Connecting(Netplay::from(
+            ConnectingState::connect(start_method),
+            self.rom,
+        ))
     }
 
     fn advance(mut self, joypad_state: [JoypadState; 2], buffers: &mut NESBuffers) -> NetplayState {
@@ -187,31 +545,93 @@ impl Netplay<LocalNesState> {
 }
 
 impl Netplay<ConnectingState> {
-    pub fn cancel(self) -> Netplay<LocalNesState> {
+    pub fn cancel(mut self) -> Netplay<LocalNesState> {
         log::debug!("Connection cancelled by user");
+        self.state.cancel();
         self.disconnect()
     }
 
     fn advance(mut self) -> NetplayState {
         //log::trace!("Advancing Netplay<ConnectingState>");
+        // Failing drops the start method, a requeue needs to know what was searched for
+        let (requeues, queue_tag) = match self.state.start_method() {
+            Some(StartMethod::MatchWithRandom(start_state)) => {
+                (start_state.requeues, start_state.queue_tag.clone())
+            }
+            _ => (None, None),
+        };
+        let clock = self
+            .state
+            .start_method()
+            .map(|start_method| start_method.start_state().clock.clone())
+            .unwrap_or_default();
         self.state = self.state.advance();
         match self.state {
             ConnectingState::Connected(connected) => {
                 log::debug!("Connected! Starting netplay session");
+                let kind = connected.start_method.kind();
+                let resumed = matches!(connected.start_method, StartMethod::Resume(..));
+                let (
+                    StartState {
+                        session_id,
+                        queue_tag,
+                        requeues,
+                        clock,
+                        ..
+                    },
+                    role,
+                ) = match connected.start_method {
+                    StartMethod::Start(start_state, _, role)
+                    | StartMethod::Resume(start_state, role, _) => (start_state, role),
+                    StartMethod::MatchWithRandom(start_state) => {
+                        // Player slots are assigned in peer id order, so both peers end up with the same host
+                        let host_slot = Bundle::current()
+                            .config
+                            .netplay
+                            .random_match_host
+                            .host_slot();
+                        let role = if connected.state.get_local_player_idx() == host_slot {
+                            JoinOrHost::Host
+                        } else {
+                            JoinOrHost::Join
+                        };
+                        (start_state, role)
+                    }
+                };
+                log::debug!("Local player is {:?}", role);
+                let player_count = Bundle::current().config.player_count();
+                if player_count < MAX_PLAYERS {
+                    log::warn!("The game only reads {player_count} player(s), the other slots won't do anything");
+                }
+                let stats_log = if Settings::current().netplay.stats_log {
+                    StatsLog::create(&session_id, connected.state.remote_peer)
+                        .map_err(|e| log::warn!("Could not create netplay stats log: {:?}", e))
+                        .ok()
+                } else {
+                    None
+                };
                 NetplayState::Connected(Netplay {
                     state: Connected {
-                        start_time: Instant::now(),
+                        start_time: clock.now(),
                         netplay_session: connected.state,
-                        session_id: match connected.start_method {
-                            StartMethod::Start(StartState { session_id, .. }, ..)
-                            | StartMethod::MatchWithRandom(StartState { session_id, .. })
-                            | StartMethod::Resume(StartState { session_id, .. }) => session_id,
-                        },
+                        session_id,
+                        queue_tag,
+                        // Connecting counts as a success, the next failure gets all the attempts again
+                        requeues: requeues.map(|_| 0),
+                        role,
+                        stats_log,
+                        opponent_remembered: false,
+                        poor_since: None,
+                        kind,
+                        resumed,
+                        clock,
                     },
+                    rom: self.rom,
                 })
             }
             ConnectingState::Failed(reason) => NetplayState::Failed(Netplay {
-                state: Failed { reason },
+                state: Failed::new(reason, requeues, queue_tag, clock),
+                rom: self.rom,
             }),
             _ => NetplayState::Connecting(self),
         }
@@ -219,17 +639,33 @@ impl Netplay<ConnectingState> {
 }
 
 impl Netplay<Connected> {
-    pub fn resume(mut self) -> Netplay<Resuming> {
+    fn fail(self, reason: NetplayError) -> NetplayState {
+        NetplayState::Failed(Netplay {
+            state: Failed::new(
+                reason,
+                self.state.requeues,
+                self.state.queue_tag.clone(),
+                self.state.clock.clone(),
+            ),
+            rom: self.rom,
+        })
+    }
+
+    /// Disconnects and tells the other player, so they don't wait for us to come back.
+    /// The socket waits a moment for the peer to answer in the background (see `LEAVE_ACK_TIMEOUT`), peers from before `Features::LEAVE_ACK` just get the message.
+    pub fn leave(self) -> Netplay<LocalNesState> {
+        self.state.netplay_session.leave();
+        self.disconnect()
+    }
+
+    pub fn resume(mut self, reason: String) -> Netplay<Resuming> {
         log::debug!(
             "Resuming netplay to one of the frames ({:?})",
-            self.state
-                .netplay_session
-                .last_confirmed_game_states
-                .clone()
-                .map(|s| s.frame)
+            self.state.netplay_session.confirmed_states.frames()
         );
 
-        Netplay::from(Resuming::new(&mut self))
+        let resuming = Resuming::new(&mut self, reason);
+        Netplay::from(resuming, self.rom)
     }
 
     fn advance(
@@ -240,55 +676,306 @@ impl Netplay<Connected> {
         //log::trace!("Advancing Netplay<Connected>");
         let netplay_session = &mut self.state.netplay_session;
 
+        if !self.state.opponent_remembered {
+            if let Some(profile) = netplay_session.peer_profile() {
+                self.state.opponent_remembered = true;
+                Settings::current_mut()
+                    .netplay
+                    .remember_opponent(RecentOpponent::new(profile));
+            }
+        }
+
+        if netplay_session.is_peer_misbehaving() {
+            // Resuming with the same peer won't help, it's not a connection problem
+            log::error!("Peer keeps sending garbage, giving up");
+            return self.fail(NetplayError::ProtocolError);
+        }
+
+        if netplay_session.is_peer_flooding() {
+            log::error!("Peer keeps flooding us, giving up");
+            return self.fail(NetplayError::PeerFlooding);
+        }
+
+        if netplay_session.has_peer_left() {
+            log::info!("The other player left, not resuming");
+            return self.fail(NetplayError::PeerLeft);
+        }
+
+        if let Some(frame) = netplay_session.desynced_at() {
+            // Resuming would start from states that differ just the same
+            log::error!("The game went out of sync at frame {frame}, giving up");
+            netplay_session.leave();
+            return self.fail(NetplayError::DesyncDetected(frame));
+        }
+
         if let Some(joypad_mapping) = &mut netplay_session.game_state.joypad_mapping.clone() {
             match netplay_session.advance(joypad_state, joypad_mapping, buffers) {
-                Ok(_) => NetplayState::Connected(self),
+                Ok(_) => {
+                    let stats = netplay_session.remote_stats();
+                    if let (Some(stats_log), Some(stats)) = (&mut self.state.stats_log, &stats) {
+                        stats_log.log(stats);
+                    }
+                    if let (Some(limit), Some(stats)) = (
+                        Bundle::current().config.netplay.poor_connection_limit,
+                        &stats,
+                    ) {
+                        let now = self.state.clock.now();
+                        if !limit.is_exceeded(stats) {
+                            self.state.poor_since = None;
+                        } else if now
+                            .saturating_duration_since(*self.state.poor_since.get_or_insert(now))
+                            >= Duration::from_secs(limit.secs as u64)
+                        {
+                            log::info!(
+                                "The connection has been over {limit:?} for {}s, giving up",
+                                limit.secs
+                            );
+                            self.state.netplay_session.leave();
+                            return self.fail(NetplayError::ConnectionTooPoor);
+                        }
+                    }
+                    NetplayState::Connected(self)
+                }
+                Err(e) if Settings::current().netplay.go_offline_on_peer_drop => {
+                    log::info!("Continuing offline due to error: {:?}", e);
+                    // The newest confirmed state, it's what both players last agreed on
+                    let game_state = self.state.netplay_session.latest_confirmed_game_state();
+                    let nes_state = game_state.nes_state.clone();
+                    NetplayState::Disconnected(Netplay::from(nes_state, self.rom))
+                }
                 Err(e) => {
                     log::error!("Resuming due to error: {:?}", e);
-                    //TODO: Popup/info about the error? Or perhaps put the reason for the resume in the resume state below?
-                    NetplayState::Resuming(self.resume())
+                    //TODO: Popup/info about the error?
+                    NetplayState::Resuming(self.resume(e.to_string()))
                 }
             }
         } else {
-            //TODO: Actual input mapping..
-            netplay_session.game_state.joypad_mapping =
-                Some(if netplay_session.get_local_player_idx() == 0 {
-                    JoypadMapping::P1
-                } else {
-                    JoypadMapping::P2
-                });
-            NetplayState::Connected(self)
+            match netplay_session.negotiate_joypad_mapping() {
+                Some(joypad_mapping) => {
+                    log::debug!("Playing as {joypad_mapping:?}");
+                    netplay_session.game_state.joypad_mapping = Some(joypad_mapping);
+                    NetplayState::Connected(self)
+                }
+                None => {
+                    // Both players would drive the same controller
+                    log::error!("Could not agree on who plays which controller, giving up");
+                    self.fail(NetplayError::ProtocolError)
+                }
+            }
         }
     }
 }
 
 impl Netplay<Resuming> {
+    /// Nothing is rendered while resuming, so the last frame of the session stays on screen until it either resumes or fails
     fn advance(mut self) -> NetplayState {
         //log::trace!("Advancing Netplay<Resuming>");
-        self.state.attempt1 = self.state.attempt1.advance();
-        self.state.attempt2 = self.state.attempt2.advance();
-
-        if let ConnectingState::Connected(_) = &self.state.attempt1 {
-            NetplayState::Connecting(Netplay {
-                state: self.state.attempt1,
-            })
-        } else if let ConnectingState::Connected(_) = &self.state.attempt2 {
+        if self
+            .state
+            .deadline
+            .is_some_and(|deadline| self.state.clock.now() >= deadline)
+        {
+            log::info!(
+                "Could not reconnect within {:?}, giving up",
+                self.state
+                    .clock
+                    .now()
+                    .saturating_duration_since(self.state.start_time)
+            );
+            self.state.cancel();
+            return NetplayState::Failed(Netplay {
+                state: Failed::new(
+                    NetplayError::ReconnectTimedOut,
+                    None,
+                    None,
+                    self.state.clock.clone(),
+                ),
+                rom: self.rom,
+            });
+        }
+        self.state.attempts = std::mem::take(&mut self.state.attempts)
+            .into_iter()
+            .map(ConnectingState::advance)
+            .collect();
+
+        // Only the attempt of the frame both agreed on gets this far (see `Features::RESUME_FRAMES`), the others leave their rooms
+        if let Some(connected) = self
+            .state
+            .attempts
+            .iter()
+            .position(|attempt| matches!(attempt, ConnectingState::Connected(_)))
+        {
+            let connected = self.state.attempts.swap_remove(connected);
+            if let Some(frame) = connected
+                .start_method()
+                .map(|start_method| start_method.start_state().game_state.frame)
+            {
+                log::info!("Resuming from frame {frame}");
+            }
+            self.state.cancel();
             NetplayState::Connecting(Netplay {
-                state: self.state.attempt2,
+                state: connected,
+                rom: self.rom,
             })
         } else {
             NetplayState::Resuming(self)
         }
     }
 
-    pub fn cancel(self) -> Netplay<LocalNesState> {
+    pub fn cancel(mut self) -> Netplay<LocalNesState> {
         log::debug!("Resume cancelled by user");
+        self.state.cancel();
         self.disconnect()
     }
 }
 
 impl Netplay<Failed> {
-    fn advance(self) -> NetplayState {
-        NetplayState::Failed(self)
+    fn advance(mut self) -> NetplayState {
+        match self.state.requeue.take() {
+            Some(requeue) if requeue.is_due() => {
+                match Netplay::new(self.rom.clone()).and_then(|netplay| {
+                    netplay.find_game_in(requeue.queue_tag, requeue.attempt, requeue.clock)
+                }) {
+                    Ok(netplay) => netplay,
+                    Err(e) => {
+                        log::error!("Could not search for another game: {:?}", e);
+                        NetplayState::Failed(self)
+                    }
+                }
+            }
+            requeue => {
+                self.state.requeue = requeue;
+                NetplayState::Failed(self)
+            }
+        }
+    }
+
+    /// Stops searching for another game, the failure stays on screen
+    pub fn stop_requeue(&mut self) {
+        self.state.requeue = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netplay::{
+        benchmark::{loopback_session, LoopbackSocket},
+        clock::ManualClock,
+        connecting_state::Connecting,
+        NetplayNesState,
+    };
+
+    fn resuming(clock: &ManualClock, grace: Duration) -> Netplay<Resuming> {
+        let clock = NetplayClock::Manual(clock.clone());
+        Netplay::from(
+            Resuming {
+                attempts: Vec::new(),
+                reason: "test".to_string(),
+                start_time: clock.now(),
+                deadline: Some(clock.now() + grace),
+                clock,
+            },
+            Arc::new(GameRom::loaded(&[], NesRegion::Ntsc)),
+        )
+    }
+
+    #[test]
+    fn resuming_gives_up_at_the_deadline() {
+        let clock = ManualClock::new();
+        let grace = Duration::from_secs(10);
+
+        clock.advance(grace - Duration::from_millis(1));
+        assert!(matches!(
+            resuming(&clock, grace).advance(),
+            NetplayState::Resuming(_)
+        ));
+
+        let netplay = resuming(&clock, grace);
+        clock.advance(grace);
+        match netplay.advance() {
+            NetplayState::Failed(failed) => {
+                assert_eq!(failed.state.reason, NetplayError::ReconnectTimedOut)
+            }
+            other => panic!("expected Failed, got {}", other.name()),
+        }
+    }
+
+    // A current thread runtime never gets to run what connecting spawns, so resuming doesn't reach out to a server
+    #[tokio::test]
+    async fn the_rom_survives_every_transition() {
+        let rom = Arc::new(GameRom::bundled());
+        let (socket, peer) = LoopbackSocket::pair(0);
+        let start_state = StartState {
+            game_state: Arc::new(NetplayNesState::new(
+                LocalNesState::start_rom(&rom.netplay_rom, false, &rom.region)
+                    .expect("the netplay ROM to start"),
+            )),
+            session_id: "test".to_string(),
+            rom_hash: rom.netplay_rom_hash.clone(),
+            queue_tag: None,
+            requeues: None,
+            confirmed_inputs: Default::default(),
+            connect_deadline: None,
+            resume_frames: Default::default(),
+            clock: NetplayClock::default(),
+        };
+        let start_method = StartMethod::Start(start_state, "TEST".to_string(), JoinOrHost::Host);
+        let connecting = Netplay::from(
+            ConnectingState::Connected(Box::new(Connecting {
+                start_method,
+                state: loopback_session(socket, peer.id, 2).expect("the session to start"),
+            })),
+            rom.clone(),
+        );
+
+        let NetplayState::Connected(connected) = connecting.advance() else {
+            panic!("expected Connected");
+        };
+        assert!(Arc::ptr_eq(connected.rom(), &rom));
+        let resuming = connected.resume("test".to_string());
+        assert!(Arc::ptr_eq(resuming.rom(), &rom));
+        let disconnected = resuming.cancel();
+        assert!(Arc::ptr_eq(disconnected.rom(), &rom));
+    }
+
+    #[test]
+    fn requeue_waits_for_the_delay() {
+        let clock = ManualClock::new();
+        let requeue = Requeue {
+            queue_tag: None,
+            attempt: 1,
+            at: clock.now() + REQUEUE_DELAY,
+            clock: NetplayClock::Manual(clock.clone()),
+        };
+        assert!(!requeue.is_due());
+        clock.advance(REQUEUE_DELAY - Duration::from_millis(1));
+        assert!(!requeue.is_due());
+        clock.advance(Duration::from_millis(1));
+        assert!(requeue.is_due());
+    }
+
+    #[test]
+    fn room_names_are_normalized() {
+        assert_eq!(sanitize_room_name(" ab1 "), Some("AB1".to_string()));
+        assert_eq!(sanitize_room_name("ABCD"), Some("ABCD".to_string()));
+        assert_eq!(sanitize_room_name("abcde"), None);
+        assert_eq!(sanitize_room_name("a-b"), None);
+        assert_eq!(sanitize_room_name("  "), None);
+    }
+
+    #[test]
+    fn queue_tags_are_normalized() {
+        assert_eq!(
+            sanitize_queue_tag(" Ranked-2 "),
+            Some("ranked-2".to_string())
+        );
+        assert_eq!(
+            sanitize_queue_tag(&"a".repeat(MAX_QUEUE_TAG_LEN)).map(|tag| tag.len()),
+            Some(MAX_QUEUE_TAG_LEN)
+        );
+        assert_eq!(sanitize_queue_tag(&"a".repeat(MAX_QUEUE_TAG_LEN + 1)), None);
+        assert_eq!(sanitize_queue_tag("no spaces"), None);
+        assert_eq!(sanitize_queue_tag(""), None);
     }
 }
@@ -0,0 +1,189 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Settings;
+
+use super::netplay_state::get_netplay_id;
+
+/// Starts the profile packet, it's only sent to peers that announced `Features::PROFILE`
+pub const PROFILE_MAGIC: &[u8; 4] = b"NBPF";
+
+const MAX_PLAYER_NAME_LEN: usize = 24;
+pub const MAX_RECENT_OPPONENTS: usize = 20;
+/// Longer ids are dropped by the peer
+const MAX_PLAYER_ID_LEN: usize = 64;
+
+/// Who we are towards the other player, see [`set_identity_provider`].
+/// An embedder with its own accounts can use their ids, so the recent opponents are real accounts.
+pub trait IdentityProvider: Send + Sync {
+    /// Opaque, it's only compared to other ids. At most 64 bytes.
+    fn id(&self) -> String;
+    /// What the other player sees us as, `None` for no name
+    fn display_name(&self) -> Option<String>;
+}
+
+/// Our `netplay_id` (hashed, since that's what the server knows us by) and the `player_name` setting
+struct DefaultIdentity;
+
+impl IdentityProvider for DefaultIdentity {
+    fn id(&self) -> String {
+        format!("{:x}", md5::compute(get_netplay_id()))
+    }
+
+    fn display_name(&self) -> Option<String> {
+        Settings::current().netplay.player_name()
+    }
+}
+
+static IDENTITY_PROVIDER: Mutex<Option<Arc<dyn IdentityProvider>>> = Mutex::new(None);
+
+/// Used for the profile of every following session, `None` goes back to the default identity. Anonymous mode still sends nothing.
+#[allow(dead_code)] // For embedders with their own accounts
+pub fn set_identity_provider(provider: Option<Arc<dyn IdentityProvider>>) {
+    *IDENTITY_PROVIDER.lock().unwrap() = provider;
+}
+
+fn identity_provider() -> Arc<dyn IdentityProvider> {
+    IDENTITY_PROVIDER
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(DefaultIdentity))
+}
+
+/// Who a peer says they are, exchanged right after the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerProfile {
+    /// Stays the same between sessions, but isn't the netplay id itself since that's what the server knows us by
+    pub id: String,
+    pub name: Option<String>,
+}
+
+impl PlayerProfile {
+    /// From the [`IdentityProvider`], `None` in anonymous mode since nothing that ties sessions together is sent then.
+    pub fn ours() -> Option<Self> {
+        if Settings::current().netplay.anonymous {
+            return None;
+        }
+        let provider = identity_provider();
+        let mut id = provider.id().trim().to_string();
+        if id.is_empty() || id.len() > MAX_PLAYER_ID_LEN || id.contains('\n') {
+            log::warn!("Can't send the player id {id:?}, using the default one instead");
+            id = DefaultIdentity.id();
+        }
+        Some(Self {
+            id,
+            name: provider
+                .display_name()
+                .as_deref()
+                .and_then(sanitize_player_name),
+        })
+    }
+
+    /// The magic, the id, a newline and the name
+    pub fn encode(&self) -> Box<[u8]> {
+        let mut packet = PROFILE_MAGIC.to_vec();
+        packet.extend_from_slice(self.id.as_bytes());
+        packet.push(b'\n');
+        if let Some(name) = &self.name {
+            packet.extend_from_slice(name.as_bytes());
+        }
+        packet.into_boxed_slice()
+    }
+
+    /// `None` for any other packet. The peer picks its own name, so it goes through the same checks as ours.
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        let rest = packet.strip_prefix(PROFILE_MAGIC)?;
+        let rest = String::from_utf8_lossy(&rest[..rest.len().min(64 + 4 * MAX_PLAYER_NAME_LEN)]);
+        let (id, name) = rest.split_once('\n')?;
+        let id = id.trim();
+        (!id.is_empty() && id.len() <= MAX_PLAYER_ID_LEN).then(|| Self {
+            id: id.to_string(),
+            name: sanitize_player_name(name),
+        })
+    }
+}
+
+/// Trims the name and drops control characters, `None` if nothing is left. Long names are cut at `MAX_PLAYER_NAME_LEN` characters.
+pub fn sanitize_player_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_PLAYER_NAME_LEN)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// The profile the peer sent, shared so it can arrive both before and after the socket moves into the session.
+#[derive(Clone, Default)]
+pub struct PeerProfile(Arc<Mutex<Option<PlayerProfile>>>);
+
+impl PeerProfile {
+    pub fn get(&self) -> Option<PlayerProfile> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, profile: PlayerProfile) {
+        *self.0.lock().unwrap() = Some(profile);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct RecentOpponent {
+    pub id: String,
+    pub name: Option<String>,
+    /// When the last session with them connected, in seconds since the unix epoch
+    pub last_played: u64,
+}
+
+impl RecentOpponent {
+    pub fn new(profile: PlayerProfile) -> Self {
+        Self {
+            id: profile.id,
+            name: profile.name,
+            last_played: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_names_are_cleaned_up() {
+        assert_eq!(sanitize_player_name("  Ted\n "), Some("Ted".to_string()));
+        assert_eq!(sanitize_player_name("\u{7}\t "), None);
+        let long = "x".repeat(MAX_PLAYER_NAME_LEN + 5);
+        assert_eq!(
+            sanitize_player_name(&long).map(|name| name.chars().count()),
+            Some(MAX_PLAYER_NAME_LEN)
+        );
+    }
+
+    #[test]
+    fn profile_round_trip() {
+        for name in [Some("Ted".to_string()), None] {
+            let profile = PlayerProfile {
+                id: "0123456789abcdef".to_string(),
+                name,
+            };
+            assert_eq!(PlayerProfile::decode(&profile.encode()), Some(profile));
+        }
+    }
+
+    #[test]
+    fn profiles_without_an_id_are_dropped() {
+        assert_eq!(PlayerProfile::decode(b"NBPF\nTed"), None);
+        assert_eq!(PlayerProfile::decode(b"NBPF0123"), None);
+        assert_eq!(PlayerProfile::decode(b"heartbeat"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::Write;
+
+use crate::{bundle::Bundle, settings::Settings};
+
+use super::{
+    connecting_state::NetplayServerConfiguration,
+    netplay_state::{get_netplay_id, NetplayState},
+    NetplayStateHandler,
+};
+
+/// Everything support usually asks for about a netplay problem, as text to paste into a bug report.
+/// Room names are left out since they are what lets others into a private game, the netplay id only with `diagnostics_include_netplay_id`.
+pub fn diagnostics_report(netplay_state_handler: &NetplayStateHandler) -> String {
+    let bundle = Bundle::current();
+    let mut report = String::new();
+    // Writing to a String can't fail
+    let _ = writeln!(
+        report,
+        "{} (nes-bundler {})",
+        bundle.config.name,
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(
+        report,
+        "Netplay ROM hash: {}",
+        netplay_state_handler.rom().netplay_rom_hash
+    );
+    // Copied out, getting the netplay id takes the settings for writing
+    let include_netplay_id = {
+        let netplay_settings = &Settings::current().netplay;
+        netplay_settings.diagnostics_include_netplay_id && !netplay_settings.anonymous
+    };
+    let _ = writeln!(
+        report,
+        "Netplay id: {}",
+        if include_netplay_id {
+            get_netplay_id()
+        } else {
+            "<redacted>".to_string()
+        }
+    );
+    let _ = writeln!(
+        report,
+        "Server: {}",
+        match &bundle.config.netplay.server {
+            NetplayServerConfiguration::Static(conf) => format!(
+                "matchbox {}, ICE {}",
+                conf.matchbox_server(),
+                conf.ice_urls().join(", ")
+            ),
+            NetplayServerConfiguration::TurnOn(servers) =>
+                format!("TurnOn {}", servers.urls().join(", ")),
+        }
+    );
+
+    if let Some(netplay) = &netplay_state_handler.netplay {
+        let _ = write!(report, "State: {}", netplay.name());
+        if let Some(reason) = netplay.reason() {
+            let _ = write!(report, " ({reason})");
+        }
+        let _ = writeln!(report);
+
+        if let NetplayState::Connected(netplay) = netplay {
+            let connected = &netplay.state;
+            let _ = writeln!(
+                report,
+                "Game: {}, {}, connected for {}s{}",
+                if connected.is_public() {
+                    format!(
+                        "public (queue {})",
+                        connected.queue_tag.as_deref().unwrap_or("default")
+                    )
+                } else {
+                    "private".to_string()
+                },
+                if connected.is_host() {
+                    "host"
+                } else {
+                    "joined"
+                },
+                connected.start_time.elapsed().as_secs(),
+                if connected.is_resumed() {
+                    ", resumed"
+                } else {
+                    ""
+                }
+            );
+            let netplay_session = &connected.netplay_session;
+            let _ = writeln!(
+                report,
+                "Frame: {} (confirmed {})",
+                netplay_session.current_frame(),
+                netplay_session.confirmed_frame()
+            );
+            match netplay_session.remote_stats() {
+                Some(stats) => {
+                    let _ = writeln!(
+                        report,
+                        "Stats: ping {}ms, rollback {}, ahead {}, behind {}/{}",
+                        stats.ping,
+                        stats.rollback_depth,
+                        stats.frames_ahead,
+                        stats.local_frames_behind,
+                        stats.remote_frames_behind
+                    );
+                }
+                None => {
+                    let _ = writeln!(report, "Stats: not yet available");
+                }
+            }
+        }
+    }
+
+    let _ = writeln!(report, "Transitions:");
+    let _ = write!(report, "{}", netplay_state_handler.transitions().timeline());
+    report
+}
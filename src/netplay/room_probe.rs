@@ -0,0 +1,82 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use matchbox_socket::{PeerId, WebRtcSocket};
+use tokio::task::JoinHandle;
+
+use super::{
+    clock::{Clock, NetplayClock},
+    connecting_state::{open_socket, StaticNetplayServerConfiguration},
+    socket::{Hello, HANDSHAKE_CHANNEL},
+};
+
+/// Unrelated players can pick the same short room name, a mismatch only counts when nobody showed up in the real room for a while
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The ROM hash is part of the session id, so players who agreed on a room name but loaded different games wait in different rooms forever.
+/// The probe waits in a room by the room name alone alongside, where everyone tells which ROM they have.
+pub struct RoomProbe {
+    socket: WebRtcSocket,
+    signalling_task: JoinHandle<()>,
+    // The peers that have been told our ROM hash
+    announced_to: HashSet<PeerId>,
+    other_rom_seen: bool,
+    rom_hash: String,
+    clock: NetplayClock,
+    started: Instant,
+}
+
+impl RoomProbe {
+    pub fn new(
+        conf: &StaticNetplayServerConfiguration,
+        room_name: &str,
+        rom_hash: String,
+        clock: NetplayClock,
+    ) -> Self {
+        let (socket, signalling_task) = open_socket(conf, &format!("probe_{room_name}"));
+        Self {
+            socket,
+            signalling_task,
+            announced_to: HashSet::new(),
+            other_rom_seen: false,
+            rom_hash,
+            started: clock.now(),
+            clock,
+        }
+    }
+
+    /// True once someone with another ROM has been in the probe room and nobody got matched with us in the grace period
+    pub fn other_rom_in_room(&mut self) -> bool {
+        self.socket.update_peers();
+        let rom_hash = &self.rom_hash;
+        let peers: Vec<PeerId> = self.socket.connected_peers().collect();
+        let channel = self.socket.channel_mut(HANDSHAKE_CHANNEL);
+        for peer in peers {
+            if self.announced_to.insert(peer) {
+                channel.send(Hello::ours(rom_hash.clone()).encode(), peer);
+            }
+        }
+        for (peer, packet) in channel.receive() {
+            if let Some(hello) = Hello::decode(&packet) {
+                if hello.rom_hash != *rom_hash && !self.other_rom_seen {
+                    log::info!(
+                        "Peer {:?} waits in a room with the same name but has netplay ROM {}, ours is {rom_hash}",
+                        peer,
+                        hello.rom_hash
+                    );
+                    self.other_rom_seen = true;
+                }
+            }
+        }
+        self.other_rom_seen
+            && self.clock.now().saturating_duration_since(self.started) >= GRACE_PERIOD
+    }
+}
+
+impl Drop for RoomProbe {
+    fn drop(&mut self) {
+        self.signalling_task.abort();
+    }
+}
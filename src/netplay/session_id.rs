@@ -0,0 +1,96 @@
+use matchbox_socket::PeerId;
+use serde::Deserialize;
+
+use crate::bundle::Bundle;
+
+/// How the ids players meet by on the matchmaking server are built, only players with the same id end up in the same game.
+/// `{room}`, `{rom_hash}`, `{version}` and `{region}` are filled in, "{version}_{room}_{rom_hash}" would for example keep players of different versions apart.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SessionIdScheme {
+    #[serde(default = "SessionIdScheme::default_private_game")]
+    pub private_game: String,
+    #[serde(default = "SessionIdScheme::default_public_game")]
+    pub public_game: String,
+}
+
+impl Default for SessionIdScheme {
+    fn default() -> Self {
+        Self {
+            private_game: Self::default_private_game(),
+            public_game: Self::default_public_game(),
+        }
+    }
+}
+
+impl SessionIdScheme {
+    fn default_private_game() -> String {
+        "{room}_{rom_hash}".to_string()
+    }
+    fn default_public_game() -> String {
+        "{rom_hash}".to_string()
+    }
+
+    fn expand(template: &str, room_name: &str, rom_hash: &str) -> String {
+        template
+            .replace("{room}", room_name)
+            .replace("{rom_hash}", rom_hash)
+            .replace("{version}", env!("CARGO_PKG_VERSION"))
+            .replace(
+                "{region}",
+                &format!("{:?}", Bundle::current().config.get_default_region()),
+            )
+    }
+
+    /// `rom_hash` is the one of the netplay ROM, see `GameRom::netplay_rom_hash`
+    pub fn private_game_id(&self, room_name: &str, rom_hash: &str) -> String {
+        Self::expand(&self.private_game, room_name, rom_hash)
+    }
+
+    /// The queue tag is always added at the end, so every queue stays its own pool whatever the scheme.
+    /// Once matched, [`with_match_nonce`] makes it unique to the pair for resuming.
+    pub fn public_game_id(&self, queue_tag: Option<&str>, rom_hash: &str) -> String {
+        let session_id = Self::expand(&self.public_game, "", rom_hash);
+        match queue_tag {
+            Some(queue_tag) => format!("{session_id}_{queue_tag}"),
+            None => session_id,
+        }
+    }
+}
+
+/// The public game id of a matched pair. Every pair in a queue starts from the same id, a resume with just that could meet
+/// another pair resuming at the same frame. Both players build the nonce from the same sorted peer ids, so they agree on it
+/// without telling each other.
+pub fn with_match_nonce(session_id: &str, peers: &[PeerId]) -> String {
+    let mut peers: Vec<String> = peers.iter().map(|peer| peer.to_string()).collect();
+    peers.sort();
+    let nonce = format!("{:x}", md5::compute(peers.join("_")));
+    format!("{session_id}_{}", &nonce[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerId {
+        PeerId(uuid::Uuid::new_v4())
+    }
+
+    #[test]
+    fn matches_in_the_same_queue_get_their_own_ids() {
+        let session_id = SessionIdScheme::default().public_game_id(None, "rom");
+        let first = with_match_nonce(&session_id, &[peer(), peer()]);
+        let second = with_match_nonce(&session_id, &[peer(), peer()]);
+        assert!(first.starts_with(&session_id));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn both_players_agree_on_the_match_id() {
+        let session_id = SessionIdScheme::default().public_game_id(Some("ranked"), "rom");
+        let (a, b) = (peer(), peer());
+        assert_eq!(
+            with_match_nonce(&session_id, &[a, b]),
+            with_match_nonce(&session_id, &[b, a])
+        );
+    }
+}
@@ -0,0 +1,920 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bitflags::bitflags;
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use ggrs::{Message, NonBlockingSocket};
+use matchbox_socket::{PeerId, WebRtcSocket};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    clock::{Clock, NetplayClock},
+    opponents::{PeerProfile, PlayerProfile, PROFILE_MAGIC},
+    PredictionPolicy,
+};
+use crate::{bundle::Bundle, settings::Settings};
+
+/// No legit GGRS message comes close to this, it's the receive buffer size GGRS uses for its own UDP socket.
+const MAX_PACKET_SIZE: usize = 4096;
+
+/// After this many rejected packets the peer is considered broken (or malicious) and the session is failed.
+pub const MAX_REJECTED_PACKETS: u32 = 20;
+
+/// A GGRS socket that validates everything the peer sends instead of trusting it to be well formed.
+/// Valid input messages then go through a jitter buffer so bursts of packets reach GGRS at a steadier pace.
+/// It also exchanges heartbeats with the peer on the reliable channel, see [`Liveness`].
+pub struct ValidatingSocket {
+    // Only taken when it's dropped, see `wait_for_leave_ack`
+    socket: Option<WebRtcSocket>,
+    clock: NetplayClock,
+    rejected_packets: RejectedPackets,
+    side_channel_limiter: SideChannelLimiter,
+    jitter_buffer: JitterBuffer,
+    liveness: Liveness,
+    next_heartbeat: Instant,
+    peer_features: Features,
+    peer_profile: PeerProfile,
+}
+
+impl ValidatingSocket {
+    pub fn new(
+        socket: WebRtcSocket,
+        jitter_buffer_depth: JitterBufferDepth,
+        heartbeat_interval: Duration,
+        peer_heartbeat_interval: Duration,
+        peer_features: Features,
+        peer_profile: PeerProfile,
+        clock: NetplayClock,
+    ) -> Self {
+        let now = clock.now();
+        Self {
+            socket: Some(socket),
+            rejected_packets: RejectedPackets::default(),
+            side_channel_limiter: SideChannelLimiter::new(now),
+            jitter_buffer: JitterBuffer::new(jitter_buffer_depth),
+            liveness: Liveness::new(
+                heartbeat_interval,
+                peer_heartbeat_interval,
+                peer_features.contains(Features::HEARTBEAT),
+                clock.clone(),
+            ),
+            next_heartbeat: now,
+            peer_features,
+            peer_profile,
+            clock,
+        }
+    }
+
+    /// A handle to the number of rejected packets that stays valid once the socket is moved into a session.
+    pub fn rejected_packets(&self) -> RejectedPackets {
+        self.rejected_packets.clone()
+    }
+
+    /// A handle to what the heartbeats say about the peer, like `rejected_packets` it outlives the move into a session.
+    pub fn liveness(&self) -> Liveness {
+        self.liveness.clone()
+    }
+
+    // Rides on the GGRS polling, which happens every frame
+    fn exchange_heartbeats(&mut self) {
+        let now = self.clock.now();
+        let socket = self.socket.as_mut().expect("a socket until it's dropped");
+        let peers: Vec<PeerId> = socket.connected_peers().collect();
+        let channel = socket.channel_mut(HANDSHAKE_CHANNEL);
+        if self.peer_features.contains(Features::HEARTBEAT) && now >= self.next_heartbeat {
+            self.next_heartbeat = now + self.liveness.interval;
+            for peer in peers {
+                channel.send(HEARTBEAT_PACKET.into(), peer);
+            }
+        }
+        for (peer, packet) in channel.receive() {
+            match self.side_channel_limiter.admit(now) {
+                Admission::Admit => {}
+                Admission::Drop => continue,
+                Admission::Flooding => {
+                    if !self.rejected_packets.flooding.swap(true, Ordering::Relaxed) {
+                        log::error!("Peer {:?} keeps flooding the reliable channel", peer);
+                    }
+                    continue;
+                }
+            }
+            if packet.len() > MAX_PACKET_SIZE {
+                let rejected = self.rejected_packets.reject();
+                log::warn!(
+                    "Dropping oversized reliable packet ({} bytes) from peer {:?} ({rejected} rejected)",
+                    packet.len(),
+                    peer
+                );
+                continue;
+            }
+            match &*packet {
+                HEARTBEAT_PACKET => *self.liveness.last_heartbeat.lock().unwrap() = now,
+                LEAVE_PACKET => {
+                    log::info!("Peer {:?} left the session", peer);
+                    self.liveness.peer_left.store(true, Ordering::Relaxed);
+                    if self.peer_features.contains(Features::LEAVE_ACK) {
+                        channel.send(LEAVE_ACK_PACKET.into(), peer);
+                    }
+                }
+                // It's sent right after the handshake, so it may arrive once the session has started
+                profile if profile.starts_with(PROFILE_MAGIC) => {
+                    if let Some(profile) = PlayerProfile::decode(profile) {
+                        self.peer_profile.set(profile);
+                    }
+                }
+                _ => log::trace!("Ignoring unexpected reliable packet from peer {:?}", peer),
+            }
+        }
+    }
+}
+
+impl Drop for ValidatingSocket {
+    fn drop(&mut self) {
+        let Some(mut socket) = self.socket.take() else {
+            return;
+        };
+        if self.liveness.leaving.load(Ordering::Relaxed)
+            && self.peer_features.contains(Features::LEAVE)
+        {
+            let peers: Vec<PeerId> = socket.connected_peers().collect();
+            let channel = socket.channel_mut(HANDSHAKE_CHANNEL);
+            for peer in &peers {
+                channel.send(LEAVE_PACKET.into(), *peer);
+            }
+            if !self.peer_features.contains(Features::LEAVE_ACK) {
+                // Best effort, there's no telling whether it got there
+                return;
+            }
+            // The socket only delivers while it's around, a task keeps it until the peer got it so whoever drops us doesn't wait
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(wait_for_leave_ack(socket, peers));
+                }
+                Err(_) => log::debug!("No runtime to wait for an answer to leaving, going anyway"),
+            }
+        }
+    }
+}
+
+/// Owns the socket for `LEAVE_ACK_TIMEOUT` at most, dropping it closes the connection to the peer
+async fn wait_for_leave_ack(mut socket: WebRtcSocket, peers: Vec<PeerId>) {
+    let acked = async move {
+        loop {
+            if socket
+                .channel_mut(HANDSHAKE_CHANNEL)
+                .receive()
+                .iter()
+                .any(|(_, packet)| &**packet == LEAVE_ACK_PACKET)
+            {
+                return;
+            }
+            Delay::new(Duration::from_millis(5)).await;
+        }
+    }
+    .fuse();
+    let timeout = Delay::new(LEAVE_ACK_TIMEOUT).fuse();
+    futures::pin_mut!(acked, timeout);
+    select! {
+        _ = acked => log::debug!("The peer knows we left"),
+        _ = timeout => log::debug!("No answer to leaving from {:?}, going anyway", peers),
+    }
+}
+
+const HEARTBEAT_PACKET: &[u8] = b"heartbeat";
+const LEAVE_PACKET: &[u8] = b"leave";
+const LEAVE_ACK_PACKET: &[u8] = b"leave-ack";
+/// How long the socket is kept around for the peer to answer a leave, it's waited for in a task of its own
+const LEAVE_ACK_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Bump this when a packet changes in a way an older peer would misread.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Peers before this can't play with us, like version 0 peers that take our handshake for a ROM hash and leave.
+/// Version 1 peers would take the heartbeat interval of version 2 for a part of the ROM hash.
+/// Raise it when a change can't be covered by a feature. A newer peer checks this against its own, so only older versions are checked.
+pub const OLDEST_COMPATIBLE_VERSION: u16 = 2;
+
+bitflags! {
+    /// The optional packets a peer understands. Only what both sides have is sent.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Features: u32 {
+        const HEARTBEAT = 1 << 0;
+        const LEAVE = 1 << 1;
+        /// A name and id for the recent opponents, see `PlayerProfile`
+        const PROFILE = 1 << 2;
+        /// Answers a leave, so the one leaving knows it got there
+        const LEAVE_ACK = 1 << 3;
+        /// Wants `PredictionPolicy::Neutral`, only used when both sides have it
+        const NEUTRAL_PREDICTION = 1 << 4;
+        /// Plays the confirmed inputs after the resume state again when resuming, see `resume_fast_forward`
+        const FAST_FORWARD = 1 << 5;
+        /// Tells which frames we try to resume from, so both pick the same one, see [`agree_on_resume_frame`]
+        const RESUME_FRAMES = 1 << 6;
+        /// Compares the checksums of the states now and then, only announced with `NetplayBuildConfiguration::desync_check`
+        const DESYNC_CHECK = 1 << 7;
+    }
+}
+
+impl Features {
+    /// Everything this version can send and understand
+    pub fn supported() -> Self {
+        Features::HEARTBEAT
+            | Features::LEAVE
+            | Features::PROFILE
+            | Features::LEAVE_ACK
+            | Features::NEUTRAL_PREDICTION
+            | Features::FAST_FORWARD
+            | Features::RESUME_FRAMES
+            | Features::DESYNC_CHECK
+    }
+
+    /// What we announce, the supported features without the preferences we don't have
+    pub fn ours() -> Self {
+        let mut features = Self::supported();
+        if Settings::current().netplay.prediction != PredictionPolicy::Neutral {
+            features.remove(Features::NEUTRAL_PREDICTION);
+        }
+        if !Settings::current().netplay.resume_fast_forward {
+            features.remove(Features::FAST_FORWARD);
+        }
+        if !Bundle::current().config.netplay.desync_check {
+            features.remove(Features::DESYNC_CHECK);
+        }
+        features
+    }
+
+    pub fn prediction(&self) -> PredictionPolicy {
+        if self.contains(Features::NEUTRAL_PREDICTION) {
+            PredictionPolicy::Neutral
+        } else {
+            PredictionPolicy::RepeatLast
+        }
+    }
+}
+
+/// Starts the handshake packet, peers from before the version negotiation send their raw ROM hash instead.
+const HELLO_MAGIC: &[u8; 4] = b"NBHI";
+
+/// Starts the packet telling how many frames of confirmed inputs we have after the resume state, only sent with `Features::FAST_FORWARD`
+const FAST_FORWARD_MAGIC: &[u8; 4] = b"NBFF";
+
+/// The magic and the number of frames as little endian
+pub fn encode_fast_forward(frames: u32) -> Box<[u8]> {
+    let mut packet = FAST_FORWARD_MAGIC.to_vec();
+    packet.extend_from_slice(&frames.to_le_bytes());
+    packet.into_boxed_slice()
+}
+
+pub fn decode_fast_forward(packet: &[u8]) -> Option<u32> {
+    let frames = packet.strip_prefix(FAST_FORWARD_MAGIC)?;
+    Some(u32::from_le_bytes(frames.try_into().ok()?))
+}
+
+/// Starts the packet listing the frames we try to resume from, only sent with `Features::RESUME_FRAMES`
+const RESUME_FRAMES_MAGIC: &[u8; 4] = b"NBRF";
+
+/// More than any `resume_attempts` makes sense for, a longer list is taken as garbage
+const MAX_RESUME_FRAMES: usize = 64;
+
+/// The magic and the frames as little endian
+pub fn encode_resume_frames(frames: &[i32]) -> Box<[u8]> {
+    let mut packet = RESUME_FRAMES_MAGIC.to_vec();
+    for frame in frames {
+        packet.extend_from_slice(&frame.to_le_bytes());
+    }
+    packet.into_boxed_slice()
+}
+
+pub fn decode_resume_frames(packet: &[u8]) -> Option<Vec<i32>> {
+    let frames = packet.strip_prefix(RESUME_FRAMES_MAGIC)?;
+    if frames.len() % 4 != 0 || frames.len() / 4 > MAX_RESUME_FRAMES {
+        return None;
+    }
+    Some(
+        frames
+            .chunks_exact(4)
+            .map(|frame| i32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))
+            .collect(),
+    )
+}
+
+/// The newest frame both try to resume from. Both sides come to the same answer from the two lists, whichever attempt they compare them in.
+pub fn agree_on_resume_frame(ours: &[i32], theirs: &[i32]) -> Option<i32> {
+    ours.iter()
+        .filter(|frame| theirs.contains(frame))
+        .max()
+        .copied()
+}
+
+/// What a peer tells about itself before the session starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub features: Features,
+    /// How often the peer sends heartbeats, 0 for peers before version 2
+    pub heartbeat_interval_ms: u16,
+    pub rom_hash: String,
+}
+
+impl Hello {
+    pub fn ours(rom_hash: String) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features: Features::ours(),
+            heartbeat_interval_ms: Settings::current()
+                .netplay
+                .heartbeat_interval()
+                .as_millis()
+                .min(u16::MAX as u128) as u16,
+            rom_hash,
+        }
+    }
+
+    /// How long the peer takes between heartbeats, see [`Liveness::missed_heartbeats`]
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms as u64)
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.version >= OLDEST_COMPATIBLE_VERSION
+    }
+
+    /// The magic, the version, the features and the heartbeat interval as little endian, then the ROM hash.
+    pub fn encode(&self) -> Box<[u8]> {
+        let mut packet = HELLO_MAGIC.to_vec();
+        packet.extend_from_slice(&self.version.to_le_bytes());
+        packet.extend_from_slice(&self.features.bits().to_le_bytes());
+        packet.extend_from_slice(&self.heartbeat_interval_ms.to_le_bytes());
+        packet.extend_from_slice(self.rom_hash.as_bytes());
+        packet.into_boxed_slice()
+    }
+
+    /// `None` for packets that aren't a handshake, like a heartbeat that overtook our own handshake.
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        if packet == HEARTBEAT_PACKET
+            || packet == LEAVE_PACKET
+            || packet == LEAVE_ACK_PACKET
+            || packet.starts_with(PROFILE_MAGIC)
+            || packet.starts_with(FAST_FORWARD_MAGIC)
+            || packet.starts_with(RESUME_FRAMES_MAGIC)
+        {
+            return None;
+        }
+        let Some(rest) = packet.strip_prefix(HELLO_MAGIC) else {
+            // A peer from before the negotiation, it knows none of the optional packets
+            return Some(Self {
+                version: 0,
+                features: Features::empty(),
+                heartbeat_interval_ms: 0,
+                rom_hash: String::from_utf8_lossy(&packet[..packet.len().min(64)]).to_string(),
+            });
+        };
+        if rest.len() < 6 {
+            return None;
+        }
+        let version = u16::from_le_bytes([rest[0], rest[1]]);
+        // Features of newer versions we don't know are dropped
+        let features =
+            Features::from_bits_truncate(u32::from_le_bytes([rest[2], rest[3], rest[4], rest[5]]));
+        // Only told since version 2, older peers have the ROM hash right after the features
+        let (heartbeat_interval_ms, rest) = if version >= 2 {
+            if rest.len() < 8 {
+                return None;
+            }
+            (u16::from_le_bytes([rest[6], rest[7]]), &rest[8..])
+        } else {
+            (0, &rest[6..])
+        };
+        let rom_hash = String::from_utf8_lossy(&rest[..rest.len().min(64)]).to_string();
+        Some(Self {
+            version,
+            features,
+            heartbeat_interval_ms,
+            rom_hash,
+        })
+    }
+}
+
+/// Tells a peer that silently went away (no packets at all, not even a goodbye) apart from one that left on purpose.
+#[derive(Clone)]
+pub struct Liveness {
+    clock: NetplayClock,
+    interval: Duration,
+    // How often the peer sends them, which can be less often than we do
+    peer_interval: Duration,
+    // Peers without heartbeats can't be told apart from a dead peer, so they're never considered missing
+    peer_sends_heartbeats: bool,
+    last_heartbeat: Arc<Mutex<Instant>>,
+    peer_left: Arc<AtomicBool>,
+    leaving: Arc<AtomicBool>,
+}
+
+impl Liveness {
+    fn new(
+        interval: Duration,
+        peer_interval: Duration,
+        peer_sends_heartbeats: bool,
+        clock: NetplayClock,
+    ) -> Self {
+        Self {
+            last_heartbeat: Arc::new(Mutex::new(clock.now())),
+            clock,
+            interval,
+            peer_interval,
+            peer_sends_heartbeats,
+            peer_left: Arc::new(AtomicBool::new(false)),
+            leaving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// For a peer that isn't behind a `ValidatingSocket`, like the one of the rollback benchmark. It never goes missing.
+    pub fn untracked() -> Self {
+        Self::new(
+            Duration::from_secs(1),
+            Duration::ZERO,
+            false,
+            NetplayClock::default(),
+        )
+    }
+
+    /// How many heartbeat intervals have passed since the last heartbeat from the peer. The longer of our interval and the peer's
+    /// is counted, so a peer that sends less often than we do isn't missing heartbeats it never meant to send.
+    pub fn missed_heartbeats(&self) -> u32 {
+        if !self.peer_sends_heartbeats {
+            return 0;
+        }
+        let since_last = self
+            .clock
+            .now()
+            .saturating_duration_since(*self.last_heartbeat.lock().unwrap());
+        (since_last.as_secs_f32() / self.interval.max(self.peer_interval).as_secs_f32()) as u32
+    }
+
+    pub fn peer_left(&self) -> bool {
+        self.peer_left.load(Ordering::Relaxed)
+    }
+
+    /// Makes the socket say goodbye to the peer when it's dropped, so it doesn't try to resume with us
+    pub fn leave(&self) {
+        self.leaving.store(true, Ordering::Relaxed);
+    }
+}
+
+/// What the peer sent that couldn't be used, shared so it stays valid once the socket is moved into a session.
+#[derive(Clone, Default)]
+pub struct RejectedPackets {
+    count: Arc<AtomicU32>,
+    flooding: Arc<AtomicBool>,
+}
+
+impl RejectedPackets {
+    /// Counts one more, returns how many there are now
+    fn reject(&self) -> u32 {
+        self.count.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.count.load(Ordering::Relaxed) > MAX_REJECTED_PACKETS
+    }
+
+    /// The peer kept sending more than `SIDE_CHANNEL_RATE` reliable packets for `FLOOD_TIMEOUT`, see [`SideChannelLimiter`]
+    pub fn flooding(&self) -> bool {
+        self.flooding.load(Ordering::Relaxed)
+    }
+}
+
+/// Reliable packets per second a peer may send on average, way more than heartbeats and the odd message need
+const SIDE_CHANNEL_RATE: f32 = 30.0;
+/// How many reliable packets may arrive at once, on top of the rate
+const SIDE_CHANNEL_BURST: f32 = 60.0;
+/// Dropping packets for this long without a break is a flood rather than a burst
+const FLOOD_TIMEOUT: Duration = Duration::from_secs(3);
+/// A flood is over when nothing had to be dropped for this long
+const FLOOD_COOLDOWN: Duration = Duration::from_secs(1);
+
+enum Admission {
+    Admit,
+    Drop,
+    Flooding,
+}
+
+/// A token bucket for the reliable channel, so a peer sending a flood of messages only costs us the time to drop them.
+/// The GGRS channel is never limited, the inputs have to get through.
+struct SideChannelLimiter {
+    tokens: f32,
+    last_refill: Instant,
+    // When packets started being dropped, `None` while none are
+    dropping_since: Option<Instant>,
+    last_drop: Instant,
+    dropped: u32,
+}
+
+impl SideChannelLimiter {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: SIDE_CHANNEL_BURST,
+            last_refill: now,
+            dropping_since: None,
+            last_drop: now,
+            dropped: 0,
+        }
+    }
+
+    fn admit(&mut self, now: Instant) -> Admission {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f32() * SIDE_CHANNEL_RATE).min(SIDE_CHANNEL_BURST);
+        self.last_refill = now;
+
+        if let Some(since) = self.dropping_since {
+            if now.saturating_duration_since(self.last_drop) >= FLOOD_COOLDOWN {
+                log::info!(
+                    "Peer stopped flooding, dropped {} reliable packets in {:?}",
+                    self.dropped,
+                    self.last_drop.saturating_duration_since(since)
+                );
+                self.dropping_since = None;
+                self.dropped = 0;
+            }
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Admission::Admit;
+        }
+
+        self.dropped += 1;
+        self.last_drop = now;
+        let since = *self.dropping_since.get_or_insert_with(|| {
+            log::warn!("Peer sends more than {SIDE_CHANNEL_RATE} reliable packets per second, dropping the rest");
+            now
+        });
+        if now.saturating_duration_since(since) >= FLOOD_TIMEOUT {
+            Admission::Flooding
+        } else {
+            Admission::Drop
+        }
+    }
+}
+
+/// The index of `MessageBody::Input` in GGRS, its fields are private so the kind of message is read from the packet
+const GGRS_INPUT_BODY: u32 = 2;
+
+/// Bincode puts the body's variant index as a little endian u32 right after the u16 magic of the header
+fn is_input(packet: &[u8]) -> bool {
+    packet.get(2..6).is_some_and(|body| {
+        u32::from_le_bytes([body[0], body[1], body[2], body[3]]) == GGRS_INPUT_BODY
+    })
+}
+
+fn decode(packet: &[u8]) -> Result<Message, String> {
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(format!("oversized packet ({} bytes)", packet.len()));
+    }
+    bincode::deserialize(packet).map_err(|e| format!("malformed packet ({e})"))
+}
+
+/// GGRS traffic, unreliable
+pub const GGRS_CHANNEL: usize = 0;
+/// The handshake before the session starts and the heartbeats during it, reliable
+pub const HANDSHAKE_CHANNEL: usize = 1;
+
+impl NonBlockingSocket<PeerId> for ValidatingSocket {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        match bincode::serialize(msg) {
+            Ok(packet) => self
+                .socket
+                .as_mut()
+                .expect("a socket until it's dropped")
+                .channel_mut(GGRS_CHANNEL)
+                .send(packet.into_boxed_slice(), *addr),
+            Err(e) => log::error!("Could not serialize message: {:?}", e),
+        }
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        self.exchange_heartbeats();
+        let (inputs, others): (Vec<_>, Vec<_>) = self
+            .socket
+            .as_mut()
+            .expect("a socket until it's dropped")
+            .channel_mut(GGRS_CHANNEL)
+            .receive()
+            .into_iter()
+            .filter_map(|(peer, packet)| match decode(&packet) {
+                Ok(msg) => Some((is_input(&packet), peer, msg)),
+                Err(reason) => {
+                    let rejected = self.rejected_packets.reject();
+                    log::warn!(
+                        "Dropping {reason} from peer {:?} ({rejected} rejected)",
+                        peer
+                    );
+                    None
+                }
+            })
+            .partition(|(input, ..)| *input);
+        let now = self.clock.now();
+        self.jitter_buffer.push(
+            inputs
+                .into_iter()
+                .map(|(_, peer, msg)| (peer, msg))
+                .collect(),
+            now,
+        );
+        // Holding back the sync or the quality reports would only skew the round trip times GGRS measures
+        let mut messages: Vec<_> = others
+            .into_iter()
+            .map(|(_, peer, msg)| (peer, msg))
+            .collect();
+        messages.extend(self.jitter_buffer.pop_due(now));
+        messages
+    }
+}
+
+/// The most the jitter buffer will ever hold messages back, beyond this it's better to let GGRS roll back.
+const MAX_JITTER_BUFFER_DEPTH: Duration = Duration::from_millis(50);
+
+/// How long input messages are held back, only the inputs go through the buffer
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub enum JitterBufferDepth {
+    /// Sized from the measured arrival jitter, stays close to zero on stable connections
+    Auto,
+    /// Always hold messages back this many milliseconds (0 disables the buffer)
+    Fixed(u16),
+}
+
+impl Default for JitterBufferDepth {
+    /// Off, a buffer only pays off on connections that are flaky in a particular way
+    fn default() -> Self {
+        JitterBufferDepth::Fixed(0)
+    }
+}
+
+struct JitterBuffer {
+    depth: JitterBufferDepth,
+    queue: VecDeque<(Instant, PeerId, Message)>,
+    last_arrival: Option<Instant>,
+    // Smoothed time between arrivals and how much arrivals deviate from it, in seconds
+    mean_interval: f32,
+    jitter: f32,
+}
+
+impl JitterBuffer {
+    fn new(depth: JitterBufferDepth) -> Self {
+        Self {
+            depth,
+            queue: VecDeque::new(),
+            last_arrival: None,
+            mean_interval: 0.0,
+            jitter: 0.0,
+        }
+    }
+
+    fn current_depth(&self) -> Duration {
+        match self.depth {
+            JitterBufferDepth::Auto => Duration::from_secs_f32(self.jitter * 2.0),
+            JitterBufferDepth::Fixed(ms) => Duration::from_millis(ms as u64),
+        }
+        .min(MAX_JITTER_BUFFER_DEPTH)
+    }
+
+    fn push(&mut self, messages: Vec<(PeerId, Message)>, now: Instant) {
+        if messages.is_empty() {
+            return;
+        }
+        if let Some(last_arrival) = self.last_arrival {
+            // Same smoothing factor as the RTP interarrival jitter estimate (RFC 3550)
+            let interval = now.duration_since(last_arrival).as_secs_f32();
+            self.mean_interval += (interval - self.mean_interval) / 16.0;
+            self.jitter += ((interval - self.mean_interval).abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some(now);
+        self.queue
+            .extend(messages.into_iter().map(|(peer, msg)| (now, peer, msg)));
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Vec<(PeerId, Message)> {
+        let depth = self.current_depth();
+        let mut due = Vec::new();
+        while let Some((arrival, ..)) = self.queue.front() {
+            if now.duration_since(*arrival) < depth {
+                break;
+            }
+            if let Some((_, peer, msg)) = self.queue.pop_front() {
+                due.push((peer, msg));
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netplay::clock::ManualClock;
+
+    #[test]
+    fn both_peers_agree_on_the_newest_common_resume_frame() {
+        let ours = [120, 110, 100];
+        let theirs = [115, 110, 100, 90];
+        // Both attempts for 110 and 100 connect, each peer decides in each room on its own
+        let chosen: Vec<i32> = [110, 100]
+            .into_iter()
+            .filter(|frame| {
+                let we_stay = agree_on_resume_frame(&ours, &theirs) == Some(*frame);
+                let they_stay = agree_on_resume_frame(&theirs, &ours) == Some(*frame);
+                assert_eq!(we_stay, they_stay);
+                we_stay
+            })
+            .collect();
+        assert_eq!(chosen, vec![110]);
+        assert_eq!(agree_on_resume_frame(&[120], &[110]), None);
+    }
+
+    fn hello() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            features: Features::HEARTBEAT | Features::RESUME_FRAMES,
+            heartbeat_interval_ms: 250,
+            rom_hash: "0123456789abcdef".to_string(),
+        }
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = hello();
+        assert_eq!(Hello::decode(&hello.encode()), Some(hello.clone()));
+        assert!(hello.is_compatible());
+    }
+
+    #[test]
+    fn unknown_features_are_dropped() {
+        let mut packet = hello().encode().to_vec();
+        packet[6..10].copy_from_slice(&(Features::LEAVE.bits() | 1 << 31).to_le_bytes());
+        assert_eq!(Hello::decode(&packet).unwrap().features, Features::LEAVE);
+    }
+
+    #[test]
+    fn raw_rom_hash_is_an_incompatible_peer() {
+        let hello = Hello::decode(b"0123456789abcdef").unwrap();
+        assert_eq!(hello.version, 0);
+        assert_eq!(hello.rom_hash, "0123456789abcdef");
+        assert_eq!(hello.features, Features::empty());
+        assert!(!hello.is_compatible());
+    }
+
+    #[test]
+    fn version_1_peers_are_incompatible() {
+        let mut packet = HELLO_MAGIC.to_vec();
+        packet.extend_from_slice(&1u16.to_le_bytes());
+        packet.extend_from_slice(&Features::HEARTBEAT.bits().to_le_bytes());
+        packet.extend_from_slice(b"0123456789abcdef");
+        let hello = Hello::decode(&packet).unwrap();
+        assert_eq!(hello.rom_hash, "0123456789abcdef");
+        assert_eq!(hello.heartbeat_interval_ms, 0);
+        assert!(!hello.is_compatible());
+    }
+
+    #[test]
+    fn other_packets_are_no_hello() {
+        assert_eq!(Hello::decode(HEARTBEAT_PACKET), None);
+        assert_eq!(Hello::decode(LEAVE_ACK_PACKET), None);
+        assert_eq!(Hello::decode(&encode_fast_forward(10)), None);
+        // Too short for the version, the features and the heartbeat interval
+        assert_eq!(Hello::decode(&hello().encode()[..11]), None);
+    }
+
+    #[test]
+    fn resume_frames_round_trip() {
+        let frames = vec![120, 110, -1, 0];
+        let packet = encode_resume_frames(&frames);
+        assert_eq!(decode_resume_frames(&packet), Some(frames));
+        assert_eq!(decode_resume_frames(&packet[..packet.len() - 1]), None);
+        assert_eq!(Hello::decode(&packet), None);
+        let too_many = encode_resume_frames(&[0; MAX_RESUME_FRAMES + 1]);
+        assert_eq!(decode_resume_frames(&too_many), None);
+    }
+
+    // The magic of the header and `MessageBody::KeepAlive`, the one GGRS message without fields
+    const KEEP_ALIVE: [u8; 6] = [0x12, 0x34, 7, 0, 0, 0];
+
+    #[test]
+    fn only_inputs_are_buffered() {
+        let mut input = KEEP_ALIVE;
+        input[2] = GGRS_INPUT_BODY as u8;
+        assert!(is_input(&input));
+        assert!(!is_input(&KEEP_ALIVE));
+        assert!(!is_input(&KEEP_ALIVE[..3]));
+    }
+
+    #[test]
+    fn jitter_buffer_is_off_by_default() {
+        assert_eq!(JitterBufferDepth::default(), JitterBufferDepth::Fixed(0));
+        let clock = ManualClock::new();
+        let mut buffer = JitterBuffer::new(JitterBufferDepth::default());
+        let message = decode(&KEEP_ALIVE).unwrap();
+        buffer.push(vec![(PeerId(uuid::Uuid::nil()), message)], clock.now());
+        assert_eq!(buffer.pop_due(clock.now()).len(), 1);
+    }
+
+    #[test]
+    fn jitter_buffer_holds_messages_back() {
+        let clock = ManualClock::new();
+        let mut buffer = JitterBuffer::new(JitterBufferDepth::Fixed(20));
+        let message = decode(&KEEP_ALIVE).unwrap();
+        buffer.push(vec![(PeerId(uuid::Uuid::nil()), message)], clock.now());
+        clock.advance(Duration::from_millis(19));
+        assert!(buffer.pop_due(clock.now()).is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(buffer.pop_due(clock.now()).len(), 1);
+    }
+
+    #[test]
+    fn heartbeats_are_missed_as_the_clock_moves() {
+        let clock = ManualClock::new();
+        let liveness = Liveness::new(
+            Duration::from_secs(1),
+            Duration::from_millis(250),
+            true,
+            NetplayClock::Manual(clock.clone()),
+        );
+        assert_eq!(liveness.missed_heartbeats(), 0);
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(liveness.missed_heartbeats(), 2);
+        *liveness.last_heartbeat.lock().unwrap() = clock.now();
+        assert_eq!(liveness.missed_heartbeats(), 0);
+    }
+
+    #[test]
+    fn a_slower_peer_is_counted_in_its_own_interval() {
+        let clock = ManualClock::new();
+        let liveness = Liveness::new(
+            Duration::from_millis(250),
+            Duration::from_secs(1),
+            true,
+            NetplayClock::Manual(clock.clone()),
+        );
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(liveness.missed_heartbeats(), 2);
+    }
+
+    #[test]
+    fn peers_without_heartbeats_are_never_missing() {
+        let clock = ManualClock::new();
+        let liveness = Liveness::new(
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            false,
+            NetplayClock::Manual(clock.clone()),
+        );
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(liveness.missed_heartbeats(), 0);
+    }
+
+    #[test]
+    fn side_channel_admits_a_burst_then_drops() {
+        let clock = ManualClock::new();
+        let mut limiter = SideChannelLimiter::new(clock.now());
+        for _ in 0..SIDE_CHANNEL_BURST as usize {
+            assert!(matches!(limiter.admit(clock.now()), Admission::Admit));
+        }
+        assert!(matches!(limiter.admit(clock.now()), Admission::Drop));
+        // The bucket refills at the rate, one more packet after a rate's worth of time
+        clock.advance(Duration::from_secs_f32(1.0 / SIDE_CHANNEL_RATE));
+        assert!(matches!(limiter.admit(clock.now()), Admission::Admit));
+    }
+
+    #[test]
+    fn side_channel_flood_is_reported_after_the_timeout() {
+        let clock = ManualClock::new();
+        let mut limiter = SideChannelLimiter::new(clock.now());
+        let step = Duration::from_millis(1);
+        let mut flooding = false;
+        let give_up = clock.now() + FLOOD_TIMEOUT * 2;
+        while clock.now() < give_up {
+            if matches!(limiter.admit(clock.now()), Admission::Flooding) {
+                flooding = true;
+                break;
+            }
+            clock.advance(step);
+        }
+        assert!(flooding);
+    }
+
+    #[test]
+    fn side_channel_flood_ends_after_the_cooldown() {
+        let clock = ManualClock::new();
+        let mut limiter = SideChannelLimiter::new(clock.now());
+        while matches!(limiter.admit(clock.now()), Admission::Admit) {}
+        assert!(limiter.dropping_since.is_some());
+        clock.advance(FLOOD_COOLDOWN);
+        assert!(matches!(limiter.admit(clock.now()), Admission::Admit));
+        assert!(limiter.dropping_since.is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::settings::Settings;
+
+use super::{
+    clock::{Clock, SystemClock},
+    NetplayNesState,
+};
+
+/// What the delayed states may use in total, a long delay keeps fewer states further apart instead of growing past it
+const SPECTATOR_DELAY_MEMORY_BUDGET: usize = 128 * 1024 * 1024;
+
+/// Holds the confirmed states back from a spectator for a while, so what they see (and stream) is behind the players.
+/// The players are not affected, this sits on the spectator's side only.
+#[allow(dead_code)] // There is no spectator mode yet
+pub struct SpectatorDelay<C: Clock = SystemClock> {
+    clock: C,
+    delay: Duration,
+    // Stored states are at least this far apart, to stay within the memory budget
+    min_spacing: Duration,
+    states: VecDeque<(Instant, Arc<NetplayNesState>)>,
+}
+
+#[allow(dead_code)] // See above
+impl SpectatorDelay {
+    pub fn new(delay: Duration, fps: f32, state_size: usize) -> Self {
+        Self::with_clock(delay, fps, state_size, SystemClock)
+    }
+
+    /// With the `spectator_delay_secs` of the settings
+    pub fn from_settings(fps: f32, state_size: usize) -> Self {
+        let delay = Duration::from_secs(Settings::current().netplay.spectator_delay_secs as u64);
+        Self::new(delay, fps, state_size)
+    }
+}
+
+#[allow(dead_code)] // See above
+impl<C: Clock> SpectatorDelay<C> {
+    /// `state_size` is what one state takes, like the size of a saved state
+    pub fn with_clock(delay: Duration, fps: f32, state_size: usize, clock: C) -> Self {
+        let max_states = (SPECTATOR_DELAY_MEMORY_BUDGET / state_size.max(1)).max(1);
+        let wanted_states = (delay.as_secs_f32() * fps).ceil() as usize;
+        let min_spacing = if wanted_states > max_states {
+            let min_spacing = delay / max_states as u32;
+            log::info!(
+                "A spectator delay of {delay:?} needs {wanted_states} states, keeping {max_states} states {min_spacing:?} apart instead"
+            );
+            min_spacing
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            clock,
+            delay,
+            min_spacing,
+            states: VecDeque::new(),
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Takes a newly confirmed state, it's dropped if it comes too soon after the last stored one
+    pub fn push(&mut self, state: Arc<NetplayNesState>) {
+        let now = self.clock.now();
+        if let Some((last, _)) = self.states.back() {
+            if now.saturating_duration_since(*last) < self.min_spacing {
+                return;
+            }
+        }
+        self.states.push_back((now, state));
+    }
+
+    /// The newest state that has been held back for the whole delay, if any. Older states are dropped.
+    pub fn pop_due(&mut self) -> Option<Arc<NetplayNesState>> {
+        let now = self.clock.now();
+        let mut due = None;
+        while let Some((confirmed_at, _)) = self.states.front() {
+            if now.saturating_duration_since(*confirmed_at) < self.delay {
+                break;
+            }
+            due = self.states.pop_front().map(|(_, state)| state);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bundle::Bundle, emulation::LocalNesState, netplay::clock::ManualClock};
+
+    fn state() -> Arc<NetplayNesState> {
+        let bundle = Bundle::current();
+        Arc::new(NetplayNesState::new(
+            LocalNesState::start_rom(
+                &bundle.netplay_rom,
+                false,
+                bundle.config.get_default_region(),
+            )
+            .expect("the bundled netplay ROM to start"),
+        ))
+    }
+
+    #[test]
+    fn states_are_held_back_for_the_delay() {
+        let clock = ManualClock::new();
+        let mut delay = SpectatorDelay::with_clock(Duration::from_secs(30), 60.0, 1, clock.clone());
+        let (first, second) = (state(), state());
+        delay.push(first.clone());
+        clock.advance(Duration::from_secs(10));
+        delay.push(second.clone());
+
+        clock.advance(Duration::from_secs(19));
+        assert!(delay.pop_due().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(Arc::ptr_eq(&delay.pop_due().unwrap(), &first));
+        assert!(delay.pop_due().is_none());
+        clock.advance(Duration::from_secs(10));
+        assert!(Arc::ptr_eq(&delay.pop_due().unwrap(), &second));
+    }
+
+    #[test]
+    fn only_the_newest_due_state_is_shown() {
+        let clock = ManualClock::new();
+        let mut delay = SpectatorDelay::with_clock(Duration::from_secs(1), 60.0, 1, clock.clone());
+        let (first, second) = (state(), state());
+        delay.push(first);
+        delay.push(second.clone());
+        clock.advance(Duration::from_secs(1));
+        assert!(Arc::ptr_eq(&delay.pop_due().unwrap(), &second));
+        assert!(delay.pop_due().is_none());
+    }
+
+    #[test]
+    fn a_long_delay_stays_within_the_memory_budget() {
+        let clock = ManualClock::new();
+        // Room for 10 states, a second apart over the 10 seconds
+        let mut delay = SpectatorDelay::with_clock(
+            Duration::from_secs(10),
+            60.0,
+            SPECTATOR_DELAY_MEMORY_BUDGET / 10,
+            clock.clone(),
+        );
+        delay.push(state());
+        clock.advance(Duration::from_millis(500));
+        delay.push(state());
+        assert_eq!(delay.states.len(), 1);
+        clock.advance(Duration::from_millis(500));
+        delay.push(state());
+        assert_eq!(delay.states.len(), 2);
+    }
+}
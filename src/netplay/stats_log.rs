@@ -0,0 +1,88 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use matchbox_socket::PeerId;
+
+use crate::bundle::Bundle;
+
+use super::netplay_session::NetplaySessionStats;
+
+const ROW_INTERVAL: Duration = Duration::from_secs(1);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Appends the connection stats of a netplay session to a CSV file next to the settings, one row per second.
+pub struct StatsLog {
+    writer: BufWriter<File>,
+    next_row: Instant,
+    next_flush: Instant,
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+impl StatsLog {
+    pub fn create(session_id: &str, peer: Option<PeerId>) -> Result<Self> {
+        let path = Bundle::current()
+            .settings_path
+            .join(format!("netplay-stats-{}.csv", unix_time().as_secs()));
+        log::info!("Logging netplay stats to {:?}", path);
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# session_id: {session_id}")?;
+        match peer {
+            Some(peer) => writeln!(writer, "# peer: {peer:?}")?,
+            None => writeln!(writer, "# peer: unknown")?,
+        }
+        writeln!(
+            writer,
+            "timestamp,ping_ms,rollback_depth,frames_ahead,local_frames_behind,remote_frames_behind"
+        )?;
+        writer.flush()?;
+
+        let now = Instant::now();
+        Ok(Self {
+            writer,
+            next_row: now,
+            next_flush: now + FLUSH_INTERVAL,
+        })
+    }
+
+    /// Can be called every frame, only writes a row when a second has passed since the last one.
+    pub fn log(&mut self, stats: &NetplaySessionStats) {
+        let now = Instant::now();
+        if now < self.next_row {
+            return;
+        }
+        self.next_row = now + ROW_INTERVAL;
+
+        let result = writeln!(
+            self.writer,
+            "{:.3},{},{},{},{},{}",
+            unix_time().as_secs_f64(),
+            stats.ping,
+            stats.rollback_depth,
+            stats.frames_ahead,
+            stats.local_frames_behind,
+            stats.remote_frames_behind
+        )
+        .and_then(|_| {
+            // Flushing now and then keeps most of the log if we crash
+            if now >= self.next_flush {
+                self.next_flush = now + FLUSH_INTERVAL;
+                self.writer.flush()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            log::warn!("Could not write netplay stats: {:?}", e);
+        }
+    }
+}
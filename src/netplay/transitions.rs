@@ -0,0 +1,84 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use super::netplay_state::NetplayState;
+
+/// Enough to see how a flaky connection went without keeping a whole evening of reconnects
+const MAX_TRANSITIONS: usize = 64;
+
+pub struct Transition {
+    /// Since the log was started
+    pub at: Duration,
+    pub from: &'static str,
+    pub to: &'static str,
+    pub reason: Option<String>,
+}
+
+impl Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>8.1}s {} → {}",
+            self.at.as_secs_f32(),
+            self.from,
+            self.to
+        )?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({reason})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The latest changes between the netplay states, oldest first. Short enough to paste into a bug report.
+pub struct TransitionLog {
+    started: Instant,
+    transitions: VecDeque<Transition>,
+}
+
+impl TransitionLog {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            transitions: VecDeque::with_capacity(MAX_TRANSITIONS),
+        }
+    }
+
+    /// Records the change from a state named `from` to `to`, if it is one
+    pub fn record(&mut self, from: &'static str, to: &NetplayState) {
+        if from == to.name() {
+            return;
+        }
+        let transition = Transition {
+            at: self.started.elapsed(),
+            from,
+            to: to.name(),
+            reason: to.reason(),
+        };
+        log::debug!("Netplay transition: {transition}");
+        if self.transitions.len() == MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+        crate::crash::set_netplay(to.name(), self.timeline());
+        if matches!(to, NetplayState::Failed(_)) {
+            // So a bug report has the whole story without verbose logging
+            log::info!("Netplay timeline:\n{}", self.timeline());
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transition> {
+        self.transitions.iter()
+    }
+
+    /// One transition per line
+    pub fn timeline(&self) -> String {
+        self.iter()
+            .map(|transition| transition.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
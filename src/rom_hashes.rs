@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{bundle::Bundle, input::rom_id, settings::Settings};
+
+/// Files changed this recently aren't cached, a second write within the resolution of the file system clock
+/// could leave both the size and the modification time the same.
+const MIN_AGE: Duration = Duration::from_secs(2);
+
+/// What a file looked like when it was hashed, any difference means it has to be hashed again.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl FileStamp {
+    /// `None` when the file system has no modification times or the file was changed just now
+    fn of(path: &Path) -> Result<Option<Self>> {
+        let metadata = fs::metadata(path)?;
+        let Ok(modified) = metadata.modified() else {
+            return Ok(None);
+        };
+        let recent = SystemTime::now()
+            .duration_since(modified)
+            .map_or(true, |age| age < MIN_AGE);
+        let Ok(since_epoch) = modified.duration_since(UNIX_EPOCH) else {
+            return Ok(None);
+        };
+        Ok((!recent).then_some(Self {
+            size: metadata.len(),
+            modified_secs: since_epoch.as_secs(),
+            modified_nanos: since_epoch.subsec_nanos(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedHash {
+    stamp: FileStamp,
+    rom_id: String,
+}
+
+/// The `rom_id`s of ROM files by path, kept in `rom-hashes.yaml` next to the settings.
+#[derive(Default)]
+struct RomHashes {
+    entries: HashMap<PathBuf, CachedHash>,
+}
+
+impl RomHashes {
+    fn file_path() -> PathBuf {
+        Bundle::current().settings_path.join("rom-hashes.yaml")
+    }
+
+    fn current() -> &'static Mutex<RomHashes> {
+        static MEM: OnceLock<Mutex<RomHashes>> = OnceLock::new();
+        MEM.get_or_init(|| {
+            let entries = File::open(Self::file_path())
+                .map_err(anyhow::Error::msg)
+                .and_then(|f| {
+                    serde_yaml::from_reader(BufReader::new(f)).map_err(anyhow::Error::msg)
+                })
+                .inspect_err(|e| log::debug!("Starting without cached ROM hashes: {:?}", e))
+                .unwrap_or_default();
+            Mutex::new(RomHashes { entries })
+        })
+    }
+
+    fn get(&self, path: &Path, stamp: FileStamp) -> Option<String> {
+        self.entries
+            .get(path)
+            .filter(|cached| cached.stamp == stamp)
+            .map(|cached| cached.rom_id.clone())
+    }
+
+    fn insert(&mut self, path: PathBuf, stamp: FileStamp, rom_id: String) {
+        self.entries.insert(path, CachedHash { stamp, rom_id });
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Err(e) = File::create(Self::file_path())
+            .map_err(anyhow::Error::msg)
+            .and_then(|file| {
+                serde_yaml::to_writer(BufWriter::new(file), &self.entries)
+                    .map_err(anyhow::Error::msg)
+            })
+        {
+            log::warn!("Could not save the ROM hashes: {:?}", e);
+        }
+    }
+}
+
+/// The ROMs with a cached hash, with roughly what their entries take in the cache file
+pub fn cached_rom_ids() -> Vec<(String, usize)> {
+    RomHashes::current()
+        .lock()
+        .unwrap()
+        .entries
+        .iter()
+        .map(|(path, cached)| {
+            let size = serde_yaml::to_string(cached).map_or(0, |yaml| yaml.len());
+            (cached.rom_id.clone(), path.as_os_str().len() + size)
+        })
+        .collect()
+}
+
+/// Drops every cached hash of the ROM, whatever path it was loaded from
+pub fn forget(rom_id: &str) {
+    let mut rom_hashes = RomHashes::current().lock().unwrap();
+    let before = rom_hashes.entries.len();
+    rom_hashes
+        .entries
+        .retain(|_, cached| cached.rom_id != rom_id);
+    if rom_hashes.entries.len() != before {
+        rom_hashes.save();
+    }
+}
+
+fn enabled() -> bool {
+    Settings::current().cache_rom_hashes
+}
+
+/// Reads the ROM at `path` along with its `rom_id`, which is only computed if the file changed since it was last hashed.
+pub fn read_rom(path: &Path) -> Result<(Vec<u8>, String)> {
+    if !enabled() {
+        let rom = fs::read(path)?;
+        let rom_id = rom_id(&rom);
+        return Ok((rom, rom_id));
+    }
+    let path = path.canonicalize()?;
+    let stamp_before = FileStamp::of(&path)?;
+    let rom = fs::read(&path)?;
+    // A stamp that's the same before and after the read means `rom` is what the stamp describes
+    let stamp = stamp_before.filter(|before| FileStamp::of(&path).ok().flatten() == Some(*before));
+
+    let mut rom_hashes = RomHashes::current().lock().unwrap();
+    match stamp {
+        Some(stamp) => match rom_hashes.get(&path, stamp) {
+            Some(rom_id) => Ok((rom, rom_id)),
+            None => {
+                let rom_id = rom_id(&rom);
+                rom_hashes.insert(path, stamp, rom_id.clone());
+                Ok((rom, rom_id))
+            }
+        },
+        None => {
+            let rom_id = rom_id(&rom);
+            Ok((rom, rom_id))
+        }
+    }
+}
+
+/// The `rom_id` of the ROM at `path` without reading it when the cached hash is still valid, for listing ROMs.
+#[allow(dead_code)] // There's no ROM picker listing several ROMs yet
+pub fn file_rom_id(path: &Path) -> Result<String> {
+    if enabled() {
+        let path = path.canonicalize()?;
+        if let Some(stamp) = FileStamp::of(&path)? {
+            if let Some(rom_id) = RomHashes::current().lock().unwrap().get(&path, stamp) {
+                return Ok(rom_id);
+            }
+        }
+    }
+    read_rom(path).map(|(_, rom_id)| rom_id)
+}
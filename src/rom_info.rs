@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::{emulation::NesRegion, rom_hashes};
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+
+/// The mappers the emulator (tetanes-core) can run, by iNES mapper number
+const SUPPORTED_MAPPERS: &[u16] = &[
+    0, 1, 2, 3, 4, 5, 7, 9, 10, 11, 18, 19, 24, 26, 34, 66, 69, 71, 155,
+];
+
+/// What the header of a ROM file says about it, see [`read_rom_info`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomInfo {
+    /// The same id the ROM gets when it's loaded, see `rom_id`
+    pub rom_id: String,
+    /// True for a NES 2.0 header, plain iNES otherwise
+    pub nes2: bool,
+    pub mapper: u16,
+    /// Only NES 2.0 headers have one
+    pub submapper: Option<u8>,
+    /// In bytes
+    pub prg_rom_size: usize,
+    /// In bytes, 0 when the cartridge has CHR RAM instead
+    pub chr_rom_size: usize,
+    /// The cartridge keeps its RAM when turned off, the game can save
+    pub battery: bool,
+    /// `None` when the ROM says it runs on both NTSC and PAL consoles
+    pub region: Option<NesRegion>,
+}
+
+impl RomInfo {
+    /// Parses the iNES or NES 2.0 header and checks that the file is as large as the header says.
+    pub fn parse(rom: &[u8], rom_id: String) -> Result<Self> {
+        if rom.len() < HEADER_SIZE || &rom[0..4] != b"NES\x1a" {
+            return Err(anyhow!("Not a NES ROM (missing iNES header)"));
+        }
+        let header = &rom[..HEADER_SIZE];
+        let nes2 = header[7] & 0x0c == 0x08;
+        // Old dumping tools wrote their name over the end of the header, which leaves the upper mapper bits garbage
+        let dirty = !nes2 && header[12..].iter().any(|byte| *byte != 0);
+        let mut mapper = (header[6] >> 4) as u16;
+        if !dirty {
+            mapper |= (header[7] & 0xf0) as u16;
+        }
+
+        let (submapper, prg_rom_size, chr_rom_size, region) = if nes2 {
+            mapper |= ((header[8] & 0x0f) as u16) << 8;
+            let region = match header[12] & 0x03 {
+                0 => Some(NesRegion::Ntsc),
+                1 => Some(NesRegion::Pal),
+                2 => None,
+                _ => Some(NesRegion::Dendy),
+            };
+            (
+                Some(header[8] >> 4),
+                nes2_rom_size(header[4], header[9] & 0x0f, 16 * 1024)?,
+                nes2_rom_size(header[5], header[9] >> 4, 8 * 1024)?,
+                region,
+            )
+        } else {
+            let region = if !dirty && header[9] & 0x01 == 1 {
+                NesRegion::Pal
+            } else {
+                NesRegion::Ntsc
+            };
+            (
+                None,
+                header[4] as usize * 16 * 1024,
+                header[5] as usize * 8 * 1024,
+                Some(region),
+            )
+        };
+        if prg_rom_size == 0 {
+            return Err(anyhow!("The header says the ROM has no PRG ROM"));
+        }
+
+        let trainer_size = if header[6] & 0x04 != 0 {
+            TRAINER_SIZE
+        } else {
+            0
+        };
+        let expected = (HEADER_SIZE + trainer_size)
+            .checked_add(prg_rom_size)
+            .and_then(|size| size.checked_add(chr_rom_size))
+            .ok_or_else(|| anyhow!("The header has an impossible ROM size"))?;
+        if rom.len() < expected {
+            return Err(anyhow!(
+                "The ROM is {} bytes but its header says it should be {expected} (truncated file?)",
+                rom.len()
+            ));
+        }
+
+        Ok(Self {
+            rom_id,
+            nes2,
+            mapper,
+            submapper,
+            prg_rom_size,
+            chr_rom_size,
+            battery: header[6] & 0x02 != 0,
+            region,
+        })
+    }
+
+    pub fn supported_mapper(&self) -> bool {
+        SUPPORTED_MAPPERS.contains(&self.mapper)
+    }
+
+    /// Why the ROM might not run, for showing before it's launched. Empty when nothing looks wrong.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.supported_mapper() {
+            warnings.push(format!("Mapper {} is not supported", self.mapper));
+        }
+        if self.region == Some(NesRegion::Dendy) {
+            warnings.push("Made for Dendy consoles, it might not run right".to_string());
+        }
+        warnings
+    }
+}
+
+/// Like "1a2b... (NES 2.0, mapper 4.1, 256 KiB PRG ROM, 128 KiB CHR ROM, battery, Ntsc)"
+impl std::fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}, mapper {}",
+            self.rom_id,
+            if self.nes2 { "NES 2.0" } else { "iNES" },
+            self.mapper
+        )?;
+        if let Some(submapper) = self.submapper {
+            write!(f, ".{submapper}")?;
+        }
+        write!(f, ", {} KiB PRG ROM", self.prg_rom_size / 1024)?;
+        if self.chr_rom_size > 0 {
+            write!(f, ", {} KiB CHR ROM", self.chr_rom_size / 1024)?;
+        }
+        if self.battery {
+            write!(f, ", battery")?;
+        }
+        match &self.region {
+            Some(region) => write!(f, ", {region:?})"),
+            None => write!(f, ", any region)"),
+        }
+    }
+}
+
+// The upper nibble of the size is 0xf when the lower byte holds an exponent and a multiplier instead
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Result<usize> {
+    if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        2usize
+            .checked_pow(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| {
+                anyhow!("The header has an impossible ROM size (2^{exponent} * {multiplier})")
+            })
+    } else {
+        Ok((((msb as usize) << 8) | lsb as usize) * unit)
+    }
+}
+
+/// The header info and `rom_id` of the ROM at `path`, without launching it. The hash is cached like when loading it.
+#[allow(dead_code)] // There's no ROM picker listing several ROMs yet
+pub fn read_rom_info(path: &Path) -> Result<RomInfo> {
+    let (rom, rom_id) = rom_hashes::read_rom(path)?;
+    RomInfo::parse(&rom, rom_id).map_err(|e| anyhow!("{}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_too_large_to_add_up_are_an_error() {
+        let mut rom = b"NES\x1a".to_vec();
+        // NES 2.0 with 2^63 bytes of both PRG and CHR ROM
+        rom.extend([0xfc, 0xfc, 0, 0x08, 0, 0xff, 0, 0, 0, 0, 0, 0]);
+        assert!(RomInfo::parse(&rom, "test".to_string()).is_err());
+    }
+}
@@ -1,12 +1,16 @@
 use crate::{
     audio::AudioSettings,
     bundle::Bundle,
-    emulation::NesRegion,
-    input::{settings::InputSettings, InputConfigurationKind},
+    emulation::{
+        pacing::{FramePacing, TimingAccuracy},
+        IntroSkip, NesRegion, SessionTimeout,
+    },
+    input::{keys::KeyCode, settings::InputSettings, InputConfigurationKind, JoypadButton},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 use std::{
     collections::hash_map::DefaultHasher,
     fs::File,
@@ -18,6 +22,9 @@ use std::{
 
 pub const MAX_PLAYERS: usize = 2;
 
+/// Bump this (and add a step to `Settings::migrate`) when the format changes in a way serde defaults can't cover.
+const SETTINGS_VERSION: u32 = 1;
+
 pub struct AutoSavingSettings<'a> {
     inner: RwLockWriteGuard<'a, Settings>,
     hash_before: u64,
@@ -57,11 +64,46 @@ impl Drop for AutoSavingSettings<'_> {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub struct Settings {
+    // Files from before the settings were versioned have no version and count as version 0
+    #[serde(default)]
+    version: u32,
     pub audio: AudioSettings,
     pub input: InputSettings,
     pub netplay_id: Option<String>,
     pub save_state: Option<String>,
     nes_region: Option<NesRegion>,
+    #[serde(default)]
+    pub pause_when_unfocused: bool,
+    #[serde(default)]
+    pub frame_pacing: FramePacing,
+    #[serde(default)]
+    pub timing_accuracy: TimingAccuracy,
+    /// Shows only about this many frames per second to save battery, every frame is still emulated. Not used in netplay.
+    #[serde(default)]
+    pub battery_saver_fps: Option<u8>,
+    /// Remembers the hashes of loaded ROM files by path, size and modification time
+    #[serde(default)]
+    pub cache_rom_hashes: bool,
+    /// Flashes the screen white on the frame this button of player 1 gets pressed, for measuring the input latency with a camera
+    #[serde(default)]
+    pub latency_test_button: Option<JoypadButton>,
+    #[serde(default)]
+    pub intro_skip: Option<IntroSkip>,
+    #[serde(default)]
+    pub session_timeout: Option<SessionTimeout>,
+    /// Like the reset button on the console
+    #[serde(default)]
+    pub soft_reset_key: Option<KeyCode>,
+    /// Like turning the console off and on again
+    #[serde(default)]
+    pub hard_reset_key: Option<KeyCode>,
+    #[cfg(feature = "netplay")]
+    #[serde(default)]
+    pub netplay: crate::netplay::NetplaySettings,
+
+    // Fields we don't know about (written by a newer version for example), kept so saving doesn't drop them
+    #[serde(skip)]
+    unknown_fields: Mapping,
 }
 
 impl Settings {
@@ -81,14 +123,18 @@ impl Settings {
     fn load() -> Settings {
         let bundle = Bundle::current();
         let settings_file_path = &bundle.settings_path.join("settings.yaml");
-        let default_settings = bundle.config.default_settings.clone();
+        let mut default_settings = bundle.config.default_settings.clone();
+        default_settings.version = SETTINGS_VERSION;
 
         let mut settings: Result<Settings> = File::open(settings_file_path)
             .map_err(anyhow::Error::msg)
-            .and_then(|f| serde_yaml::from_reader(BufReader::new(f)).map_err(anyhow::Error::msg));
+            .and_then(|f| serde_yaml::from_reader(BufReader::new(f)).map_err(anyhow::Error::msg))
+            .and_then(|value| Self::from_value(value, &default_settings));
 
         match &mut settings {
             Ok(settings) => {
+                settings.validate(&default_settings);
+
                 let default_selected = default_settings.clone().input.selected;
                 //Make sure no gamepads are selected after loading settings (they will be autoselected later if they are connected)
                 if let InputConfigurationKind::Gamepad(_) =
@@ -102,6 +148,14 @@ impl Settings {
                     settings.input.selected[1] = default_selected[1].clone();
                 }
             }
+            Err(_) if !settings_file_path.exists() => {
+                // First launch, the defaults become the user's own so a bundle update won't change them
+                log::info!(
+                    "No settings yet, saving the defaults to {:?}",
+                    settings_file_path
+                );
+                default_settings.save();
+            }
             Err(e) => log::warn!(
                 "Could not load settings ({:?}): {:?}",
                 settings_file_path,
@@ -113,12 +167,120 @@ impl Settings {
         settings.unwrap_or(default_settings)
     }
 
+    fn from_value(value: Value, default_settings: &Settings) -> Result<Settings> {
+        let Value::Mapping(mut mapping) = value else {
+            return Err(anyhow!("Settings is not a mapping"));
+        };
+        let version = mapping.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+        if version > SETTINGS_VERSION {
+            log::warn!(
+                "Settings are from a newer version ({version} > {SETTINGS_VERSION}), some of them might not be used"
+            );
+        } else {
+            Self::migrate(&mut mapping, version);
+        }
+
+        let mut settings = match serde_yaml::from_value::<Settings>(Value::Mapping(mapping.clone()))
+        {
+            Ok(settings) => settings,
+            Err(e) => Self::repair(&mapping, default_settings).ok_or(e)?,
+        };
+        settings.version = version.max(SETTINGS_VERSION);
+
+        if let Value::Mapping(known_fields) = serde_yaml::to_value(&settings)? {
+            settings.unknown_fields = mapping
+                .iter()
+                .filter(|(key, _)| !known_fields.contains_key(*key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+        }
+        if version < SETTINGS_VERSION {
+            // Upgrade the file right away rather than migrating it on every launch
+            settings.save();
+        }
+        Ok(settings)
+    }
+
+    fn migrate(settings: &mut Mapping, from_version: u32) {
+        if from_version < 1 {
+            // Every field added before versioning has a serde default, so there's nothing to convert
+            log::info!("Migrating settings from version {from_version} to 1");
+        }
+        settings.insert("version".into(), SETTINGS_VERSION.into());
+    }
+
+    /// Tries to replace one broken field with its default instead of throwing away all the settings.
+    fn repair(settings: &Mapping, default_settings: &Settings) -> Option<Settings> {
+        let Ok(Value::Mapping(defaults)) = serde_yaml::to_value(default_settings) else {
+            return None;
+        };
+        defaults.into_iter().find_map(|(key, default_value)| {
+            let mut candidate = settings.clone();
+            candidate.insert(key.clone(), default_value);
+            let settings = serde_yaml::from_value(Value::Mapping(candidate)).ok()?;
+            log::warn!("Invalid setting {:?}, using the default", key);
+            Some(settings)
+        })
+    }
+
+    /// Clamps values that deserialize fine but are out of range.
+    fn validate(&mut self, default_settings: &Settings) {
+        if self.audio.volume > 100 {
+            log::warn!("Invalid volume {}, using 100", self.audio.volume);
+            self.audio.volume = 100;
+        }
+        if self.audio.latency == 0 {
+            log::warn!("Invalid audio latency 0, using the default");
+            self.audio.latency = default_settings.audio.latency;
+        }
+        if self.audio.netplay_latency == 0 {
+            log::warn!("Invalid netplay audio latency 0, using the default");
+            self.audio.netplay_latency = default_settings.audio.netplay_latency;
+        }
+        for idx in 0..MAX_PLAYERS {
+            if !self
+                .input
+                .configurations
+                .contains_key(&self.input.selected[idx])
+            {
+                log::warn!(
+                    "Unknown input configuration {:?} selected, using the default",
+                    self.input.selected[idx]
+                );
+                self.input.selected[idx] = default_settings.input.selected[idx].clone();
+            }
+        }
+        if let Some(nes_region) = &self.nes_region {
+            if !Bundle::current()
+                .config
+                .supported_nes_regions
+                .contains(nes_region)
+            {
+                log::warn!("Unsupported NES region {:?}, using the default", nes_region);
+                self.nes_region = None;
+            }
+        }
+    }
+
     fn save(&self) {
         let settings_file_path = &Bundle::current().settings_path.join("settings.yaml");
-        if let Err(e) = File::create(settings_file_path)
+        if let Err(e) = serde_yaml::to_value(self)
+            .map(|mut value| {
+                if let Value::Mapping(mapping) = &mut value {
+                    for (key, unknown_value) in &self.unknown_fields {
+                        mapping.insert(key.clone(), unknown_value.clone());
+                    }
+                }
+                value
+            })
             .map_err(anyhow::Error::msg)
-            .and_then(|file| {
-                serde_yaml::to_writer(BufWriter::new(file), self).map_err(anyhow::Error::msg)
+            .and_then(|value| {
+                File::create(settings_file_path)
+                    .map_err(anyhow::Error::msg)
+                    .and_then(|file| {
+                        serde_yaml::to_writer(BufWriter::new(file), &value)
+                            .map_err(anyhow::Error::msg)
+                    })
             })
         {
             log::error!("Failed to save settings: {:?}", e);
@@ -0,0 +1,66 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{rom_hashes, settings::Settings};
+
+/// Something kept on disk for a single ROM, by its `rom_id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StoredKind {
+    /// The NES button remaps of both players, in the settings
+    ButtonRemaps,
+    /// The hash of a ROM file, see `rom_hashes`
+    CachedHash,
+}
+
+impl Display for StoredKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoredKind::ButtonRemaps => write!(f, "Button remaps"),
+            StoredKind::CachedHash => write!(f, "Cached hash"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredData {
+    pub kind: StoredKind,
+    /// Roughly how many bytes it takes on disk
+    pub size: usize,
+}
+
+/// Everything stored per ROM, by `rom_id`. A ROM only shows up if something is stored for it.
+pub fn stored_per_rom() -> BTreeMap<String, Vec<StoredData>> {
+    let mut per_rom: BTreeMap<String, Vec<StoredData>> = BTreeMap::new();
+    for (rom_id, remaps) in &Settings::current().input.button_remaps {
+        per_rom.entry(rom_id.clone()).or_default().push(StoredData {
+            kind: StoredKind::ButtonRemaps,
+            size: serde_yaml::to_string(remaps).map_or(0, |yaml| yaml.len()),
+        });
+    }
+    for (rom_id, size) in rom_hashes::cached_rom_ids() {
+        let stored = per_rom.entry(rom_id).or_default();
+        // A ROM loaded from more than one path has a hash for each
+        match stored
+            .iter_mut()
+            .find(|data| data.kind == StoredKind::CachedHash)
+        {
+            Some(data) => data.size += size,
+            None => stored.push(StoredData {
+                kind: StoredKind::CachedHash,
+                size,
+            }),
+        }
+    }
+    per_rom
+}
+
+/// Deletes what's stored of one kind for the ROM, or everything with `None`. Other ROMs are left alone.
+pub fn clear(rom_id: &str, kind: Option<StoredKind>) {
+    let clears = |k: StoredKind| kind.map_or(true, |kind| kind == k);
+    if clears(StoredKind::ButtonRemaps) {
+        Settings::current_mut().input.button_remaps.remove(rom_id);
+    }
+    if clears(StoredKind::CachedHash) {
+        rom_hashes::forget(rom_id);
+    }
+    log::info!("Cleared {kind:?} stored for ROM {rom_id}");
+}
@@ -9,7 +9,7 @@ use gui::EguiRenderer;
 use wgpu::{PresentMode, TextureViewDescriptor};
 use winit::window::Window;
 
-use crate::bundle::Bundle;
+use crate::{bundle::Bundle, emulation::pacing::FramePacing, settings::Settings};
 
 pub mod texture;
 
@@ -18,6 +18,8 @@ pub struct Renderer {
     device: wgpu::Device,
     pub queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
+    present_modes: Vec<PresentMode>,
+    frame_pacing: FramePacing,
     pub size: winit::dpi::PhysicalSize<u32>,
 
     pub window: Arc<Window>,
@@ -65,18 +67,8 @@ impl Renderer {
             .find(|f| !f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
 
-        let present_mode = if Bundle::current().config.enable_vsync {
-            PresentMode::AutoVsync
-        } else {
-            [
-                PresentMode::Mailbox,
-                PresentMode::Immediate,
-                PresentMode::Fifo,
-            ]
-            .into_iter()
-            .find(|mode| surface_caps.present_modes.contains(mode))
-            .unwrap_or(PresentMode::AutoNoVsync)
-        };
+        let frame_pacing = Settings::current().frame_pacing;
+        let present_mode = Self::present_mode(frame_pacing, &surface_caps.present_modes);
 
         let config = wgpu::SurfaceConfiguration {
             desired_maximum_frame_latency: 1,
@@ -98,12 +90,41 @@ impl Renderer {
             device,
             queue,
             config,
+            present_modes: surface_caps.present_modes,
+            frame_pacing,
             size,
             window,
             egui,
         })
     }
 
+    fn present_mode(frame_pacing: FramePacing, present_modes: &[PresentMode]) -> PresentMode {
+        // A fixed timestep has its own clock, waiting for the display would only make it stutter
+        if frame_pacing == FramePacing::Vsync && Bundle::current().config.enable_vsync {
+            PresentMode::AutoVsync
+        } else {
+            [
+                PresentMode::Mailbox,
+                PresentMode::Immediate,
+                PresentMode::Fifo,
+            ]
+            .into_iter()
+            .find(|mode| present_modes.contains(mode))
+            .unwrap_or(PresentMode::AutoNoVsync)
+        }
+    }
+
+    /// Reconfigures the surface if the frame pacing setting changed since the last frame
+    fn sync_frame_pacing(&mut self) {
+        let frame_pacing = Settings::current().frame_pacing;
+        if self.frame_pacing != frame_pacing {
+            self.frame_pacing = frame_pacing;
+            self.config.present_mode = Self::present_mode(frame_pacing, &self.present_modes);
+            log::debug!("Switching present mode to {:?}", self.config.present_mode);
+            self.surface.configure(&self.device, &self.config);
+        }
+    }
+
     pub fn window(&self) -> &Window {
         &self.window
     }
@@ -121,6 +142,7 @@ impl Renderer {
         #[cfg(feature = "debug")]
         puffin::profile_function!();
 
+        self.sync_frame_pacing();
         let output = {
             #[cfg(feature = "debug")]
             puffin::profile_scope!("get_current_texture");